@@ -0,0 +1,99 @@
+//! Throughput of the parser of the server-sent events of a response
+//!
+//! Run with `cargo bench --bench stream`; compare against a saved baseline with
+//! `cargo bench --bench stream -- --save-baseline before` and `-- --baseline before`.
+
+use bytes::Bytes;
+use copilot_chat::chat::{ChatStreamer, DEFAULT_MAX_BUFFER_SIZE, Streamer};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+
+/// A stream of `events` content deltas followed by the `[DONE]` marker
+fn events(events: usize) -> Vec<u8> {
+    let mut buffer = String::new();
+    for i in 0..events {
+        buffer.push_str(&format!(
+            "data: {{\"choices\":[{{\"index\":0,\"content_filter_offsets\":{{\"check_offset\":175,\"start_offset\":{i},\
+             \"end_offset\":{i}}},\"delta\":{{\"content\":\" token{i}\"}}}}],\"created\":1751000792,\
+             \"id\":\"chatcmpl-BmvaCUrU0DjRli6juhycOsjF1OAZr\",\"model\":\"gpt-4.1\"}}\n\n"
+        ));
+    }
+    buffer.push_str("data: [DONE]\n\n");
+    buffer.into_bytes()
+}
+
+fn process_buffer(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().expect("create the runtime");
+    let streamer = ChatStreamer::new(DEFAULT_MAX_BUFFER_SIZE);
+
+    let mut group = c.benchmark_group("process_buffer");
+    for count in [10, 100, 1000] {
+        let buffer = events(count);
+        group.throughput(Throughput::Bytes(buffer.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &buffer, |b, buffer| {
+            b.iter(|| {
+                runtime
+                    .block_on(streamer.process_buffer(buffer))
+                    .expect("parse the events")
+            })
+        });
+    }
+    group.finish();
+}
+
+fn handle_stream(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().expect("create the runtime");
+    let streamer = ChatStreamer::new(DEFAULT_MAX_BUFFER_SIZE);
+    let buffer = events(1000);
+
+    let mut group = c.benchmark_group("handle_stream");
+    group.throughput(Throughput::Bytes(buffer.len() as u64));
+    // Small network chunks split the events, large ones carry many of them
+    for chunk_size in [64, 1024, 16 * 1024] {
+        let chunks: Vec<Bytes> = buffer.chunks(chunk_size).map(Bytes::copy_from_slice).collect();
+        group.bench_with_input(BenchmarkId::from_parameter(chunk_size), &chunks, |b, chunks| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    // Large enough to hold every delta, so the writer is not needed
+                    let (sender, _receiver) = tokio::sync::mpsc::channel(buffer.len());
+                    let stream = futures_util::stream::iter(chunks.iter().cloned().map(Ok));
+                    streamer.handle_stream(stream, sender).await.expect("handle the stream")
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// A single event of 256 KiB received in network chunks, the buffer keeps growing until it completes
+fn large_event(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().expect("create the runtime");
+    let streamer = ChatStreamer::new(DEFAULT_MAX_BUFFER_SIZE);
+    let content = "token ".repeat(256 * 1024 / 6);
+    let buffer = format!(
+        "data: {{\"choices\":[{{\"index\":0,\"delta\":{{\"content\":\"{}\"}}}}]}}\n\ndata: [DONE]\n\n",
+        content
+    );
+
+    let mut group = c.benchmark_group("large_event");
+    group.throughput(Throughput::Bytes(buffer.len() as u64));
+    for chunk_size in [1024, 16 * 1024] {
+        let chunks: Vec<Bytes> = buffer
+            .as_bytes()
+            .chunks(chunk_size)
+            .map(Bytes::copy_from_slice)
+            .collect();
+        group.bench_with_input(BenchmarkId::from_parameter(chunk_size), &chunks, |b, chunks| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let (sender, _receiver) = tokio::sync::mpsc::channel(2);
+                    let stream = futures_util::stream::iter(chunks.iter().cloned().map(Ok));
+                    streamer.handle_stream(stream, sender).await.expect("handle the stream")
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, process_buffer, handle_stream, large_event);
+criterion_main!(benches);
@@ -0,0 +1,136 @@
+use std::{borrow::Cow, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::tools::image::Image;
+
+/// The content of a message: plain text, or content parts when it includes other media, e.g. images.
+/// Serialized as a string or as the array of parts of the OpenAI format respectively.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+/// A part of the content of a message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImageUrl {
+    /// A `data:` URL with the encoded image, or a remote one
+    pub url: String,
+}
+
+impl MessageContent {
+    /// The text of the content, the text parts are joined by newlines
+    pub fn text(&self) -> Cow<'_, str> {
+        match self {
+            Self::Text(text) => Cow::Borrowed(text),
+            Self::Parts(parts) => Cow::Owned(
+                parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        ContentPart::Text { text } => Some(text.as_str()),
+                        ContentPart::ImageUrl { .. } => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+        }
+    }
+
+    /// Append `text` to the content, to the last text part if it has parts
+    pub fn push_str(&mut self, text: &str) {
+        match self {
+            Self::Text(content) => content.push_str(text),
+            Self::Parts(parts) => match parts.last_mut() {
+                Some(ContentPart::Text { text: last }) => last.push_str(text),
+                _ => parts.push(ContentPart::Text { text: text.to_string() }),
+            },
+        }
+    }
+
+    /// Add the `images` to the content, it is converted to parts if there is any
+    pub fn with_images(self, images: &[Image]) -> Self {
+        if images.is_empty() {
+            return self;
+        }
+
+        let mut parts = match self {
+            Self::Text(text) => vec![ContentPart::Text { text }],
+            Self::Parts(parts) => parts,
+        };
+        parts.extend(images.iter().map(|image| ContentPart::ImageUrl {
+            image_url: ImageUrl { url: image.data_url() },
+        }));
+        Self::Parts(parts)
+    }
+}
+
+impl Default for MessageContent {
+    fn default() -> Self {
+        Self::Text(String::new())
+    }
+}
+
+impl From<String> for MessageContent {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<&str> for MessageContent {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+impl Display for MessageContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text())
+    }
+}
+
+impl PartialEq<&str> for MessageContent {
+    fn eq(&self, other: &&str) -> bool {
+        self.text() == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_text_and_parts() {
+        let text = MessageContent::from("hello");
+        assert_eq!(serde_json::to_value(&text).expect("serialize text"), "hello");
+
+        let image = Image {
+            path: "pixel.png".to_string(),
+            media_type: "image/png".to_string(),
+            data: vec![0],
+        };
+        let parts = text.with_images(&[image]);
+        let json = serde_json::to_value(&parts).expect("serialize parts");
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"type": "text", "text": "hello"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,AA=="}}
+            ])
+        );
+
+        let parsed: MessageContent = serde_json::from_value(json).expect("deserialize parts");
+        assert_eq!(parsed, parts);
+        assert_eq!(parsed.text(), "hello");
+        let parsed: MessageContent = serde_json::from_str(r#""plain""#).expect("deserialize text");
+        assert_eq!(parsed, MessageContent::Text("plain".to_string()));
+    }
+}
@@ -1,41 +1,172 @@
 use std::{
     cell::RefCell,
+    collections::{BTreeMap, HashMap},
     env::current_dir,
     fmt::Display,
     fs::{File, create_dir_all},
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
 };
 
 use crate::{
     chat::prompts::GENERAL,
     cli::{commands::Command, handlers::CommandHandler},
-    client::provider::Provider,
+    client::{
+        ProviderError,
+        provider::{Provider, RequestOptions},
+    },
     tools::{
-        diff::{Diff, DiffsManager, Range},
-        files::{FileReader, TrackedFile},
+        diff::{DiffsManager, Range},
+        files::{FileOptions, FileReader, TrackedFile, ask_range, directory_document, file_tree, load_once_content},
+        git,
+        includes::follow_includes,
         reader::{Readable, ReaderTool},
+        url::{UrlReadable, UrlReader, is_url},
     },
 };
 use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
 use serde::{Deserialize, Serialize};
-use tokio::{io::AsyncWrite, sync::mpsc::channel};
-use tracing::{debug, error, info, trace};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc::channel,
+};
+use tracing::{debug, error, info, instrument, trace, warn};
 
 use super::{
+    content::MessageContent,
     errors::ChatError,
-    prompts::{CODE, COMMIT, GIT},
-    stream::Streamer,
+    history::HistoryPolicy,
+    prompts::{CHANGELOG, CODE, CommitLimits, GIT, PR, PR_DESCRIPTION},
+    request::PendingRequest,
+    stream::{DeadlineExceeded, PartialResponse, StreamEvent, Streamer, Usage, candidate_header, find_stop},
+    tokenizer,
 };
 
+/// Model used when the user does not select one
+pub const DEFAULT_MODEL: &str = "gpt-4.1";
+
+/// File in the cache directory that maps each cache key to its directory
+const CACHE_INDEX: &str = "index.json";
+
+/// Separates the cache key of the directory from the name of the thread in the cache files
+const THREAD_SEPARATOR: &str = "@";
+
+/// Max length of the readable part of a cache key
+const CACHE_KEY_PREFIX_LEN: usize = 32;
+
+/// Name of the cache files of `dir`: the directory name followed by a hash of the canonical path,
+/// so the length is bounded regardless of the depth of the directory
+fn cache_key(dir: &Path) -> String {
+    let canonical = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    let name: String = canonical
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        .take(CACHE_KEY_PREFIX_LEN)
+        .collect();
+    let hash: String = Sha256::digest(canonical.as_os_str().as_encoded_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    format!("{}-{}", name, hash)
+}
+
+/// The `content` of the attachment `name`, or a reference to it if the same content was already
+/// attached in the session
+fn attach_once(attached: &mut HashMap<String, Vec<u8>>, name: &str, content: String) -> String {
+    let hash = Sha256::digest(content.as_bytes()).to_vec();
+    if attached.get(name) == Some(&hash) {
+        debug!(%name, "Unchanged attachment, sending a reference");
+        return format!("File: {} (unchanged, its content was attached before)", name);
+    }
+
+    attached.insert(name.to_string(), hash);
+    content
+}
+
+/// The tree of the files of `root`, without the `exclude` names, the hidden entries and the paths
+/// ignored by git
+async fn project_tree(root: &Path, exclude: &[String]) -> anyhow::Result<String> {
+    let exclude = exclude.to_vec();
+    let files = CommandHandler::find_files_with_ext(root.to_path_buf(), None, &vec![], Some(&exclude))?;
+    // Outside of a repository nothing is ignored
+    let ignored = git::ignored_paths(root).await.unwrap_or_default();
+
+    let files: Vec<PathBuf> = files
+        .into_iter()
+        .map(PathBuf::from)
+        .filter(|file| {
+            let relative = file.strip_prefix(root).unwrap_or(file);
+            let hidden = relative
+                .components()
+                .any(|component| component.as_os_str().to_string_lossy().starts_with('.'));
+            !hidden && !git::is_ignored(&ignored, &relative.to_string_lossy())
+        })
+        .collect();
+
+    Ok(file_tree(root, &files))
+}
+
+/// Both paths name the same file, e.g. `src/main.rs` and `./src/main.rs`
+fn same_file(a: &str, b: &str) -> bool {
+    a == b || matches!((Path::new(a).canonicalize(), Path::new(b).canonicalize()), (Ok(a), Ok(b)) if a == b)
+}
+
+/// The path of a file argument without its range, e.g. `src/main.rs` of `src/main.rs:10-40`
+fn file_arg_path(arg: &str) -> &str {
+    match arg.split_once(':') {
+        Some((path, _)) if !is_url(arg) => path,
+        _ => arg,
+    }
+}
+
 /// Main Chat structure, contains all chat-related attributes and methods
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Chat<P: Provider> {
     messages: RefCell<Vec<Message>>,
     #[serde(skip)]
     provider: P,
+    #[serde(skip)]
+    options: RequestOptions,
+    /// Options of the last request, with its images, kept to re-send it if it fails
+    #[serde(skip)]
+    sent_options: RequestOptions,
+    #[serde(skip)]
+    file_options: FileOptions,
+    /// Do not include the prompt of the message type in a new chat
+    #[serde(skip)]
+    skip_task_prompt: bool,
+    /// Previous assistant turns sent with the requests
+    #[serde(skip)]
+    history_policy: HistoryPolicy,
     tracked_files: Vec<TrackedFile>,
+    /// Files that could not be attached, only filled when the file options keep going on errors
+    #[serde(skip)]
+    failed_files: Vec<String>,
+    /// Why the last response finished, as reported by the provider
+    #[serde(skip)]
+    finish_reason: Option<String>,
+    /// Tokens used by the last request, if the provider reports them
+    #[serde(skip)]
+    usage: Option<Usage>,
+    /// Model of the last request
+    #[serde(skip)]
+    model: Option<String>,
+    /// Named thread of the chat, the directory can keep several chats
+    #[serde(skip)]
+    thread: Option<String>,
+    /// Hash of the content of the directories and URLs attached in the session, by their name, so
+    /// they are not inlined again while they do not change
+    #[serde(skip)]
+    attached: HashMap<String, Vec<u8>>,
+    /// Files attached to every request of the session, with their range if it is set
+    #[serde(skip)]
+    attachments: Vec<String>,
 }
 
 impl<P: Provider + Default> Chat<P> {
@@ -44,7 +175,19 @@ impl<P: Provider + Default> Chat<P> {
         Self {
             messages: RefCell::new(vec![]),
             provider,
+            options: RequestOptions::default(),
+            sent_options: RequestOptions::default(),
+            file_options: FileOptions::default(),
+            skip_task_prompt: false,
+            history_policy: HistoryPolicy::default(),
             tracked_files: vec![],
+            failed_files: vec![],
+            finish_reason: None,
+            usage: None,
+            model: None,
+            thread: None,
+            attached: HashMap::new(),
+            attachments: vec![],
         }
     }
 
@@ -53,32 +196,150 @@ impl<P: Provider + Default> Chat<P> {
         self
     }
 
+    /// Set the parameters used in the requests
+    pub fn set_options(&mut self, options: RequestOptions) {
+        self.options = options;
+    }
+
+    /// Set how the files are attached to the requests
+    pub fn set_file_options(&mut self, file_options: FileOptions) {
+        self.file_options = file_options;
+    }
+
+    /// Skip the prompt of the message type (code, commit, ...) when a chat starts, only the
+    /// general prompt and the user messages are sent
+    pub fn set_skip_task_prompt(&mut self, skip: bool) {
+        self.skip_task_prompt = skip;
+    }
+
+    /// Set which of the previous assistant turns are sent with the requests
+    pub fn set_history_policy(&mut self, policy: HistoryPolicy) {
+        self.history_policy = policy;
+    }
+
+    /// Save the chat in the named `thread` of the directory instead of the default one
+    pub fn set_thread(&mut self, thread: Option<String>) {
+        self.thread = thread;
+    }
+
+    pub fn thread(&self) -> Option<&str> {
+        self.thread.as_deref()
+    }
+
+    /// Files skipped because they could not be attached
+    pub fn failed_files(&self) -> &[String] {
+        &self.failed_files
+    }
+
+    /// Why the last response finished, as reported by the provider
+    pub fn finish_reason(&self) -> Option<&str> {
+        self.finish_reason.as_deref()
+    }
+
+    /// Tokens used by the last request, if the provider reports them
+    pub fn usage(&self) -> Option<Usage> {
+        self.usage
+    }
+
+    /// Model of the last request, the default one of the provider if it didn't set one
+    pub fn model(&self) -> Option<&str> {
+        self.model.as_deref()
+    }
+
+    /// The last response was cut because it reached the max tokens
+    pub fn is_truncated(&self) -> bool {
+        self.finish_reason.as_deref() == Some("length")
+    }
+
+    /// Attach `file` to every next request of the session, e.g. `src/main.rs:10-40`; it replaces
+    /// the range of the file if it is already attached
+    pub fn attach(&mut self, file: &str) -> Result<(), ChatError> {
+        let path = file_arg_path(file);
+        if !is_url(file) && !Path::new(path).exists() {
+            return Err(ChatError::Tool(format!("{} does not exist", path)));
+        }
+
+        self.attachments
+            .retain(|attachment| !same_file(file_arg_path(attachment), path));
+        self.attachments.push(file.to_string());
+        Ok(())
+    }
+
+    /// Stop attaching `file` to the requests, it is also untracked so attaching it again sends
+    /// it whole. Returns whether it was attached.
+    pub fn detach(&mut self, file: &str) -> bool {
+        let path = file_arg_path(file);
+        let len = self.attachments.len();
+        self.attachments
+            .retain(|attachment| !same_file(file_arg_path(attachment), path));
+        self.tracked_files.retain(|tracked| !same_file(&tracked.path, path));
+
+        self.attachments.len() < len
+    }
+
+    /// Files attached to every request of the session
+    pub fn attachments(&self) -> &[String] {
+        &self.attachments
+    }
+
     pub fn add_message(&self, message: Message) {
         self.messages.borrow_mut().push(message);
     }
 
-    /// Try to load a chat for the current directory
-    pub fn try_load_chat(path: Option<&str>) -> Result<Option<Self>, ChatError> {
-        let cache = Self::get_cache_path(path)?;
-        let cwd = current_dir()?;
-        let encoded = percent_encode(
-            cwd.to_str()
-                .ok_or_else(|| ChatError::Cache(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid path")))?
-                .as_bytes(),
-            NON_ALPHANUMERIC,
-        );
-
-        let cache_file = cache.join(format!("{}.json", encoded));
+    /// Try to load a chat for the current directory, of the `thread` if it is set
+    pub fn try_load_chat(path: Option<&str>, thread: Option<&str>) -> Result<Option<Self>, ChatError> {
+        let cache_file = Self::chat_file(path, thread)?;
         if !cache_file.exists() {
             return Ok(None);
         }
 
         let chat_str = std::fs::read_to_string(&cache_file)?;
-        Ok(Some(serde_json::from_str(&chat_str)?))
+        let mut chat: Self = serde_json::from_str(&chat_str)?;
+        chat.thread = thread.map(str::to_string);
+        Ok(Some(chat))
+    }
+
+    /// Append to the chat the messages of the next request: the prompts if the chat is new, the
+    /// `message`, the attached files and the user prompt.
+    pub async fn assemble_messages(
+        &mut self,
+        message: Option<Message>,
+        message_type: &MessageType,
+    ) -> Result<(), ChatError> {
+        let task_prompt = (!self.skip_task_prompt).then_some(message_type);
+        let mut builder = prepare_builder(&self.provider, &self.messages, message, task_prompt)?;
+        let failed_files = Self::handle_files(
+            &mut self.tracked_files,
+            &mut self.attached,
+            &self.attachments,
+            &self.file_options,
+            message_type,
+            &mut builder,
+        )
+        .await?;
+        self.failed_files.extend(failed_files);
+        if let Some(user_message) = message_type.resolve_user_prompt() {
+            builder.with(user_message);
+        }
+        Ok(())
+    }
+
+    /// Append a message outside of a request, e.g. a system instruction. A new chat gets the prompts
+    /// of `message_type` first, so they still open the conversation.
+    pub fn add_instruction(&self, message: Message, message_type: &MessageType) -> Result<(), ChatError> {
+        let task_prompt = (!self.skip_task_prompt).then_some(message_type);
+        prepare_builder(&self.provider, &self.messages, Some(message), task_prompt)?;
+        Ok(())
+    }
+
+    /// All the messages of the chat
+    pub fn messages(&self) -> Vec<Message> {
+        self.messages.borrow().clone()
     }
 
     /// Send a message to Copilot and write the response to `Stdout` using the streamed data
     /// also returns the `Assistant` message when it is ready.
+    #[instrument(level = "debug", name = "completion", skip_all)]
     pub async fn send_message_with_stream(
         &mut self,
         model: Option<&str>,
@@ -87,25 +348,35 @@ impl<P: Provider + Default> Chat<P> {
         streamer: impl Streamer + 'static,
         mut writer: impl AsyncWrite + Send + Unpin + 'static,
     ) -> Result<Message, ChatError> {
-        let mut builder = prepare_builder(&self.provider, &self.messages, message, &message_type)?;
-        Self::handle_files(&mut self.tracked_files, &message_type, &mut builder).await?;
-        if let Some(user_message) = message_type.resolve_user_prompt() {
-            builder.with(user_message);
-        }
+        self.assemble_messages(message, &message_type).await?;
+        // The policy only changes what is sent, the chat keeps all the messages
+        let history = RefCell::new(self.history_policy.apply(&self.messages.borrow()));
 
-        let model_to_use = model.unwrap_or("gpt-4.1");
+        let model_to_use = model.unwrap_or(self.provider.default_model()).to_string();
         info!(model=%model_to_use, "Using");
-
-        // TODO: Validate model
+        let options = self.request_options(&model_to_use).await?;
+        self.model = Some(model_to_use.clone());
+        let candidates = options.candidates.unwrap_or(1);
+        let builder = self.provider.builder(&history);
 
         trace!("sending request to copilot");
-        let stream = builder
-            .request(model_to_use)
-            .await
-            .map_err(|e| ChatError::Provider(e.to_string()))?;
+        let request = builder.request(&model_to_use, &options);
+        let stream = match streamer.deadline() {
+            Some(deadline) => tokio::time::timeout_at(deadline, request)
+                .await
+                .map_err(|_| ChatError::Deadline { content: String::new() })?,
+            None => request.await,
+        }
+        .map_err(provider_error)?;
 
         debug!("Creating channels");
         let (sender, receiver) = channel(32);
+        if candidates > 1 {
+            sender
+                .send(StreamEvent::Content(candidate_header(0)))
+                .await
+                .map_err(|e| ChatError::Stream(e.to_string()))?;
+        }
 
         let streamer_clone = streamer.clone();
 
@@ -122,15 +393,242 @@ impl<P: Provider + Default> Chat<P> {
         info!("Collecting message");
 
         // Collect the message
-        let message = streamer
+        let response = streamer
             .handle_stream(std::pin::pin!(stream), sender)
             .await
-            .map_err(|e| ChatError::Stream(e.to_string()))?;
+            .map_err(|e| {
+                let e = match e.downcast::<DeadlineExceeded>() {
+                    Ok(deadline) => {
+                        return ChatError::Deadline {
+                            content: deadline.message.content.to_string(),
+                        };
+                    }
+                    Err(e) => e,
+                };
+                match e.downcast::<PartialResponse>() {
+                    Ok(partial) => ChatError::PartialStream {
+                        content: partial.message.content.to_string(),
+                        reason: partial.reason,
+                    },
+                    Err(e) => ChatError::Stream(e.to_string()),
+                }
+            });
 
+        // The writer finishes the content received before a failure too
         job.await?;
+        let response = response?;
+
+        info!(finish_reason = ?response.finish_reason, "Message collected");
+        self.finish_reason = response.finish_reason;
+        self.usage = response.usage;
+        Ok(response.message)
+    }
+
+    /// Send a message to Copilot without streaming and write the whole response to `writer` at
+    /// once, also returns the `Assistant` message. The request fails if it is not complete before
+    /// the `deadline`.
+    #[instrument(level = "debug", name = "completion", skip_all)]
+    pub async fn send_message(
+        &mut self,
+        model: Option<&str>,
+        message: Option<Message>,
+        message_type: MessageType,
+        streamer: &impl Streamer,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> Result<Message, ChatError> {
+        self.assemble_messages(message, &message_type).await?;
+        let history = RefCell::new(self.history_policy.apply(&self.messages.borrow()));
+
+        let model_to_use = model.unwrap_or(self.provider.default_model()).to_string();
+        info!(model=%model_to_use, "Using");
+        let options = self.request_options(&model_to_use).await?;
+        self.model = Some(model_to_use.clone());
 
-        info!("Message collected");
-        Ok(message)
+        trace!("sending request to copilot without streaming");
+        let request = self.provider.complete(&model_to_use, &history, &options);
+        let mut completion = match streamer.deadline() {
+            Some(deadline) => tokio::time::timeout_at(deadline, request)
+                .await
+                .map_err(|_| ChatError::Deadline { content: String::new() })?,
+            None => request.await,
+        }
+        .map_err(provider_error)?;
+
+        // The same truncation as a streamed response, in case the provider doesn't stop
+        for (index, choice) in completion.choices.iter_mut().enumerate() {
+            let mut text = choice.content.text().into_owned();
+            if let Some(end) = find_stop(&text, streamer.stop_sequences()) {
+                text.truncate(end);
+                choice.content = text.into();
+                if index == 0 {
+                    completion.finish_reason = Some("stop".to_string());
+                }
+            }
+        }
+
+        let content = match completion.choices.as_slice() {
+            [choice] => choice.content.text().into_owned(),
+            choices => choices
+                .iter()
+                .enumerate()
+                .map(|(index, choice)| format!("{}{}", candidate_header(index), choice.content.text()))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        let write_error = |e: std::io::Error| ChatError::Stream(e.to_string());
+        writer.write_all(content.as_bytes()).await.map_err(write_error)?;
+        writer.flush().await.map_err(write_error)?;
+
+        info!(finish_reason = ?completion.finish_reason, "Message received");
+        self.finish_reason = completion.finish_reason;
+        self.usage = completion.usage;
+        completion
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| ChatError::Stream("The response has no choices".to_string()))
+    }
+
+    /// The options of the next request to `model`, with the max tokens clamped to the limit of
+    /// the model. Fails if the model does not accept the candidates, images or schema.
+    async fn request_options(&mut self, model: &str) -> Result<RequestOptions, ChatError> {
+        if let Some(max_tokens) = self.options.max_tokens {
+            self.options.max_tokens = Some(self.clamp_max_tokens(model, max_tokens).await);
+        }
+
+        if self.options.candidates.unwrap_or(1) > 1 {
+            self.validate_candidates(model).await?;
+        }
+        if !self.options.images.is_empty() {
+            self.validate_images(model).await?;
+        }
+        if self.options.schema.is_some() {
+            self.validate_structured_outputs(model).await?;
+        }
+
+        // The images are only sent with the first request
+        let images = std::mem::take(&mut self.options.images);
+        self.sent_options = RequestOptions {
+            images,
+            ..self.options.clone()
+        };
+        Ok(self.sent_options.clone())
+    }
+
+    /// Estimate the prompt tokens of the chat with the tokenizer of `model`, returns the count
+    /// and the name of the tokenizer used
+    pub async fn estimate_tokens(&self, model: Option<&str>) -> (usize, String) {
+        let model = model.unwrap_or(self.provider.default_model());
+        let name = self.provider.tokenizer(model).await.unwrap_or_else(|e| {
+            warn!(%e, "The tokenizer of the model cannot be retrieved");
+            None
+        });
+
+        let tokenizer = tokenizer::for_model(name.as_deref());
+        let tokens = tokenizer::estimate_tokens(tokenizer.as_ref(), &self.messages.borrow());
+        (tokens, tokenizer.name().to_string())
+    }
+
+    /// Fail if `model` is known to generate only one completion per request
+    async fn validate_candidates(&self, model: &str) -> Result<(), ChatError> {
+        match self.provider.supports_candidates(model).await {
+            Ok(Some(false)) => Err(ChatError::Request(format!(
+                "{} does not support multiple candidates",
+                model
+            ))),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                warn!(%e, "Model capabilities cannot be retrieved, skipping validation");
+                Ok(())
+            }
+        }
+    }
+
+    /// Fail if `model` is known to not follow a JSON schema for the response
+    async fn validate_structured_outputs(&self, model: &str) -> Result<(), ChatError> {
+        match self.provider.structured_outputs(model).await {
+            Ok(Some(false)) => Err(ChatError::Request(format!(
+                "{} does not support structured outputs",
+                model
+            ))),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                warn!(%e, "Model capabilities cannot be retrieved, skipping validation");
+                Ok(())
+            }
+        }
+    }
+
+    /// Check that the content of `message` follows the schema of the requests, if it is set
+    pub fn validate_response(&self, message: &Message) -> Result<(), ChatError> {
+        match &self.options.schema {
+            Some(schema) => schema.validate(&message.content.text()).map_err(ChatError::Schema),
+            None => Ok(()),
+        }
+    }
+
+    /// Fail if `model` does not accept the images of the request, their media types, sizes or count
+    async fn validate_images(&self, model: &str) -> Result<(), ChatError> {
+        let vision = match self.provider.vision(model).await {
+            Ok(Some(vision)) => vision,
+            Ok(None) => return Err(ChatError::Request(format!("{} does not accept images", model))),
+            Err(e) => {
+                warn!(%e, "Model capabilities cannot be retrieved, skipping validation");
+                return Ok(());
+            }
+        };
+
+        let images = &self.options.images;
+        if let Some(max_images) = vision.max_images
+            && images.len() > max_images
+        {
+            return Err(ChatError::Request(format!(
+                "{} accepts up to {} images, {} given",
+                model,
+                max_images,
+                images.len()
+            )));
+        }
+
+        for image in images {
+            if !vision.media_types.is_empty() && !vision.media_types.contains(&image.media_type) {
+                return Err(ChatError::Request(format!(
+                    "{} does not accept {} images ({}), supported: {}",
+                    model,
+                    image.media_type,
+                    image.path,
+                    vision.media_types.join(", ")
+                )));
+            }
+            if let Some(max_size) = vision.max_image_size
+                && image.data.len() > max_size
+            {
+                return Err(ChatError::Request(format!(
+                    "{} exceeds the max image size of {} bytes",
+                    image.path, max_size
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Limit `max_tokens` to the max output tokens allowed by the model, warning the user if it exceeds it
+    async fn clamp_max_tokens(&self, model: &str, max_tokens: i32) -> i32 {
+        match self.provider.max_output_tokens(model).await {
+            Ok(Some(limit)) if max_tokens > limit => {
+                warn!(%max_tokens, %limit, "Max response tokens exceeds the model limit");
+                eprintln!(
+                    "Warning: {} max response tokens exceeds the limit of {} for {}; using {}",
+                    max_tokens, limit, model, limit
+                );
+                limit
+            }
+            Ok(_) => max_tokens,
+            Err(e) => {
+                warn!(%e, "Model limits cannot be retrieved, skipping validation");
+                max_tokens
+            }
+        }
     }
 
     /// Save the chat for the current directory
@@ -139,17 +637,10 @@ impl<P: Provider + Default> Chat<P> {
         create_dir_all(&cache)?;
         info!(?cache, "Saving chat");
 
-        let cwd = current_dir()?;
-        let encoded = percent_encode(
-            cwd.to_str()
-                .ok_or_else(|| ChatError::Cache(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid path")))?
-                .as_bytes(),
-            NON_ALPHANUMERIC,
-        );
-
-        let cache_file = cache.join(format!("{}.json", encoded));
+        let cache_file = Self::chat_file(path, self.thread())?;
         let mut file = File::create(&cache_file)?;
         file.write_all(serde_json::to_string(self)?.as_bytes())?;
+        Self::update_cache_index(&cache)?;
         info!(?cache_file, "Chat saved successfully");
         Ok(())
     }
@@ -159,25 +650,18 @@ impl<P: Provider + Default> Chat<P> {
         let cache = Self::get_cache_path(path)?;
         info!(?cache, "Deleting chat");
 
-        let cwd = current_dir()?;
-        let encoded = percent_encode(
-            cwd.to_str()
-                .ok_or_else(|| ChatError::Cache(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid path")))?
-                .as_bytes(),
-            NON_ALPHANUMERIC,
-        );
-
-        let cache_file = cache.join(format!("{}.json", encoded));
+        let cache_file = Self::chat_file(path, self.thread())?;
         if cache_file.exists() {
             std::fs::remove_file(&cache_file)?;
             info!(?cache_file, "Chat deleted successfully");
         } else {
             info!(?cache_file, "Chat not found; skipping deletion.");
         }
-        Ok(())
+        Self::prune_cache_index(&cache, path)
     }
 
-    fn get_cache_path(path: Option<&str>) -> Result<PathBuf, ChatError> {
+    /// Resolve the directory where the chats are stored, `~/.cache/copilot-chat` by default
+    pub fn get_cache_path(path: Option<&str>) -> Result<PathBuf, ChatError> {
         if let Some(path) = path {
             PathBuf::from_str(path)
                 .map_err(|e| ChatError::Cache(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))
@@ -193,34 +677,285 @@ impl<P: Provider + Default> Chat<P> {
         }
     }
 
+    /// The file where the chat of the current directory is stored, of the `thread` if it is set
+    pub fn chat_file(path: Option<&str>, thread: Option<&str>) -> Result<PathBuf, ChatError> {
+        Self::get_cache_file(path, thread, "json")
+    }
+
+    /// The same file as [`Chat::chat_file`] without touching the cache, a chat named with the
+    /// previous scheme is not migrated
+    pub fn chat_file_path(path: Option<&str>, thread: Option<&str>) -> Result<PathBuf, ChatError> {
+        Self::cache_file_path(path, thread, "json")
+    }
+
+    /// The threads saved for the current directory, sorted; `None` is the default thread
+    pub fn list_threads(path: Option<&str>) -> Result<Vec<Option<String>>, ChatError> {
+        let cache = Self::get_cache_path(path)?;
+        if !cache.exists() {
+            return Ok(vec![]);
+        }
+
+        let key = cache_key(&current_dir()?);
+        let mut threads = vec![];
+        for entry in std::fs::read_dir(&cache)? {
+            let name = entry?.file_name();
+            let Some(stem) = name.to_str().and_then(|name| name.strip_suffix(".json")) else {
+                continue;
+            };
+            if stem == key {
+                threads.push(None);
+            } else if let Some(thread) = stem.strip_prefix(&key).and_then(|rest| rest.strip_prefix(THREAD_SEPARATOR))
+                // Other files of the thread have a longer extension, e.g. `.pending.json`
+                && !thread.contains('.')
+            {
+                threads.push(Some(thread.to_string()));
+            }
+        }
+        threads.sort();
+        Ok(threads)
+    }
+
+    /// Resolve the cache file of the current directory with the given `extension`, of the `thread`
+    /// if it is set. A file named with the previous scheme, the percent-encoded directory, is
+    /// renamed to the current one.
+    fn get_cache_file(path: Option<&str>, thread: Option<&str>, extension: &str) -> Result<PathBuf, ChatError> {
+        let cache = Self::get_cache_path(path)?;
+        let cwd = current_dir()?;
+        let cache_file = Self::cache_file_path(path, thread, extension)?;
+
+        // The threads are newer than the previous scheme
+        if !cache_file.exists() && thread.is_none() {
+            let encoded = percent_encode(
+                cwd.to_str()
+                    .ok_or_else(|| {
+                        ChatError::Cache(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid path"))
+                    })?
+                    .as_bytes(),
+                NON_ALPHANUMERIC,
+            );
+            let legacy_file = cache.join(format!("{}.{}", encoded, extension));
+            if legacy_file.exists() {
+                info!(?legacy_file, ?cache_file, "Migrating the cache file");
+                std::fs::rename(&legacy_file, &cache_file)?;
+            }
+        }
+
+        Ok(cache_file)
+    }
+
+    /// The cache file of the current directory with the given `extension`, of the `thread` if it is set
+    fn cache_file_path(path: Option<&str>, thread: Option<&str>, extension: &str) -> Result<PathBuf, ChatError> {
+        let cache = Self::get_cache_path(path)?;
+        let cwd = current_dir()?;
+        let key = match thread {
+            Some(thread) => format!("{}{}{}", cache_key(&cwd), THREAD_SEPARATOR, thread),
+            None => cache_key(&cwd),
+        };
+        Ok(cache.join(format!("{}.{}", key, extension)))
+    }
+
+    /// Record the directory of the current cache key in the index of the cache, the keys are hashed
+    /// so they cannot be reversed
+    fn update_cache_index(cache: &Path) -> Result<(), ChatError> {
+        let cwd = current_dir()?;
+        let index_file = cache.join(CACHE_INDEX);
+        let mut index: BTreeMap<String, PathBuf> = if index_file.exists() {
+            serde_json::from_str(&std::fs::read_to_string(&index_file)?)?
+        } else {
+            BTreeMap::new()
+        };
+
+        let key = cache_key(&cwd);
+        if index.get(&key) != Some(&cwd) {
+            index.insert(key, cwd);
+            std::fs::write(&index_file, serde_json::to_string_pretty(&index)?)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the current directory from the index of the cache once none of its chats is left
+    fn prune_cache_index(cache: &Path, path: Option<&str>) -> Result<(), ChatError> {
+        let index_file = cache.join(CACHE_INDEX);
+        if !index_file.exists() || !Self::list_threads(path)?.is_empty() {
+            return Ok(());
+        }
+
+        let mut index: BTreeMap<String, PathBuf> = serde_json::from_str(&std::fs::read_to_string(&index_file)?)?;
+        if index.remove(&cache_key(&current_dir()?)).is_some() {
+            std::fs::write(&index_file, serde_json::to_string_pretty(&index)?)?;
+        }
+        Ok(())
+    }
+
+    /// Persist the messages and options of the current request, so it can be re-sent if it fails
+    pub fn save_pending_request(
+        &self,
+        model: Option<&str>,
+        message_type: &MessageType,
+        path: Option<&str>,
+    ) -> Result<(), ChatError> {
+        let cache = Self::get_cache_path(path)?;
+        create_dir_all(&cache)?;
+
+        let request = PendingRequest {
+            model: model.map(|m| m.to_string()),
+            options: self.sent_options.clone(),
+            messages: self.messages.borrow().clone(),
+            code_chat: matches!(message_type, MessageType::Code { .. }),
+        };
+
+        let cache_file = Self::get_cache_file(path, self.thread(), "pending.json")?;
+        let mut file = File::create(&cache_file)?;
+        file.write_all(serde_json::to_string(&request)?.as_bytes())?;
+        info!(?cache_file, "Pending request saved");
+        Ok(())
+    }
+
+    /// Try to load the pending request of the current directory, of the `thread` if it is set
+    pub fn try_load_pending_request(
+        path: Option<&str>,
+        thread: Option<&str>,
+    ) -> Result<Option<PendingRequest>, ChatError> {
+        let cache_file = Self::get_cache_file(path, thread, "pending.json")?;
+        if !cache_file.exists() {
+            return Ok(None);
+        }
+
+        let request_str = std::fs::read_to_string(&cache_file)?;
+        Ok(Some(serde_json::from_str(&request_str)?))
+    }
+
+    /// Delete the pending request of the current directory, of the `thread` if it is set
+    pub fn remove_pending_request(path: Option<&str>, thread: Option<&str>) -> Result<(), ChatError> {
+        let cache_file = Self::get_cache_file(path, thread, "pending.json")?;
+        if cache_file.exists() {
+            std::fs::remove_file(&cache_file)?;
+            debug!(?cache_file, "Pending request removed");
+        }
+        Ok(())
+    }
+
+    /// Replace all the messages of the chat
+    pub fn set_messages(&self, messages: Vec<Message>) {
+        self.messages.replace(messages);
+    }
+
+    /// Keep only the first `len` messages of the chat
+    pub fn truncate_messages(&self, len: usize) {
+        self.messages.borrow_mut().truncate(len);
+    }
+
     async fn handle_files<'a>(
         tracked_files: &mut Vec<TrackedFile>,
+        attached: &mut HashMap<String, Vec<u8>>,
+        attachments: &[String],
+        file_options: &FileOptions,
         message_type: &MessageType,
         builder: &mut Builder<'a, P>,
-    ) -> Result<(), ChatError> {
-        if let MessageType::Code { files: Some(files), .. } = message_type {
-            for file in files {
+    ) -> Result<Vec<String>, ChatError> {
+        let mut failed_files = vec![];
+        // The structure of the project goes before the files
+        if let MessageType::Code { .. } = message_type
+            && let Some(root) = &file_options.file_tree
+        {
+            let tree = project_tree(root, &file_options.exclude)
+                .await
+                .map_err(|e| ChatError::Tool(e.to_string()))?;
+            info!(?root, "Sending the file tree to copilot");
+            builder.with(Message {
+                content: attach_once(attached, &format!("tree of {}", root.display()), tree).into(),
+                role: Role::User,
+            });
+        }
+        if let MessageType::Code { files, .. } = message_type {
+            // The attachments of the session go with every request, after the files of the prompt
+            let mut files: Vec<String> = files.iter().flatten().cloned().collect();
+            for attachment in attachments {
+                if !files
+                    .iter()
+                    .any(|file| same_file(file_arg_path(file), file_arg_path(attachment)))
+                {
+                    files.push(attachment.clone());
+                }
+            }
+            let mut files = match file_options.follow_includes {
+                Some(depth) => follow_includes(&files, depth),
+                None => files,
+            };
+            if files.len() > file_options.max_files {
+                warn!(
+                    count = files.len(),
+                    max = file_options.max_files,
+                    "Too many files, skipping the rest"
+                );
+                eprintln!(
+                    "Warning: only the first {} of {} files are attached",
+                    file_options.max_files,
+                    files.len()
+                );
+                files.truncate(file_options.max_files);
+            }
+
+            for file in &files {
                 debug!(%file, "Processing file");
-                Self::process_file(tracked_files, file, builder).await?;
+                match Self::process_file(tracked_files, attached, file_options, file, builder).await {
+                    Ok(()) => {}
+                    Err(e) if file_options.keep_going => {
+                        warn!(%file, %e, "File cannot be attached, skipping");
+                        eprintln!("Warning: {} cannot be attached: {}", file, e);
+                        failed_files.push(file.to_string());
+                    }
+                    Err(e) => return Err(e),
+                }
             }
         }
-        Ok(())
+        Ok(failed_files)
     }
 
     async fn process_file<'a>(
         tracked_files: &mut Vec<TrackedFile>,
+        attached: &mut HashMap<String, Vec<u8>>,
+        file_options: &FileOptions,
         file: &str,
         builder: &mut Builder<'a, P>,
     ) -> Result<(), ChatError> {
-        let reader = FileReader;
-        let range = Range::from_file_arg(file);
-        let file_path = if let Some((path, _)) = file.split_once(':') {
-            path
-        } else {
-            file
+        if is_url(file) {
+            return Self::process_url(file, attached, file_options, builder).await;
+        }
+        if Path::new(file).is_dir() {
+            // Outside of a repository nothing is ignored
+            let ignored = git::ignored_paths(Path::new(file)).await.unwrap_or_default();
+            let content = directory_document(Path::new(file), file_options, &ignored)
+                .map_err(|e| ChatError::Tool(e.to_string()))?;
+            info!(%file, "Sending directory to copilot");
+            builder.with(Message {
+                content: attach_once(attached, file, content).into(),
+                role: Role::User,
+            });
+            return Ok(());
+        }
+
+        let reader = FileReader::new(file_options.clock);
+        let file_path = file_arg_path(file);
+        let tracked = tracked_files.iter().position(|p| same_file(&p.path, file_path));
+        // Only asked when the file is attached, the next requests send its changes
+        let range = match Range::from_file_arg(file) {
+            None if file_options.interactive_range
+                && tracked.is_none()
+                && atty::is(atty::Stream::Stdin)
+                && atty::is(atty::Stream::Stdout) =>
+            {
+                let mut readable = TrackedFile::from_file_arg(file, file_options.clock);
+                reader
+                    .read(&mut readable)
+                    .await
+                    .map_err(|e| ChatError::Tool(e.to_string()))?;
+                ask_range(&readable).await.map_err(|e| ChatError::Tool(e.to_string()))?
+            }
+            range => range,
         };
 
-        if let Some(index) = tracked_files.iter().position(|p| p.path == file_path) {
+        if let Some(index) = tracked {
             let mut tracked_file = tracked_files.remove(index);
 
             if tracked_file.content().is_empty() {
@@ -245,7 +980,7 @@ impl<P: Provider + Default> Chat<P> {
             if let Some(diff_man) = diff_man {
                 info!("Differences found, sending to copilot");
                 debug!("Differences: {:?}", diff_man);
-                builder.with_diffs(&diff_man, tracked_file.location());
+                builder.with_diffs(&diff_man, tracked_file.location(), file_options);
             } else {
                 debug!("No differences found, skipping the update.");
             }
@@ -256,14 +991,14 @@ impl<P: Provider + Default> Chat<P> {
                     .await
                     .map_err(|e| ChatError::Tool(e.to_string()))?;
                 builder.with(Message {
-                    content: file_content,
+                    content: file_content.into(),
                     role: Role::User,
                 });
             }
 
             tracked_files.insert(index, tracked_file);
         } else {
-            let mut tracked_file = TrackedFile::from_file_arg(file);
+            let mut tracked_file = TrackedFile::from_file_arg(file, file_options.clock);
             reader
                 .read(&mut tracked_file)
                 .await
@@ -273,11 +1008,11 @@ impl<P: Provider + Default> Chat<P> {
             info!(?file_path, "File not tracked, sending to copilot");
 
             let load_content = tracked_file
-                .prepare_load_once()
+                .prepare_load_once(file_options)
                 .await
                 .map_err(|e| ChatError::Tool(e.to_string()))?;
             builder.with(Message {
-                content: load_content,
+                content: load_content.into(),
                 role: Role::User,
             });
 
@@ -288,7 +1023,7 @@ impl<P: Provider + Default> Chat<P> {
                     .await
                     .map_err(|e| ChatError::Tool(e.to_string()))?;
                 builder.with(Message {
-                    content: copilot_content,
+                    content: copilot_content.into(),
                     role: Role::User,
                 });
             }
@@ -297,25 +1032,55 @@ impl<P: Provider + Default> Chat<P> {
         }
         Ok(())
     }
+
+    /// Fetch and attach a remote file, it is not tracked so it is sent whole again when it changes
+    async fn process_url<'a>(
+        url: &str,
+        attached: &mut HashMap<String, Vec<u8>>,
+        file_options: &FileOptions,
+        builder: &mut Builder<'a, P>,
+    ) -> Result<(), ChatError> {
+        let mut readable = UrlReadable::new(url);
+        UrlReader::new(builder.client.http_client())
+            .read(&mut readable)
+            .await
+            .map_err(|e| ChatError::Tool(e.to_string()))?;
+
+        info!(%url, "Sending URL content to copilot");
+        builder.with(Message {
+            content: attach_once(attached, url, load_once_content(&readable, file_options)).into(),
+            role: Role::User,
+        });
+        Ok(())
+    }
+}
+
+/// The error of a failed request, keeping the [`ProviderError`] if it is one
+fn provider_error(error: anyhow::Error) -> ChatError {
+    match error.downcast::<ProviderError>() {
+        Ok(error) => ChatError::Provider(error),
+        Err(e) => ChatError::Provider(ProviderError::other(e.to_string())),
+    }
 }
 
 fn prepare_builder<'a, P: Provider>(
     provider: &'a P,
     messages: &'a RefCell<Vec<Message>>,
     message: Option<Message>,
-    message_type: &MessageType,
+    task_prompt: Option<&MessageType>,
 ) -> Result<Builder<'a, P>, ChatError> {
     let mut builder = provider.builder(messages);
     if builder.messages.borrow().is_empty() {
-        builder
-            .with(Message {
-                role: Role::User,
-                content: GENERAL.to_string(),
-            })
-            .with(Message {
+        builder.with(Message {
+            role: Role::User,
+            content: GENERAL.into(),
+        });
+        if let Some(message_type) = task_prompt {
+            builder.with(Message {
                 role: Role::User,
-                content: message_type.to_string(),
+                content: message_type.to_string().into(),
             });
+        }
     }
     message.map(|m| builder.with(m));
 
@@ -326,7 +1091,7 @@ fn prepare_builder<'a, P: Provider>(
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     pub role: Role,
-    pub content: String,
+    pub content: MessageContent,
 }
 
 /// The sender of the message
@@ -340,6 +1105,17 @@ pub enum Role {
     User,
 }
 
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let role = match self {
+            Role::System => "system",
+            Role::Assistant => "assistant",
+            Role::User => "user",
+        };
+        write!(f, "{}", role)
+    }
+}
+
 /// A builder for the initial prompt
 pub struct Builder<'a, P: Provider> {
     client: &'a P,
@@ -363,35 +1139,29 @@ impl<'a, P: Provider> Builder<'a, P> {
     pub async fn request(
         &self,
         model: &str,
+        options: &RequestOptions,
     ) -> anyhow::Result<impl futures_util::Stream<Item = reqwest::Result<bytes::Bytes>>> {
-        self.client.request(model, self.messages).await
+        self.client.request(model, self.messages, options).await
     }
 
-    pub fn with_diffs(&mut self, diff_man: &DiffsManager, filename: &str) -> &mut Self {
-        if diff_man.diffs.is_empty() {
+    /// Attach the changes of a file, rendered as configured in `file_options`
+    pub fn with_diffs(&mut self, diff_man: &DiffsManager, filename: &str, file_options: &FileOptions) -> &mut Self {
+        let diffs = diff_man.to_string_with(file_options.diff_style, file_options.diff_context);
+        if diffs.is_empty() {
             debug!("There is not differences, skipping attach them");
             return self;
         }
 
-        let mut content = format!(
+        let content = format!(
             "Here the updates of the file {}:
 
-",
-            filename
+{}",
+            filename, diffs
         );
 
-        for diff in diff_man.diffs.iter() {
-            // Skip the unchanged lines
-            if let Diff::Match(_) = diff {
-                continue;
-            };
-
-            content.push_str(&diff.to_string());
-        }
-
         let message = Message {
             role: Role::User,
-            content,
+            content: content.into(),
         };
 
         debug!(?message, "Attaching differences");
@@ -403,9 +1173,11 @@ impl<'a, P: Provider> Builder<'a, P> {
 /// Message type to be sent to Copilot
 /// each type include an user prompt
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 pub enum MessageType {
-    Commit(Option<String>),
+    Commit(Option<String>, CommitLimits),
+    Pr(Option<String>),
+    PrDescription(Option<String>),
+    Changelog(Option<String>),
     Code {
         user_prompt: Option<String>,
         files: Option<Vec<String>>,
@@ -413,21 +1185,54 @@ pub enum MessageType {
     Git(Option<String>),
 }
 
-impl<'a> From<&CommandHandler<'_>> for MessageType {
+/// Map each command to the message type of its requests; the code requests (no command or the TCP
+/// server) also carry the files of the CLI, with the patterns expanded.
+impl From<&CommandHandler<'_>> for MessageType {
     fn from(value: &CommandHandler<'_>) -> Self {
+        let user_prompt = value.user_prompt.map(|prompt| value.cli_command.wrap_prompt(prompt));
+
         match value.cli_command.command {
-            Some(Command::Commit) => MessageType::Commit(value.user_prompt.map(|s| s.to_string())),
-            Some(Command::Models) | Some(Command::Clear) => MessageType::default(),
-            None => MessageType::Code {
-                user_prompt: value.user_prompt.map(|s| s.to_string()),
+            Some(Command::Commit {
+                since: None,
+                subject_max,
+                body_min,
+                body_max,
+                ..
+            }) => MessageType::Commit(
+                user_prompt,
+                CommitLimits {
+                    subject_max,
+                    body_min,
+                    body_max,
+                },
+            ),
+            Some(Command::Commit { since: Some(_), .. }) => MessageType::Pr(user_prompt),
+            Some(Command::PrDescription { .. }) => MessageType::PrDescription(user_prompt),
+            Some(Command::Changelog { .. }) => MessageType::Changelog(user_prompt),
+            Some(Command::Git) => MessageType::Git(user_prompt),
+            Some(Command::Tcp { .. } | Command::Resume) | None => MessageType::Code {
+                user_prompt,
                 files: CommandHandler::expand_files_from_dir(
-                    &current_dir().unwrap_or(PathBuf::new()),
+                    &value
+                        .cli_command
+                        .root
+                        .clone()
+                        .unwrap_or_else(|| current_dir().unwrap_or_default()),
                     value.cli_command.files.as_ref(),
                     value.cli_command.exclude.as_ref(),
                 )
                 .unwrap_or(None),
             },
-            _ => MessageType::default(),
+            // These commands do not send requests
+            Some(
+                Command::Models { .. }
+                | Command::Prompts
+                | Command::Whoami
+                | Command::Login
+                | Command::Clear
+                | Command::Threads
+                | Command::Compact { .. },
+            ) => MessageType::default(),
         }
     }
 }
@@ -445,7 +1250,10 @@ impl Display for MessageType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let prompt = match self {
             MessageType::Code { .. } => CODE,
-            MessageType::Commit(_) => COMMIT,
+            MessageType::Commit(_, limits) => return write!(f, "{}", limits.commit_prompt()),
+            MessageType::Pr(_) => PR,
+            MessageType::PrDescription(_) => PR_DESCRIPTION,
+            MessageType::Changelog(_) => CHANGELOG,
             MessageType::Git(_) => GIT,
         };
         write!(f, "{}", prompt)
@@ -454,25 +1262,43 @@ impl Display for MessageType {
 
 impl MessageType {
     fn resolve_user_prompt(&self) -> Option<Message> {
+        self.user_prompt().map(|content| Message {
+            role: Role::User,
+            content: content.into(),
+        })
+    }
+
+    /// The prompt written by the user, as it is sent
+    pub fn user_prompt(&self) -> Option<&str> {
         let prompt = match self {
             MessageType::Code { user_prompt, .. } => user_prompt,
-            MessageType::Commit(user_prompt) => user_prompt,
+            MessageType::Commit(user_prompt, _) => user_prompt,
+            MessageType::Pr(user_prompt) => user_prompt,
+            MessageType::PrDescription(user_prompt) => user_prompt,
+            MessageType::Changelog(user_prompt) => user_prompt,
             MessageType::Git(user_prompt) => user_prompt,
         };
 
-        prompt.as_ref().map(|content| Message {
-            role: Role::User,
-            content: content.to_string(),
-        })
+        prompt.as_deref()
     }
 
+    /// The request carries a prompt written by the user
+    pub fn has_user_prompt(&self) -> bool {
+        self.user_prompt().is_some()
+    }
+
+    /// Remove the user prompt so it is not sent again in the next turns, the files of a code
+    /// request are kept to track their updates
     pub fn clear_user_prompt(&mut self) {
         *self = match std::mem::take(self) {
             MessageType::Code { user_prompt: _, files } => MessageType::Code {
                 user_prompt: None,
                 files,
             },
-            MessageType::Commit(_) => MessageType::Commit(None),
+            MessageType::Commit(_, limits) => MessageType::Commit(None, limits),
+            MessageType::Pr(_) => MessageType::Pr(None),
+            MessageType::PrDescription(_) => MessageType::PrDescription(None),
+            MessageType::Changelog(_) => MessageType::Changelog(None),
             MessageType::Git(_) => MessageType::Git(None),
         }
     }
@@ -481,8 +1307,17 @@ impl MessageType {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::chat::stream::tests::TestStreamer;
-    use crate::client::provider::tests::TestProvider;
+    use crate::chat::stream::{ChatStreamer, tests::TestStreamer};
+    use crate::cli::commands::Cli;
+    use crate::client::provider::{Vision, tests::TestProvider};
+    use crate::tools::image::Image;
+    use clap::Parser;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+    use std::time::Duration;
+    use tokio::sync::mpsc::Receiver;
 
     /// Simulate the > /dev/null
     struct TestWriter;
@@ -490,9 +1325,9 @@ mod tests {
         fn poll_write(
             self: std::pin::Pin<&mut Self>,
             _cx: &mut std::task::Context<'_>,
-            _buf: &[u8],
+            buf: &[u8],
         ) -> std::task::Poll<Result<usize, std::io::Error>> {
-            std::task::Poll::Ready(Ok(10))
+            std::task::Poll::Ready(Ok(buf.len()))
         }
 
         fn poll_flush(
@@ -521,7 +1356,7 @@ mod tests {
 
         let message = Message {
             role: Role::User,
-            content: "hello".to_string(),
+            content: "hello".into(),
         };
 
         let response = chat
@@ -538,7 +1373,93 @@ mod tests {
             .await
             .expect("process the stream");
 
-        assert_eq!(response.content, "Rust ".repeat(10));
+        assert_eq!(response.content.text(), "Rust ".repeat(10));
+    }
+
+    #[tokio::test]
+    async fn write_before_a_stream_failure() {
+        /// Take a while to write, the request fails before the writer finishes
+        #[derive(Clone)]
+        struct SlowStreamer(Arc<AtomicBool>);
+        impl Streamer for SlowStreamer {
+            async fn write_at_end(
+                &self,
+                _writer: &mut (impl AsyncWrite + Unpin + Send),
+                mut receiver: Receiver<StreamEvent>,
+            ) -> anyhow::Result<()> {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                while receiver.recv().await.is_some() {}
+                self.0.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+        }
+
+        let written = Arc::new(AtomicBool::new(false));
+        let mut chat = Chat::new(TestProvider::new(1, "data: {malformed}\n\n"));
+
+        let result = chat
+            .send_message_with_stream(
+                None,
+                None,
+                MessageType::default(),
+                SlowStreamer(written.clone()),
+                TestWriter,
+            )
+            .await;
+
+        assert!(matches!(result, Err(ChatError::Stream(_))));
+        assert!(written.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn send_message_without_stream() {
+        let provider = TestProvider::new(3, "Rust ");
+        let mut chat = Chat::new(provider);
+        let mut written = vec![];
+
+        let response = chat
+            .send_message(None, None, MessageType::default(), &TestStreamer, &mut written)
+            .await
+            .expect("complete the request");
+
+        assert_eq!(response.content.text(), "Rust Rust Rust ");
+        assert_eq!(String::from_utf8(written).expect("utf-8"), "Rust Rust Rust ");
+        assert_eq!(chat.finish_reason(), Some("stop"));
+        assert_eq!(chat.model(), Some(DEFAULT_MODEL));
+        assert!(!chat.provider.input_messages.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stop_a_response_without_stream() {
+        let mut chat = Chat::new(TestProvider::new(3, "Rust "));
+        let streamer = ChatStreamer::default().with_stop_sequences(vec![" Rust".to_string()]);
+        let mut written = vec![];
+
+        let response = chat
+            .send_message(None, None, MessageType::default(), &streamer, &mut written)
+            .await
+            .expect("complete the request");
+
+        assert_eq!(response.content.text(), "Rust");
+        assert_eq!(String::from_utf8(written).expect("utf-8"), "Rust");
+        assert_eq!(chat.finish_reason(), Some("stop"));
+    }
+
+    #[tokio::test]
+    async fn clamp_max_response_tokens() {
+        let mut provider = TestProvider::new(1, "");
+        provider.max_output_tokens = Some(1024);
+        let mut chat = Chat::new(provider);
+        chat.set_options(RequestOptions {
+            max_tokens: Some(8192),
+            ..Default::default()
+        });
+
+        chat.send_message_with_stream(None, None, MessageType::default(), TestStreamer, TestWriter)
+            .await
+            .expect("process the stream");
+
+        assert_eq!(chat.provider.input_options.borrow().max_tokens, Some(1024));
     }
 
     #[tokio::test]
@@ -550,7 +1471,7 @@ mod tests {
 
         let message = Message {
             role: Role::User,
-            content: "hello".to_string(),
+            content: "hello".into(),
         };
 
         chat.send_message_with_stream(
@@ -578,6 +1499,441 @@ mod tests {
         assert!(exists);
     }
 
+    #[tokio::test]
+    async fn reject_images_without_vision() {
+        let image = Image {
+            path: "diagram.png".to_string(),
+            media_type: "image/png".to_string(),
+            data: vec![0; 16],
+        };
+        let options = RequestOptions {
+            images: vec![image],
+            ..Default::default()
+        };
+
+        let mut chat = Chat::new(TestProvider::new(10, ""));
+        chat.set_options(options.clone());
+        let error = chat
+            .send_message_with_stream(None, None, MessageType::default(), TestStreamer, TestWriter)
+            .await
+            .expect_err("the model does not accept images");
+        assert!(error.to_string().contains("does not accept images"));
+
+        let mut provider = TestProvider::new(10, "");
+        provider.vision = Some(Vision {
+            max_image_size: Some(8),
+            ..Default::default()
+        });
+        let mut chat = Chat::new(provider);
+        chat.set_options(options);
+        let error = chat
+            .send_message_with_stream(None, None, MessageType::default(), TestStreamer, TestWriter)
+            .await
+            .expect_err("the image is too large");
+        assert!(error.to_string().contains("max image size"));
+    }
+
+    #[tokio::test]
+    async fn skip_task_prompt() {
+        let mut chat = Chat::new(TestProvider::new(10, ""));
+        chat.set_skip_task_prompt(true);
+
+        chat.send_message_with_stream(None, None, MessageType::default(), TestStreamer, TestWriter)
+            .await
+            .expect("process the stream");
+
+        let messages = chat.provider.input_messages.into_inner();
+        assert!(messages.iter().any(|m| m.content == GENERAL));
+        assert!(!messages.iter().any(|m| m.content == CODE));
+    }
+
+    #[tokio::test]
+    async fn instruction_after_the_prompts() {
+        let mut chat = Chat::new(TestProvider::new(10, ""));
+        let instruction = |content: &str| Message {
+            role: Role::System,
+            content: content.into(),
+        };
+
+        chat.add_instruction(instruction("answer in Spanish"), &MessageType::default())
+            .expect("add the instruction");
+        chat.add_instruction(instruction("be brief"), &MessageType::default())
+            .expect("add the instruction");
+        chat.send_message_with_stream(None, None, MessageType::default(), TestStreamer, TestWriter)
+            .await
+            .expect("process the stream");
+
+        let contents: Vec<_> = chat
+            .provider
+            .input_messages
+            .into_inner()
+            .iter()
+            .map(|m| m.content.text().into_owned())
+            .collect();
+        assert_eq!(contents, [GENERAL, CODE, "answer in Spanish", "be brief"]);
+    }
+
+    #[tokio::test]
+    async fn attach_unchanged_content_once() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        std::fs::write(dir.path().join("lib.rs"), "fn shared() {}\n").expect("write the file");
+        let file = dir.path().join("main.rs");
+        std::fs::write(&file, "fn main() {}\n").expect("write the file");
+
+        let mut chat = Chat::new(TestProvider::new(0, ""));
+        // The same directory and the same file with another spelling
+        for files in [
+            vec![dir.path().display().to_string(), file.display().to_string()],
+            vec![
+                dir.path().display().to_string(),
+                dir.path().join(".").join("main.rs").display().to_string(),
+            ],
+        ] {
+            let message_type = MessageType::Code {
+                user_prompt: Some("Explain this".to_string()),
+                files: Some(files),
+            };
+            chat.send_message_with_stream(None, None, message_type, TestStreamer, TestWriter)
+                .await
+                .expect("process the stream");
+        }
+
+        let inlined = |chat: &Chat<TestProvider>, text: &str| {
+            chat.messages()
+                .iter()
+                .filter(|m| m.content.text().contains(text))
+                .count()
+        };
+        assert_eq!(inlined(&chat, "fn shared() {}"), 1);
+        assert_eq!(
+            inlined(&chat, "fn main() {}"),
+            2,
+            "once in the directory and once as a file"
+        );
+        assert_eq!(inlined(&chat, "unchanged, its content was attached before"), 1);
+
+        // A change is attached again
+        std::fs::write(dir.path().join("lib.rs"), "fn shared() -> u8 { 0 }\n").expect("update the file");
+        let message_type = MessageType::Code {
+            user_prompt: Some("And now?".to_string()),
+            files: Some(vec![dir.path().display().to_string()]),
+        };
+        chat.send_message_with_stream(None, None, message_type, TestStreamer, TestWriter)
+            .await
+            .expect("process the stream");
+        assert_eq!(inlined(&chat, "fn shared() -> u8 { 0 }"), 1);
+    }
+
+    #[tokio::test]
+    async fn keep_going_on_file_errors() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let file = dir.path().join("main.rs");
+        std::fs::write(&file, "fn main() {}\n").expect("write the file");
+
+        // Only UTF-8 files can be attached
+        let binary = dir.path().join("image.bin");
+        std::fs::write(&binary, [0xff, 0xfe, 0x00]).expect("write the binary file");
+        let files = vec![binary.display().to_string(), file.display().to_string()];
+        let message_type = MessageType::Code {
+            user_prompt: None,
+            files: Some(files.clone()),
+        };
+
+        let mut chat = Chat::new(TestProvider::new(10, ""));
+        let result = chat
+            .send_message_with_stream(None, None, message_type.clone(), TestStreamer, TestWriter)
+            .await;
+        assert!(matches!(result, Err(ChatError::Tool(_))));
+
+        let mut chat = Chat::new(TestProvider::new(10, ""));
+        chat.set_file_options(FileOptions {
+            keep_going: true,
+            ..Default::default()
+        });
+        chat.send_message_with_stream(None, None, message_type, TestStreamer, TestWriter)
+            .await
+            .expect("skip the binary file");
+
+        assert_eq!(chat.failed_files(), &files[..1]);
+        let messages = chat.provider.input_messages.into_inner();
+        assert!(messages.iter().any(|m| m.content.text().contains("fn main() {}")));
+    }
+
+    #[tokio::test]
+    async fn attachments_go_with_every_request() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let file = dir.path().join("lib.rs");
+        std::fs::write(&file, "pub fn answer() -> u8 { 42 }\n").expect("write the file");
+        let file = file.display().to_string();
+
+        let mut chat = Chat::new(TestProvider::new(10, ""));
+        assert!(
+            chat.attach(&dir.path().join("missing.rs").display().to_string())
+                .is_err()
+        );
+        chat.attach(&file).expect("attach the file");
+        chat.attach(&format!("{}:1-1", file)).expect("replace the range");
+        assert_eq!(chat.attachments(), [format!("{}:1-1", file)]);
+
+        let message_type = MessageType::Code {
+            user_prompt: Some("explain it".to_string()),
+            files: None,
+        };
+        chat.assemble_messages(None, &message_type).await.expect("assemble");
+        assert!(
+            chat.messages()
+                .iter()
+                .any(|m| m.content.text().contains("pub fn answer() -> u8 { 42 }"))
+        );
+        assert_eq!(chat.tracked_files.len(), 1);
+
+        assert!(chat.detach(&file));
+        assert!(!chat.detach(&file));
+        assert!(chat.attachments().is_empty());
+        assert!(chat.tracked_files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn wrapped_prompt_reaches_the_request() {
+        let cli = Cli::parse_from([
+            "copilot-chat",
+            "--prompt-prefix",
+            "In Rust, ",
+            "--prompt-suffix",
+            " Keep it short.",
+            "sort a vector",
+        ]);
+        let message_type = MessageType::from(&CommandHandler::new(&cli, Some("sort a vector")));
+
+        let mut chat = Chat::new(TestProvider::new(10, ""));
+        chat.send_message_with_stream(None, None, message_type, TestStreamer, TestWriter)
+            .await
+            .expect("process the stream");
+
+        let messages = chat.provider.input_messages.into_inner();
+        assert_eq!(
+            messages.last().expect("user prompt").content,
+            "In Rust, sort a vector Keep it short."
+        );
+    }
+
+    #[test]
+    fn message_type_from_command() {
+        let message_type = |args: &[&str]| {
+            let cli = Cli::parse_from(args);
+            MessageType::from(&CommandHandler::new(&cli, Some("prompt")))
+        };
+        let prompt = Some("prompt".to_string());
+
+        assert!(matches!(message_type(&["copilot-chat", "commit"]), MessageType::Commit(p, _) if p == prompt));
+        assert!(
+            matches!(message_type(&["copilot-chat", "commit", "--since", "main"]), MessageType::Pr(p) if p == prompt)
+        );
+        assert!(
+            matches!(message_type(&["copilot-chat", "pr-description"]), MessageType::PrDescription(p) if p == prompt)
+        );
+        assert!(matches!(message_type(&["copilot-chat", "changelog"]), MessageType::Changelog(p) if p == prompt));
+        assert!(matches!(message_type(&["copilot-chat", "git"]), MessageType::Git(p) if p == prompt));
+        assert!(matches!(
+            message_type(&["copilot-chat", "models"]),
+            MessageType::Code {
+                user_prompt: None,
+                files: None
+            }
+        ));
+
+        let expected_files = Some(vec!["src/main.rs".to_string(), "Cargo.toml".to_string()]);
+        for args in [
+            &["copilot-chat", "--files", "src/main.rs,Cargo.toml"][..],
+            &["copilot-chat", "--files", "src/main.rs,Cargo.toml", "tcp"][..],
+            &["copilot-chat", "--files", "src/main.rs,Cargo.toml", "resume"][..],
+        ] {
+            match message_type(args) {
+                MessageType::Code { user_prompt, files } => {
+                    assert_eq!(user_prompt, prompt);
+                    assert_eq!(files, expected_files);
+                }
+                other => panic!("unexpected message type {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn clear_user_prompt() {
+        let prompt = || Some("prompt".to_string());
+        let mut message_types = [
+            MessageType::Commit(prompt(), CommitLimits::default()),
+            MessageType::Pr(prompt()),
+            MessageType::PrDescription(prompt()),
+            MessageType::Changelog(prompt()),
+            MessageType::Git(prompt()),
+            MessageType::Code {
+                user_prompt: prompt(),
+                files: Some(vec!["src/main.rs".to_string()]),
+            },
+        ];
+
+        for message_type in &mut message_types {
+            assert!(message_type.resolve_user_prompt().is_some());
+            message_type.clear_user_prompt();
+            assert!(message_type.resolve_user_prompt().is_none(), "{:?}", message_type);
+        }
+
+        // The variant is preserved
+        assert!(matches!(message_types[1], MessageType::Pr(None)));
+        assert!(matches!(&message_types[5], MessageType::Code { files: Some(files), .. } if files.len() == 1));
+    }
+
+    #[tokio::test]
+    async fn save_and_load_pending_request() {
+        let cache = tempfile::tempdir().expect("create temp dir");
+        let path = cache.path().to_str();
+        let mut provider = TestProvider::new(0, "");
+        provider.vision = Some(Vision::default());
+        let mut chat = Chat::new(provider);
+
+        chat.add_message(Message {
+            content: "Explain this".into(),
+            role: Role::User,
+        });
+        chat.set_options(RequestOptions {
+            candidates: Some(2),
+            stop: vec!["END".to_string()],
+            images: vec![Image {
+                path: "diagram.png".to_string(),
+                media_type: "image/png".to_string(),
+                data: vec![0, 1, 2],
+            }],
+            ..Default::default()
+        });
+        // The images are taken from the options when the request is sent
+        chat.request_options("gpt-4o").await.expect("valid options");
+        chat.save_pending_request(Some("gpt-4o"), &MessageType::default(), path)
+            .expect("save the request");
+
+        let request = Chat::<TestProvider>::try_load_pending_request(path, None)
+            .expect("load the request")
+            .expect("request exists");
+        assert_eq!(request.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(request.messages.len(), 1);
+        assert_eq!(request.messages[0].content, "Explain this");
+        assert_eq!(request.options.candidates, Some(2));
+        assert_eq!(request.options.stop, ["END"]);
+        assert_eq!(request.options.images.len(), 1);
+        assert_eq!(request.options.images[0].data, [0, 1, 2]);
+        assert!(request.code_chat);
+
+        chat.save_pending_request(None, &MessageType::Git(None), path)
+            .expect("save the request");
+        let request = Chat::<TestProvider>::try_load_pending_request(path, None)
+            .expect("load the request")
+            .expect("request exists");
+        assert!(!request.code_chat);
+
+        Chat::<TestProvider>::remove_pending_request(path, None).expect("remove the request");
+        assert!(
+            Chat::<TestProvider>::try_load_pending_request(path, None)
+                .expect("load the request")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn bounded_cache_key() {
+        let deep: PathBuf = (0..100).map(|_| "a-very-long-directory-name").collect();
+        let key = cache_key(&deep);
+        assert!(key.len() <= CACHE_KEY_PREFIX_LEN + 17);
+        assert!(key.starts_with("a-very-long-directory-name-"));
+        assert_ne!(key, cache_key(&deep.join("other")));
+    }
+
+    #[test]
+    fn independent_threads() {
+        let cache = tempfile::tempdir().expect("create temp dir");
+        let path = cache.path().to_str();
+
+        let mut chat = Chat::new(TestProvider::new(0, ""));
+        chat.add_message(Message {
+            content: "Default".into(),
+            role: Role::User,
+        });
+        chat.save_chat(path).expect("save the default chat");
+        chat.set_thread(Some("docs".to_string()));
+        chat.add_message(Message {
+            content: "Docs".into(),
+            role: Role::User,
+        });
+        chat.save_chat(path).expect("save the thread");
+        chat.save_pending_request(None, &MessageType::default(), path)
+            .expect("save the request");
+
+        let load = |thread| {
+            Chat::<TestProvider>::try_load_chat(path, thread)
+                .expect("load chat")
+                .expect("chat exists")
+        };
+        assert_eq!(load(None).messages().len(), 1);
+        let thread = load(Some("docs"));
+        assert_eq!(thread.messages().len(), 2);
+        assert_eq!(thread.thread(), Some("docs"));
+        assert_eq!(
+            Chat::<TestProvider>::list_threads(path).expect("list threads"),
+            [None, Some("docs".to_string())]
+        );
+
+        let indexed = || {
+            let index = std::fs::read_to_string(cache.path().join(CACHE_INDEX)).expect("read the index");
+            index.contains(&cache_key(&current_dir().expect("current dir")))
+        };
+        thread.remove_chat(path).expect("remove the thread");
+        assert!(
+            Chat::<TestProvider>::try_load_chat(path, Some("docs"))
+                .expect("load chat")
+                .is_none()
+        );
+        assert_eq!(load(None).messages().len(), 1);
+        assert!(indexed());
+
+        // The directory leaves the index with its last chat
+        load(None).remove_chat(path).expect("remove the default chat");
+        assert!(!indexed());
+    }
+
+    #[test]
+    fn migrate_percent_encoded_cache() {
+        let cache = tempfile::tempdir().expect("create temp dir");
+        let path = cache.path().to_str();
+        let chat = Chat::new(TestProvider::new(0, ""));
+        chat.add_message(Message {
+            content: "Hello".into(),
+            role: Role::User,
+        });
+        chat.save_chat(path).expect("save the chat");
+
+        let cwd = current_dir().expect("current dir");
+        let encoded = percent_encode(cwd.to_str().expect("utf-8 cwd").as_bytes(), NON_ALPHANUMERIC);
+        let legacy_file = cache.path().join(format!("{}.json", encoded));
+        let cache_file = Chat::<TestProvider>::chat_file(path, None).expect("cache file");
+        std::fs::rename(&cache_file, &legacy_file).expect("rename to the legacy name");
+
+        // Looking up the path doesn't migrate the file
+        assert_eq!(
+            Chat::<TestProvider>::chat_file_path(path, None).expect("cache file"),
+            cache_file
+        );
+        assert!(legacy_file.exists());
+
+        let loaded = Chat::<TestProvider>::try_load_chat(path, None)
+            .expect("load chat")
+            .expect("migrated chat");
+        assert_eq!(loaded.messages().len(), 1);
+        assert!(cache_file.exists());
+        assert!(!legacy_file.exists());
+
+        let index = std::fs::read_to_string(cache.path().join(CACHE_INDEX)).expect("read the index");
+        assert!(index.contains(&cache_key(&cwd)));
+    }
+
     #[test]
     fn save_and_load_chat() {
         let file = "/tmp";
@@ -585,18 +1941,18 @@ mod tests {
         let chat1 = Chat::new(provider);
 
         chat1.add_message(Message {
-            content: "Hello".to_string(),
+            content: "Hello".into(),
             role: Role::User,
         });
         chat1.add_message(Message {
-            content: "Hello, how are you?".to_string(),
+            content: "Hello, how are you?".into(),
             role: Role::System,
         });
 
         chat1.save_chat(Some(file)).expect("save the chat");
 
         let provider = TestProvider::new(0, "");
-        let chat2 = Chat::try_load_chat(Some(file))
+        let chat2 = Chat::try_load_chat(Some(file), None)
             .expect("load chat")
             .expect("retrieve chat")
             .with_provider(provider);
@@ -621,4 +1977,30 @@ mod tests {
             chat2.messages.borrow().first().expect("first message in chat 2").role
         )
     }
+
+    #[test]
+    fn assistant_role_round_trip() {
+        let cache = tempfile::tempdir().expect("create the cache dir");
+        let path = cache.path().to_str().expect("utf-8 path");
+        let chat = Chat::new(TestProvider::new(0, ""));
+        chat.add_message(Message {
+            content: "Hello".into(),
+            role: Role::User,
+        });
+        chat.add_message(Message {
+            content: "Hi, what can I do for you?".into(),
+            role: Role::Assistant,
+        });
+        chat.save_chat(Some(path)).expect("save the chat");
+
+        let saved = std::fs::read_to_string(Chat::<TestProvider>::chat_file(Some(path), None).expect("chat file"))
+            .expect("read the chat");
+        assert!(saved.contains(r#""role":"assistant""#));
+
+        let loaded = Chat::<TestProvider>::try_load_chat(Some(path), None)
+            .expect("load the chat")
+            .expect("saved chat");
+        let roles: Vec<Role> = loaded.messages().iter().map(|message| message.role.clone()).collect();
+        assert_eq!(roles, [Role::User, Role::Assistant]);
+    }
 }
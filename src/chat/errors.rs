@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::client::ProviderError;
+
 #[derive(Debug, Error)]
 pub enum ChatError {
     #[error("Failed to access chat cache: {0}")]
@@ -10,10 +12,40 @@ pub enum ChatError {
     Request(String),
     #[error("Failed to process stream: {0}")]
     Stream(String),
+    #[error("Failed to process stream, the response is incomplete: {reason}")]
+    PartialStream { content: String, reason: String },
+    #[error("The response exceeded the deadline")]
+    Deadline { content: String },
     #[error("Provider error: {0}")]
-    Provider(String),
+    Provider(#[from] ProviderError),
     #[error("Tool error: {0}")]
     Tool(String),
+    #[error("The response does not follow the schema: {0}")]
+    Schema(String),
+    #[error("The model {model} is not available{}", match suggestions.as_slice() {
+        [] => String::new(),
+        suggestions => format!(", did you mean {}?", suggestions.join(", ")),
+    })]
+    UnknownModel { model: String, suggestions: Vec<String> },
     #[error("Tokio join error: {0}")]
     Join(#[from] tokio::task::JoinError),
 }
+
+impl ChatError {
+    /// Short identifier of the error category, for programmatic consumers
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Cache(_) => "cache",
+            Self::Serde(_) => "serde",
+            Self::Request(_) => "request",
+            Self::Stream(_) | Self::PartialStream { .. } => "stream",
+            Self::Deadline { .. } => "deadline",
+            Self::Provider(error) if error.is_auth() => "auth",
+            Self::Provider(_) => "provider",
+            Self::Tool(_) => "tool",
+            Self::Schema(_) => "schema",
+            Self::UnknownModel { .. } => "model",
+            Self::Join(_) => "internal",
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::str::FromStr;
+
+use super::{Message, Role};
+
+/// Max characters kept of an assistant turn summarized by [`HistoryPolicy::Summary`]
+const SUMMARY_LEN: usize = 200;
+
+/// Which of the previous assistant turns are sent with a request, the chat always keeps all of them
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum HistoryPolicy {
+    /// Send every assistant turn
+    #[default]
+    All,
+    /// Send only the last N assistant turns
+    Last(usize),
+    /// Send the last assistant turn and the first line of the previous ones
+    Summary,
+}
+
+impl FromStr for HistoryPolicy {
+    type Err = String;
+
+    /// Parse `all`, `summary` or `last:N`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Self::All),
+            "summary" => Ok(Self::Summary),
+            _ => s
+                .strip_prefix("last:")
+                .and_then(|n| n.parse().ok())
+                .map(Self::Last)
+                .ok_or_else(|| format!("invalid history policy `{}`, expected all, summary or last:N", s)),
+        }
+    }
+}
+
+impl HistoryPolicy {
+    /// The `messages` to send following the policy
+    pub fn apply(&self, messages: &[Message]) -> Vec<Message> {
+        let assistant_turns = messages.iter().filter(|m| m.role == Role::Assistant).count();
+        let keep = match self {
+            Self::All => return messages.to_vec(),
+            Self::Last(n) => *n,
+            Self::Summary => 1,
+        };
+        let mut skip = assistant_turns.saturating_sub(keep);
+
+        messages
+            .iter()
+            .filter_map(|message| {
+                if message.role != Role::Assistant || skip == 0 {
+                    return Some(message.clone());
+                }
+                skip -= 1;
+
+                match self {
+                    Self::Summary => Some(Message {
+                        role: Role::Assistant,
+                        content: summarize(&message.content.text()).into(),
+                    }),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The first non-empty line of `content`, shortened to [`SUMMARY_LEN`] characters
+fn summarize(content: &str) -> String {
+    let line = content
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or_default();
+    let summary: String = line.chars().take(SUMMARY_LEN).collect();
+    format!("{} [...]", summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation() -> Vec<Message> {
+        [
+            (Role::User, "first"),
+            (Role::Assistant, "answer one\nmore details"),
+            (Role::User, "second"),
+            (Role::Assistant, "answer two"),
+            (Role::User, "third"),
+        ]
+        .into_iter()
+        .map(|(role, content)| Message {
+            role,
+            content: content.into(),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn apply_history_policies() {
+        let messages = conversation();
+        let contents = |policy: HistoryPolicy| -> Vec<String> {
+            policy
+                .apply(&messages)
+                .into_iter()
+                .map(|message| message.content.to_string())
+                .collect()
+        };
+
+        assert_eq!(contents(HistoryPolicy::All).len(), 5);
+        assert_eq!(
+            contents(HistoryPolicy::Last(1)),
+            ["first", "second", "answer two", "third"]
+        );
+        assert_eq!(contents(HistoryPolicy::Last(0)), ["first", "second", "third"]);
+        assert_eq!(
+            contents(HistoryPolicy::Summary),
+            ["first", "answer one [...]", "second", "answer two", "third"]
+        );
+    }
+
+    #[test]
+    fn parse_history_policy() {
+        assert_eq!("last:3".parse(), Ok(HistoryPolicy::Last(3)));
+        assert_eq!("summary".parse(), Ok(HistoryPolicy::Summary));
+        assert!("last".parse::<HistoryPolicy>().is_err());
+    }
+}
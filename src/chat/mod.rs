@@ -1,7 +1,10 @@
+pub mod content;
 mod core;
 pub mod errors;
+pub mod history;
 pub mod prompts;
 mod stream;
-pub use core::{Builder, Chat, Message, MessageType, Role};
-pub use stream::ChatStreamer;
+pub use core::{Builder, Chat, DEFAULT_MODEL, Message, MessageType, Role};
+pub use stream::{ChatStreamer, DEFAULT_MAX_BUFFER_SIZE, StreamEvent, Streamer, Usage};
 pub mod request;
+pub mod tokenizer;
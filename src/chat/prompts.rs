@@ -7,12 +7,13 @@ Focus on core logic, avoid boilerplate, and prefer idiomatic, low-level implemen
 Work under the hood — no fluff, just clean and purposeful code.
 "#;
 
-/// Prompt for generating commit messages following the Commitizen convention.
+/// Prompt for generating commit messages following the Commitizen convention. It is a template
+/// of the [`CommitLimits`], see [`CommitLimits::commit_prompt`].
 pub static COMMIT: &str = r#"
 Write a commit message using the Commitizen convention. Use the correct type.
 (feat, fix, chore, refactor, docs, test, etc.) and provide a concise description of the main change.
 Include a scope and a short body explaining why the change was made.
-For the commit header, use fewer than 52 characters. For the body, use at least 80 characters and do not exceed 100.
+For the commit header, use fewer than {subject_max} characters. For the body, use at least {body_min} characters and do not exceed {body_max}.
 Indicate the important changes in a dashed list. Do not be vague; be straightforward and action-oriented. It is not necessary to elaborate.
 Only mention maintainability or other related aspects if it is clear; in general, focus on the
 Changes.
@@ -22,6 +23,41 @@ If you don't have git diff data, request it, but do not provide a message withou
 Also void using a code block, only put the raw text
 "#;
 
+/// Default max characters of the header of a commit message
+pub const DEFAULT_SUBJECT_MAX: usize = 52;
+/// Default min characters of the body of a commit message
+pub const DEFAULT_BODY_MIN: usize = 80;
+/// Default max characters of the body of a commit message
+pub const DEFAULT_BODY_MAX: usize = 100;
+
+/// Length limits of a commit message, filled in the [`COMMIT`] prompt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommitLimits {
+    pub subject_max: usize,
+    pub body_min: usize,
+    pub body_max: usize,
+}
+
+impl Default for CommitLimits {
+    fn default() -> Self {
+        Self {
+            subject_max: DEFAULT_SUBJECT_MAX,
+            body_min: DEFAULT_BODY_MIN,
+            body_max: DEFAULT_BODY_MAX,
+        }
+    }
+}
+
+impl CommitLimits {
+    /// The [`COMMIT`] prompt with these limits
+    pub fn commit_prompt(&self) -> String {
+        COMMIT
+            .replace("{subject_max}", &self.subject_max.to_string())
+            .replace("{body_min}", &self.body_min.to_string())
+            .replace("{body_max}", &self.body_max.to_string())
+    }
+}
+
 /// Prompt for generating or modifying code snippets directly.
 pub static CODE: &str = r#"
 You are an expert systems developer. Given a function, struct, or code snippet, complete or improve it
@@ -66,3 +102,90 @@ You are a Git power user. Given a Git task, provide the most efficient and corre
 command(s) or configuration. Prefer short, safe, and reproducible commands.
 Explain only if the operation is not self-explanatory.
 "#;
+
+/// Prompt for summarizing the changes of a branch as a pull request description.
+pub static PR: &str = r#"
+Write a pull request description for the provided commits and diff. Start with a concise title
+of fewer than 72 characters on the first line, followed by a blank line and the body.
+The body explains what changed and why, listing the important changes in a dashed list.
+Do not be vague; be straightforward and action-oriented.
+
+Only give me the title and body; it is not necessary to explain them.
+If you don't have the commits or diff data, request it, but do not provide a description without the necessary data.
+"#;
+
+/// Prompt for writing a markdown pull request body for the changes of a branch.
+pub static PR_DESCRIPTION: &str = r#"
+Write a pull request description in markdown for the provided commits and diff, ready to paste into the PR body.
+Use the following sections:
+
+## What
+A short summary of the changes, with the important ones in a dashed list.
+
+## Why
+The motivation of the changes. Only state what is clear from the commits and diff; do not invent reasons.
+
+## Testing
+How the changes were or should be verified, based on the tests included in the diff.
+
+## Checklist
+A markdown task list (`- [ ]`) with the follow-ups a reviewer should check.
+
+Only give me the description; it is not necessary to explain it.
+If you don't have the commits or diff data, request it, but do not provide a description without the necessary data.
+"#;
+
+/// Prompt for generating a changelog from the commit log.
+pub static CHANGELOG: &str = r#"
+Write a changelog in markdown for the provided commit log. Group the entries by the conventional-commit
+type under these headings, omitting the empty ones: Features (feat), Fixes (fix), Performance (perf),
+Refactors (refactor), Documentation (docs), Tests (test) and Other changes (anything else).
+Each entry is a dashed item with a short, user-facing description and the commit hash in parentheses.
+Mark breaking changes (`!` or `BREAKING CHANGE`) in a Breaking Changes section at the top.
+
+Only give me the changelog; it is not necessary to explain it.
+"#;
+
+/// Sent with `--reask` when a response is cut by the max tokens.
+pub static CONTINUE: &str = "Continue exactly where your last response stopped, without repeating anything.";
+
+/// Prompt for summarizing a chat before its history is replaced by the summary.
+pub static COMPACT: &str = r#"
+Summarize our conversation so far so it can replace the history of this chat. Keep the goals, the decisions
+made and their reasons, the relevant file names, code and commands, and any open questions or next steps.
+Be dense and factual, omit the greetings and the discarded ideas.
+
+Only give me the summary; it is not necessary to explain it.
+"#;
+
+/// All the built-in prompts with the name of the message type that uses them.
+pub static ALL: &[(&str, &str)] = &[
+    ("general", GENERAL),
+    ("code", CODE),
+    ("commit", COMMIT),
+    ("git", GIT),
+    ("pr", PR),
+    ("pr-description", PR_DESCRIPTION),
+    ("changelog", CHANGELOG),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_prompt_limits() {
+        let prompt = CommitLimits::default().commit_prompt();
+        assert!(prompt.contains("fewer than 52 characters"));
+        assert!(prompt.contains("at least 80 characters and do not exceed 100"));
+
+        let prompt = CommitLimits {
+            subject_max: 72,
+            body_min: 0,
+            body_max: 72,
+        }
+        .commit_prompt();
+        assert!(prompt.contains("fewer than 72 characters"));
+        assert!(!prompt.contains('{'));
+    }
+}
@@ -1 +1,17 @@
+use serde::{Deserialize, Serialize};
 
+use super::Message;
+use crate::client::provider::RequestOptions;
+
+/// A fully-assembled request that did not complete, persisted so it can be re-sent verbatim
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PendingRequest {
+    pub model: Option<String>,
+    /// The options as they were sent, with the images of the request
+    #[serde(default)]
+    pub options: RequestOptions,
+    pub messages: Vec<Message>,
+    /// The request belongs to a code chat, the only one kept in the history
+    #[serde(default)]
+    pub code_chat: bool,
+}
@@ -4,9 +4,19 @@ use super::Message;
 use bytes::{Buf, BufMut, BytesMut};
 use futures_util::{Stream, StreamExt};
 use serde::Deserialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Display;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
-use tokio::sync::mpsc::{Receiver, Sender};
-use tracing::{debug, error, trace};
+use tokio::sync::mpsc::{Receiver, Sender, error::TrySendError};
+use tokio::time::Instant;
+use tracing::{Instrument, Span, debug, debug_span, error, instrument, trace, warn};
+
+/// Default limit of the bytes buffered while waiting for a complete event
+pub const DEFAULT_MAX_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Interval between the writes of a paced response, about a frame of the terminal
+const PACE_TICK: Duration = Duration::from_millis(16);
 
 /// Handle the stream and all related actions. Use channels to communicate with the
 /// caller and write the content to the `writer`.
@@ -15,45 +25,270 @@ pub trait Streamer: Clone + Send {
     fn write_at_end(
         &self,
         writer: &mut (impl tokio::io::AsyncWrite + Unpin + Send),
-        receiver: Receiver<String>,
+        receiver: Receiver<StreamEvent>,
     ) -> impl std::future::Future<Output = anyhow::Result<()>> + Send;
 
-    /// Handle the stream data and process all the chunks; use a Finite State Machine (FSM) for
-    /// capturing the chunks and ensure that incomplete chunks are not processed until the message
-    /// is completely passed to the buffer.
+    /// Max bytes kept in the buffer without finding a complete event, a stream that exceeds it
+    /// is considered malformed.
+    fn max_buffer_size(&self) -> usize {
+        DEFAULT_MAX_BUFFER_SIZE
+    }
+
+    /// Max time waiting for the first content of the response, no limit if it is not set
+    fn first_token_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Instant when the response must be complete, it is stopped otherwise
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Print the [`StreamStats`] to stderr when the stream finishes
+    fn report_stats(&self) -> bool {
+        false
+    }
+
+    /// Skip the malformed events instead of failing, as long as another event of the response is
+    /// valid
+    fn lenient_parse(&self) -> bool {
+        false
+    }
+
+    /// Sequences where the response ends, it is truncated before them if the provider doesn't stop
+    fn stop_sequences(&self) -> &[String] {
+        &[]
+    }
+
+    /// Handle the stream data and send its content to the writer, followed by a terminal event:
+    /// [`StreamEvent::Done`] if the response is complete or [`StreamEvent::Error`] if it failed.
+    #[instrument(level = "debug", skip_all)]
     async fn handle_stream(
+        &self,
+        stream: impl Stream<Item = reqwest::Result<bytes::Bytes>> + Unpin,
+        sender: Sender<StreamEvent>,
+    ) -> anyhow::Result<StreamResponse> {
+        let result = self.collect_stream(stream, &sender).await;
+        let end = match &result {
+            Ok(response) => StreamEvent::Done {
+                finish_reason: response.finish_reason.clone(),
+            },
+            Err(e) => StreamEvent::Error(e.to_string()),
+        };
+        if sender.send(end).await.is_err() {
+            debug!("The writer is closed, skipping the end of the stream");
+        }
+        result
+    }
+
+    /// Process all the chunks of the stream; use a Finite State Machine (FSM) for capturing the
+    /// chunks and ensure that incomplete chunks are not processed until the message is completely
+    /// passed to the buffer.
+    async fn collect_stream(
         &self,
         mut stream: impl Stream<Item = reqwest::Result<bytes::Bytes>> + Unpin,
-        sender: Sender<String>,
-    ) -> anyhow::Result<Message> {
+        sender: &Sender<StreamEvent>,
+    ) -> anyhow::Result<StreamResponse> {
         let mut response = String::new();
+        let mut candidates: BTreeMap<usize, String> = BTreeMap::new();
+        let mut stats = StreamStats::default();
+        let mut finish_reason = None;
+        let mut usage = None;
+        let stops = self.stop_sequences();
+        // The end of the response that could be the start of a stop sequence is held back until
+        // the next content tells it is not
+        let holdback = stops.iter().map(|stop| stop.len()).max().unwrap_or(1).saturating_sub(1);
+        // Length of the response already sent to the writer
+        let mut sent: usize = 0;
+        // First error of the malformed events skipped in lenient mode
+        let mut first_skipped = None;
 
         debug!("Opening stream");
         let mut buffer = BytesMut::with_capacity(8192);
-        while let Some(chunk) = stream.next().await {
+        // Length of the start of the buffer already searched for a separator
+        let mut scanned: usize = 0;
+        let first_token_deadline = self.first_token_timeout().map(|timeout| Instant::now() + timeout);
+        let started = Instant::now();
+        // Open until the first content arrives, its duration is the time to first token
+        let mut first_token_span = Some(debug_span!("first_token"));
+        'stream: loop {
+            let next = async {
+                match first_token_deadline {
+                    // Once the content starts, the stream can take as long as it needs
+                    Some(deadline) if response.is_empty() && candidates.is_empty() => {
+                        tokio::time::timeout_at(deadline, stream.next()).await.map_err(|_| {
+                            error!("First token timeout");
+                            anyhow::anyhow!(
+                                "no response received within {}s",
+                                self.first_token_timeout().unwrap_or_default().as_secs_f32()
+                            )
+                        })
+                    }
+                    _ => Ok(stream.next().await),
+                }
+            }
+            .instrument(first_token_span.clone().unwrap_or_else(Span::none));
+            let chunk = match self.deadline() {
+                Some(deadline) => match tokio::time::timeout_at(deadline, next).await {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        error!("Deadline exceeded");
+                        return Err(DeadlineExceeded {
+                            message: Message {
+                                role: Role::Assistant,
+                                content: response.into(),
+                            },
+                        }
+                        .into());
+                    }
+                },
+                None => next.await,
+            }?;
+            let Some(chunk) = chunk else {
+                break;
+            };
+
             trace!(?chunk, "processing");
-            let chunk = chunk?;
-            buffer.put_slice(&chunk);
+            let processed = match chunk {
+                Ok(chunk) => {
+                    buffer.put_slice(&chunk);
+                    // The bytes before `scanned` have no separator, so only the new bytes can complete
+                    // an event; one byte of overlap finds a separator split between two chunks
+                    let processed = if find_separator(&buffer[scanned.saturating_sub(1)..]).is_some() {
+                        scanned = 0;
+                        self.process_buffer(&buffer).await
+                    } else {
+                        scanned = buffer.len();
+                        Ok(None)
+                    };
+                    processed.and_then(|processed| {
+                        let pending = buffer.len() - processed.as_ref().map_or(0, |(_, advance, ..)| *advance);
+                        if pending > self.max_buffer_size() {
+                            error!(pending, "Stream buffer limit exceeded");
+                            return Err(anyhow::anyhow!(
+                                "the stream buffer exceeds {} bytes without a complete event",
+                                self.max_buffer_size()
+                            ));
+                        }
+                        Ok(processed)
+                    })
+                }
+                Err(e) => Err(e.into()),
+            };
+
+            let processed = match processed {
+                Ok(processed) => processed,
+                Err(reason) => return Err(partial_response(response, reason)),
+            };
 
-            if let Some((chunks_str, advance)) = self.process_buffer(&buffer).await? {
+            if let Some((chunks, advance, skipped, failed)) = processed {
                 buffer.advance(advance);
-                for chunk_str in chunks_str {
-                    trace!(chunk_str);
-                    response.push_str(&chunk_str);
-                    sender.send(chunk_str).await?;
+                if let Some(e) = skipped {
+                    first_skipped.get_or_insert(e);
+                }
+                for chunk in chunks {
+                    trace!(?chunk);
+                    if chunk.usage.is_some() {
+                        usage = chunk.usage;
+                    }
+                    // Only the first candidate is streamed, the others are written when it finishes
+                    if chunk.index == 0 {
+                        if chunk.finish_reason.is_some() {
+                            finish_reason = chunk.finish_reason;
+                        }
+                        if chunk.content.is_empty() {
+                            continue;
+                        }
+                        if stops.is_empty() {
+                            response.push_str(&chunk.content);
+                            stats.send(sender, chunk.content).await?;
+                            continue;
+                        }
+
+                        response.push_str(&chunk.content);
+                        let search_from = floor_char_boundary(&response, sent.saturating_sub(holdback));
+                        if let Some(end) = find_stop(&response[search_from..], stops) {
+                            debug!("Stop sequence found");
+                            response.truncate(search_from + end);
+                            if response.len() > sent {
+                                stats.send(sender, response[sent..].to_string()).await?;
+                            }
+                            sent = response.len();
+                            finish_reason = Some("stop".to_string());
+                            break 'stream;
+                        }
+                        let safe = floor_char_boundary(&response, response.len().saturating_sub(holdback));
+                        if safe > sent {
+                            stats.send(sender, response[sent..safe].to_string()).await?;
+                            sent = safe;
+                        }
+                    } else {
+                        candidates.entry(chunk.index).or_default().push_str(&chunk.content);
+                    }
+                }
+                // The event after the valid ones failed, the stream may end before another read
+                if let Some(reason) = failed {
+                    return Err(partial_response(response, reason));
                 }
             }
+
+            if first_token_span.is_some() && !(response.is_empty() && candidates.is_empty()) {
+                debug!(
+                    elapsed_ms = started.elapsed().as_millis() as u64,
+                    "First token received"
+                );
+                first_token_span = None;
+            }
         }
 
-        Ok(Message {
-            role: Role::Assistant,
-            content: response,
+        // The malformed events are only skipped along with valid ones
+        if let Some(e) = first_skipped
+            && response.is_empty()
+            && candidates.is_empty()
+            && finish_reason.is_none()
+            && usage.is_none()
+        {
+            error!("No event of the response can be parsed");
+            return Err(anyhow::anyhow!("cannot parse chunk: {e}"));
+        }
+
+        if !stops.is_empty() && response.len() > sent {
+            stats.send(sender, response[sent..].to_string()).await?;
+        }
+
+        for (index, mut content) in candidates {
+            if let Some(end) = find_stop(&content, stops) {
+                content.truncate(end);
+            }
+            stats
+                .send(sender, format!("\n{}{}", candidate_header(index), content))
+                .await?;
+        }
+
+        debug!(%stats, elapsed_ms = started.elapsed().as_millis() as u64, "Stream finished");
+        if self.report_stats() {
+            eprintln!("\n{}", stats);
+        }
+
+        Ok(StreamResponse {
+            message: Message {
+                role: Role::Assistant,
+                content: response.into(),
+            },
+            finish_reason,
+            usage,
         })
     }
 
-    /// Process the entire buffer and return the complete chunk strings.
-    /// Return the chunk strings and the advancement for the buffer.
-    async fn process_buffer(&self, buffer: &[u8]) -> anyhow::Result<Option<(Vec<String>, usize)>> {
+    /// Process the entire buffer and return the complete chunks, with the index of the choice
+    /// (candidate) they belong to.
+    /// Return the chunk strings, the advancement for the buffer, the first error of the
+    /// malformed events skipped in lenient mode and the error of the event that failed after the
+    /// chunks.
+    async fn process_buffer(
+        &self,
+        buffer: &[u8],
+    ) -> anyhow::Result<Option<(Vec<Chunk>, usize, Option<String>, Option<anyhow::Error>)>> {
         if buffer.is_empty() {
             return Ok(None);
         }
@@ -64,13 +299,14 @@ pub trait Streamer: Clone + Send {
         let mut chunks = Vec::new();
         let mut total_consumed = 0;
         let mut pos = 0;
+        // First error of the malformed events skipped in lenient mode
+        let mut skipped = None;
+        // Error of the event that ends the processing, returned along with the chunks before it
+        let mut failed = None;
 
         while pos < buffer.len() {
             // Find the next chunk separator
-            if let Some(separator_pos) = buffer[pos..]
-                .windows(CHUNK_SEPARATOR.len())
-                .position(|window| window == CHUNK_SEPARATOR)
-            {
+            if let Some(separator_pos) = find_separator(&buffer[pos..]) {
                 let chunk_end = pos + separator_pos;
                 let chunk = &buffer[pos..chunk_end];
 
@@ -88,26 +324,48 @@ pub trait Streamer: Clone + Send {
                     // Try to parse as JSON
                     match serde_json::from_slice::<CopilotResponse>(json_data) {
                         Ok(resp_msg) => {
-                            if let Some(choice) = resp_msg.choices.first() {
-                                if let Some(msg) = &choice.delta {
-                                    if let Some(content) = &msg.content {
-                                        chunks.push(content.to_string());
-                                    }
+                            // An event can carry the deltas of several choices
+                            for choice in resp_msg.choices {
+                                let content = choice.delta.and_then(|delta| delta.content);
+                                if content.is_some() || choice.finish_reason.is_some() {
+                                    chunks.push(Chunk {
+                                        index: choice.index as usize,
+                                        content: content.unwrap_or_default(),
+                                        finish_reason: choice.finish_reason,
+                                        usage: None,
+                                    });
                                 }
                             }
+                            // The usage comes in the last event, usually without choices
+                            if let Some(usage) = resp_msg.usage {
+                                chunks.push(Chunk {
+                                    usage: Some(usage),
+                                    ..Default::default()
+                                });
+                            }
                         }
-                        Err(_) => {
-                            // Try to parse as error
-                            match serde_json::from_slice::<CopilotError>(json_data) {
-                                Ok(err) => {
-                                    error!(err.error.message, "error in stream");
+                        Err(e) => {
+                            if let Ok(err) = serde_json::from_slice::<CopilotError>(json_data) {
+                                error!(err.error.message, "error in stream");
+                                if chunks.is_empty() {
                                     return Err(anyhow::anyhow!(err.error.message));
                                 }
-                                Err(e) => {
-                                    error!("Failed to parse chunk as JSON: {}", String::from_utf8_lossy(json_data));
+                                failed = Some(anyhow::anyhow!(err.error.message));
+                                total_consumed = pos + separator_pos + CHUNK_SEPARATOR.len();
+                                break;
+                            }
+
+                            if !self.lenient_parse() {
+                                error!("Failed to parse chunk as JSON: {}", String::from_utf8_lossy(json_data));
+                                if chunks.is_empty() {
                                     return Err(anyhow::anyhow!("cannot parse chunk: {e}"));
                                 }
+                                failed = Some(anyhow::anyhow!("cannot parse chunk: {e}"));
+                                total_consumed = pos + separator_pos + CHUNK_SEPARATOR.len();
+                                break;
                             }
+                            warn!("Skipping malformed event: {}", String::from_utf8_lossy(json_data));
+                            skipped.get_or_insert(e.to_string());
                         }
                     }
                 }
@@ -124,11 +382,156 @@ pub trait Streamer: Clone + Send {
         if chunks.is_empty() && total_consumed == 0 {
             Ok(None)
         } else {
-            Ok(Some((chunks, total_consumed)))
+            Ok(Some((chunks, total_consumed, skipped, failed)))
         }
     }
 }
 
+/// Fail with the content received so far, a long response is still useful when it fails near
+/// the end
+fn partial_response(response: String, reason: anyhow::Error) -> anyhow::Error {
+    if response.is_empty() {
+        return reason;
+    }
+    PartialResponse {
+        message: Message {
+            role: Role::Assistant,
+            content: response.into(),
+        },
+        reason: reason.to_string(),
+    }
+    .into()
+}
+
+/// Position of the first `\n\n`, the separator of the events, in `buffer`
+fn find_separator(buffer: &[u8]) -> Option<usize> {
+    memchr::memchr_iter(b'\n', buffer).find(|&i| buffer.get(i + 1) == Some(&b'\n'))
+}
+
+/// The largest char boundary of `text` that is not after `index`
+fn floor_char_boundary(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Position of the first of the `stops` sequences in `text`
+pub fn find_stop(text: &str, stops: &[String]) -> Option<usize> {
+    stops
+        .iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| text.find(stop.as_str()))
+        .min()
+}
+
+/// Backpressure between the stream and the writer, useful for tuning the channel capacity
+#[derive(Debug, Default)]
+pub struct StreamStats {
+    pub sends: usize,
+    /// Sends that waited because the channel was full, i.e. the writer is slower than the network
+    pub blocked_sends: usize,
+    pub blocked_time: Duration,
+    /// Max chunks waiting in the channel
+    pub max_backlog: usize,
+}
+
+impl StreamStats {
+    /// Send `chunk` to the writer, tracking if the channel is full
+    async fn send(&mut self, sender: &Sender<StreamEvent>, chunk: String) -> anyhow::Result<()> {
+        self.sends += 1;
+        self.max_backlog = self.max_backlog.max(sender.max_capacity() - sender.capacity());
+
+        match sender.try_send(StreamEvent::Content(chunk)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(chunk)) => {
+                self.blocked_sends += 1;
+                let start = Instant::now();
+                sender.send(chunk).await?;
+                self.blocked_time += start.elapsed();
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(anyhow::anyhow!("the writer channel is closed")),
+        }
+    }
+}
+
+impl Display for StreamStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "stream: {} chunks, {} blocked ({:.1?}), max backlog {}",
+            self.sends, self.blocked_sends, self.blocked_time, self.max_backlog
+        )
+    }
+}
+
+/// What the stream sends to the writer
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// Content of the response
+    Content(String),
+    /// The response is complete
+    Done { finish_reason: Option<String> },
+    /// The response stopped because of an error
+    Error(String),
+}
+
+/// A piece of the response of the choice (candidate) in `index`
+#[derive(Debug, PartialEq, Default)]
+pub struct Chunk {
+    pub index: usize,
+    pub content: String,
+    /// Why the choice finished, e.g. `stop` or `length`; only set in its last chunk
+    pub finish_reason: Option<String>,
+    /// Tokens of the request, only set in the last chunk of the response
+    pub usage: Option<Usage>,
+}
+
+/// Tokens used by a request, as reported by the provider
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+/// The collected response of a stream
+#[derive(Debug)]
+pub struct StreamResponse {
+    /// The first candidate
+    pub message: Message,
+    pub finish_reason: Option<String>,
+    /// Tokens used by the request, if the provider reports them
+    pub usage: Option<Usage>,
+}
+
+/// Header written before the candidate in `index` when several are requested
+pub fn candidate_header(index: usize) -> String {
+    format!("── candidate {} ──\n", index + 1)
+}
+
+/// The response was not complete before the deadline
+#[derive(Debug, thiserror::Error)]
+#[error("the response exceeded the deadline")]
+pub struct DeadlineExceeded {
+    /// The content received before the deadline
+    pub message: Message,
+}
+
+/// The stream failed after part of the response was received
+#[derive(Debug, thiserror::Error)]
+#[error("{reason}")]
+pub struct PartialResponse {
+    /// The content received before the failure
+    pub message: Message,
+    pub reason: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct CopilotError {
     error: CopilotErrorDetail,
@@ -143,6 +546,7 @@ struct CopilotErrorDetail {
 #[derive(Debug, Deserialize)]
 struct CopilotResponse {
     choices: Vec<Choice>,
+    usage: Option<Usage>,
 }
 
 /// Content 'delta' of the message: a partial chunk of the complete message
@@ -156,28 +560,185 @@ struct Delta {
 #[derive(Debug, Deserialize)]
 struct Choice {
     delta: Option<Delta>,
+    #[serde(default)]
     index: i32,
     finish_reason: Option<String>,
 }
 
 /// Handle the stream of the chat
 #[derive(Clone)]
-pub struct ChatStreamer;
+pub struct ChatStreamer {
+    max_buffer_size: usize,
+    first_token_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    report_stats: bool,
+    lenient_parse: bool,
+    stop_sequences: Vec<String>,
+    display_rate: Option<u32>,
+}
+
+impl ChatStreamer {
+    pub fn new(max_buffer_size: usize) -> Self {
+        Self {
+            max_buffer_size,
+            first_token_timeout: None,
+            deadline: None,
+            report_stats: false,
+            lenient_parse: false,
+            stop_sequences: Vec::new(),
+            display_rate: None,
+        }
+    }
+
+    /// Stop the response if it is not complete at `deadline`
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Print the backpressure stats of each response to stderr
+    pub fn with_stats(mut self, report_stats: bool) -> Self {
+        self.report_stats = report_stats;
+        self
+    }
+
+    /// Skip the malformed events that come with valid ones instead of failing
+    pub fn with_lenient_parse(mut self, lenient_parse: bool) -> Self {
+        self.lenient_parse = lenient_parse;
+        self
+    }
+
+    /// End the response before the first of the `stop_sequences`
+    pub fn with_stop_sequences(mut self, stop_sequences: Vec<String>) -> Self {
+        self.stop_sequences = stop_sequences;
+        self
+    }
+
+    /// Fail if the first content of the response does not arrive within `timeout`
+    pub fn with_first_token_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.first_token_timeout = timeout;
+        self
+    }
+
+    /// Write at most `chars_per_sec` characters per second, the content that arrives faster is
+    /// buffered; at full speed if it is not set
+    pub fn with_display_rate(mut self, chars_per_sec: Option<u32>) -> Self {
+        self.display_rate = chars_per_sec;
+        self
+    }
+}
+
+/// Write the content of `receiver` at `chars_per_sec` until the stream ends and everything that
+/// was buffered ahead is written
+async fn write_paced(
+    writer: &mut (impl tokio::io::AsyncWrite + Unpin),
+    receiver: &mut Receiver<StreamEvent>,
+    chars_per_sec: u32,
+) -> anyhow::Result<()> {
+    let mut pending: VecDeque<char> = VecDeque::new();
+    let mut ticks = tokio::time::interval(PACE_TICK);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // Characters written since `start`, the budget starts over when the buffer runs out so the
+    // time waiting for content is not spent all at once
+    let mut start = Instant::now();
+    let mut written: usize = 0;
+    let mut open = true;
+
+    while open || !pending.is_empty() {
+        tokio::select! {
+            event = receiver.recv(), if open => match event {
+                Some(StreamEvent::Content(content)) => {
+                    if pending.is_empty() {
+                        start = Instant::now();
+                        written = 0;
+                    }
+                    pending.extend(content.chars());
+                }
+                Some(StreamEvent::Done { finish_reason }) => {
+                    debug!(?finish_reason, buffered = pending.len(), "End of streaming");
+                    open = false;
+                }
+                Some(StreamEvent::Error(e)) => {
+                    debug!(%e, "The stream failed");
+                    open = false;
+                }
+                None => {
+                    warn!("The stream closed without an end event");
+                    open = false;
+                }
+            },
+            _ = ticks.tick(), if !pending.is_empty() => {
+                let due = (start.elapsed().as_secs_f64() * chars_per_sec as f64) as usize;
+                let count = due.saturating_sub(written).min(pending.len());
+                if count > 0 {
+                    let chunk: String = pending.drain(..count).collect();
+                    writer.write_all(chunk.as_bytes()).await?;
+                    writer.flush().await?;
+                    written += count;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl Default for ChatStreamer {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_BUFFER_SIZE)
+    }
+}
 
 impl Streamer for ChatStreamer {
+    fn max_buffer_size(&self) -> usize {
+        self.max_buffer_size
+    }
+
+    fn first_token_timeout(&self) -> Option<Duration> {
+        self.first_token_timeout
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn report_stats(&self) -> bool {
+        self.report_stats
+    }
+
+    fn lenient_parse(&self) -> bool {
+        self.lenient_parse
+    }
+
+    fn stop_sequences(&self) -> &[String] {
+        &self.stop_sequences
+    }
+
     async fn write_at_end(
         &self,
         writer: &mut (impl tokio::io::AsyncWrite + Unpin),
-        mut receiver: Receiver<String>,
+        mut receiver: Receiver<StreamEvent>,
     ) -> anyhow::Result<()> {
+        if let Some(chars_per_sec) = self.display_rate {
+            return write_paced(writer, &mut receiver, chars_per_sec).await;
+        }
+
         loop {
             match receiver.recv().await {
-                Some(content) => {
+                Some(StreamEvent::Content(content)) => {
                     writer.write_all(content.as_bytes()).await?;
                     writer.flush().await?
                 }
+                Some(StreamEvent::Done { finish_reason }) => {
+                    debug!(?finish_reason, "End of streaming");
+                    break;
+                }
+                Some(StreamEvent::Error(e)) => {
+                    debug!(%e, "The stream failed");
+                    break;
+                }
                 None => {
-                    debug!("End of streaming");
+                    warn!("The stream closed without an end event");
                     break;
                 }
             };
@@ -199,28 +760,21 @@ pub(crate) mod tests {
         async fn write_at_end(
             &self,
             writer: &mut (impl tokio::io::AsyncWrite + Unpin),
-            mut receiver: Receiver<String>,
+            mut receiver: Receiver<StreamEvent>,
         ) -> anyhow::Result<()> {
-            loop {
-                match receiver.recv().await {
-                    Some(chunk) => {
-                        writer.write(chunk.as_bytes()).await?;
-                        writer.flush().await?;
-                    }
-                    None => break,
-                };
+            while let Some(StreamEvent::Content(chunk)) = receiver.recv().await {
+                writer.write_all(chunk.as_bytes()).await?;
+                writer.flush().await?;
             }
             Ok(())
         }
     }
 
-    async fn count_chunks(mut receiver: Receiver<String>) -> usize {
+    /// Count the content events, the terminal event is not a chunk
+    async fn count_chunks(mut receiver: Receiver<StreamEvent>) -> usize {
         let mut result = 0;
-        loop {
-            match receiver.recv().await {
-                Some(_) => result = result + 1,
-                None => break,
-            }
+        while let Some(StreamEvent::Content(_)) = receiver.recv().await {
+            result += 1;
         }
 
         result
@@ -239,14 +793,14 @@ pub(crate) mod tests {
 
         let streamer = TestStreamer;
         let (sender, receiver) = channel(1);
-        let resp = streamer.process_buffer(&chunk.as_bytes()).await;
+        let resp = streamer.process_buffer(chunk.as_bytes()).await;
 
         assert!(resp.is_ok());
 
-        let (msgs, _) = resp.unwrap().unwrap();
+        let (msgs, ..) = resp.unwrap().unwrap();
 
         for m in msgs {
-            sender.send(m).await.unwrap();
+            sender.send(StreamEvent::Content(m.content)).await.unwrap();
         }
         drop(sender);
 
@@ -274,14 +828,14 @@ pub(crate) mod tests {
 
         let streamer = TestStreamer;
         let (sender, receiver) = channel(2);
-        let resp = streamer.process_buffer(&double.as_bytes()).await;
+        let resp = streamer.process_buffer(double.as_bytes()).await;
 
         assert!(resp.is_ok());
 
-        let (msgs, _) = resp.unwrap().unwrap();
+        let (msgs, ..) = resp.unwrap().unwrap();
 
         for m in msgs {
-            sender.send(m).await.unwrap();
+            sender.send(StreamEvent::Content(m.content)).await.unwrap();
         }
         drop(sender);
 
@@ -305,14 +859,14 @@ pub(crate) mod tests {
 
         let streamer = TestStreamer;
         let (sender, receiver) = channel(2);
-        let resp = streamer.process_buffer(&double_incomplete.as_bytes()).await;
+        let resp = streamer.process_buffer(double_incomplete.as_bytes()).await;
 
         assert!(resp.is_ok());
 
-        let (msgs, _) = resp.unwrap().unwrap();
+        let (msgs, ..) = resp.unwrap().unwrap();
 
         for m in msgs {
-            sender.send(m).await.unwrap();
+            sender.send(StreamEvent::Content(m.content)).await.unwrap();
         }
         drop(sender);
 
@@ -335,14 +889,14 @@ pub(crate) mod tests {
 
         let streamer = TestStreamer;
         let (sender, receiver) = channel(2);
-        let resp = streamer.process_buffer(&chunks.as_bytes()).await;
+        let resp = streamer.process_buffer(chunks.as_bytes()).await;
 
         assert!(resp.is_ok());
 
-        let (msgs, _) = resp.unwrap().unwrap();
+        let (msgs, ..) = resp.unwrap().unwrap();
 
         for m in msgs {
-            sender.send(m).await.unwrap();
+            sender.send(StreamEvent::Content(m.content)).await.unwrap();
         }
         drop(sender);
 
@@ -350,4 +904,398 @@ pub(crate) mod tests {
 
         assert_eq!(count, 1);
     }
+
+    #[tokio::test]
+    async fn partial_content_on_error() {
+        let chunks = [
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\" wor\n\n",
+        ]
+        .map(|chunk| Ok(bytes::Bytes::from(chunk)));
+
+        let (sender, receiver) = channel(2);
+        let result = TestStreamer
+            .handle_stream(futures_util::stream::iter(chunks), sender)
+            .await;
+
+        let error = result.expect_err("malformed chunk").downcast::<PartialResponse>();
+        let partial = error.expect("partial response");
+        assert_eq!(partial.message.content, "Hello");
+        assert!(partial.reason.contains("cannot parse chunk"));
+        assert_eq!(count_chunks(receiver).await, 1);
+    }
+
+    #[tokio::test]
+    async fn error_event_after_content_in_the_last_read() {
+        let chunk = "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello\"}}]}\n\n\
+                     data: {\"error\":{\"message\":\"quota exceeded\"}}\n\n";
+        let stream = futures_util::stream::iter([Ok(bytes::Bytes::from(chunk))]);
+
+        let (sender, _receiver) = channel(2);
+        let result = TestStreamer.handle_stream(stream, sender).await;
+
+        let error = result.expect_err("error event").downcast::<PartialResponse>();
+        let partial = error.expect("partial response");
+        assert_eq!(partial.message.content, "Hello");
+        assert_eq!(partial.reason, "quota exceeded");
+    }
+
+    #[tokio::test]
+    async fn candidates_by_index() {
+        let chunks = [
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"first\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":1,\"delta\":{\"content\":\"second\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\" one\"}}]}\n\ndata: [DONE]\n\n",
+        ]
+        .map(|chunk| Ok(bytes::Bytes::from(chunk)));
+
+        let (sender, mut receiver) = channel(4);
+        let response = TestStreamer
+            .handle_stream(futures_util::stream::iter(chunks), sender)
+            .await
+            .expect("process the stream");
+
+        // The first candidate is the message and the others are written after it
+        assert_eq!(response.message.content, "first one");
+        let mut written = String::new();
+        while let Some(StreamEvent::Content(chunk)) = receiver.recv().await {
+            written.push_str(&chunk);
+        }
+        assert_eq!(written, format!("first one\n{}second", candidate_header(1)));
+    }
+
+    #[tokio::test]
+    async fn interleaved_choices() {
+        let events = concat!(
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"a\"}},",
+            "{\"index\":1,\"delta\":{\"content\":\"x\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":1,\"delta\":{\"content\":\"y\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"b\"}}]}\n\n",
+        );
+
+        let (chunks, advance, ..) = TestStreamer
+            .process_buffer(events.as_bytes())
+            .await
+            .expect("parse the events")
+            .expect("complete events");
+
+        assert_eq!(advance, events.len());
+        assert_eq!(
+            chunks
+                .into_iter()
+                .map(|chunk| (chunk.index, chunk.content))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, "a".to_string()),
+                (1, "x".to_string()),
+                (1, "y".to_string()),
+                (0, "b".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn capture_finish_reason() {
+        let chunks = [
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hel\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"length\"}]}\n\ndata: [DONE]\n\n",
+        ]
+        .map(|chunk| Ok(bytes::Bytes::from(chunk)));
+
+        let (sender, _receiver) = channel(2);
+        let response = TestStreamer
+            .handle_stream(futures_util::stream::iter(chunks), sender)
+            .await
+            .expect("process the stream");
+
+        assert_eq!(response.message.content, "Hel");
+        assert_eq!(response.finish_reason.as_deref(), Some("length"));
+        assert_eq!(response.usage, None);
+    }
+
+    #[tokio::test]
+    async fn capture_usage() {
+        let chunks = [
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hi\"},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":12,\"completion_tokens\":1,\"total_tokens\":13}}\n\n",
+            "data: [DONE]\n\n",
+        ]
+        .map(|chunk| Ok(bytes::Bytes::from(chunk)));
+
+        let (sender, _receiver) = channel(2);
+        let response = TestStreamer
+            .handle_stream(futures_util::stream::iter(chunks), sender)
+            .await
+            .expect("process the stream");
+
+        assert_eq!(response.message.content, "Hi");
+        assert_eq!(
+            response.usage,
+            Some(Usage {
+                prompt_tokens: 12,
+                completion_tokens: 1,
+                total_tokens: 13
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn separator_split_between_chunks() {
+        let events = "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hel\"}}]}\n\n\
+                      data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"lo\"}}]}\n\ndata: [DONE]\n\n";
+        // One byte per chunk splits every separator
+        let chunks: Vec<_> = events
+            .bytes()
+            .map(|byte| Ok(bytes::Bytes::copy_from_slice(&[byte])))
+            .collect();
+
+        let (sender, _receiver) = channel(8);
+        let response = TestStreamer
+            .handle_stream(futures_util::stream::iter(chunks), sender)
+            .await
+            .expect("process the stream");
+        assert_eq!(response.message.content, "Hello");
+    }
+
+    #[tokio::test]
+    async fn stop_sequence_split_between_chunks() {
+        let event = |content: &str| {
+            let data = serde_json::json!({"choices": [{"index": 0, "delta": {"content": content}}]});
+            Ok(bytes::Bytes::from(format!("data: {}\n\n", data)))
+        };
+        let chunks = vec![event("name: Ru"), event("st\nEN"), event("D\nignored"), event(" too")];
+
+        let (sender, mut receiver) = channel(8);
+        let response = ChatStreamer::default()
+            .with_stop_sequences(vec!["END".to_string()])
+            .handle_stream(futures_util::stream::iter(chunks), sender)
+            .await
+            .expect("process the stream");
+        assert_eq!(response.message.content, "name: Rust\n");
+        assert_eq!(response.finish_reason.as_deref(), Some("stop"));
+
+        let mut written = String::new();
+        while let Some(StreamEvent::Content(content)) = receiver.recv().await {
+            written.push_str(&content);
+        }
+        assert_eq!(written, "name: Rust\n");
+    }
+
+    #[tokio::test]
+    async fn lenient_parse_skips_malformed_events() {
+        let events = "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hel\"}}]}\n\n\
+                      data: {not json}\n\n\
+                      data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"lo\"}}]}\n\n";
+
+        // Strict parsing returns the events before the malformed one along with its error
+        let strict = ChatStreamer::default();
+        let (chunks, _, _, failed) = strict
+            .process_buffer(events.as_bytes())
+            .await
+            .expect("valid event first")
+            .expect("valid events");
+        assert_eq!(chunks.len(), 1);
+        assert!(failed.is_some());
+
+        let lenient = ChatStreamer::default().with_lenient_parse(true);
+        let (chunks, advance, skipped, _) = lenient
+            .process_buffer(events.as_bytes())
+            .await
+            .expect("skip the malformed event")
+            .expect("valid events");
+        let content: Vec<_> = chunks.iter().map(|chunk| chunk.content.as_str()).collect();
+        assert_eq!(content, ["Hel", "lo"]);
+        assert_eq!(advance, events.len());
+        assert!(skipped.is_some());
+    }
+
+    #[tokio::test]
+    async fn strict_parse_fails_in_the_last_read() {
+        let chunk = "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello\"}}]}\n\n\
+                     data: {not json}\n\n";
+        let stream = futures_util::stream::iter([Ok(bytes::Bytes::from(chunk))]);
+        let (sender, _receiver) = channel(2);
+
+        let error = ChatStreamer::default()
+            .collect_stream(stream, &sender)
+            .await
+            .expect_err("malformed event")
+            .downcast::<PartialResponse>();
+        let partial = error.expect("partial response");
+        assert_eq!(partial.message.content, "Hello");
+        assert!(partial.reason.contains("cannot parse chunk"));
+    }
+
+    #[tokio::test]
+    async fn lenient_parse_across_reads() {
+        let lenient = ChatStreamer::default().with_lenient_parse(true);
+
+        // Each event arrives in its own read, the malformed one is skipped
+        let events = [
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hel\"}}]}\n\n",
+            "data: {not json}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"lo\"}}]}\n\n",
+            "data: [DONE]\n\n",
+        ];
+        let stream = futures_util::stream::iter(events.map(|event| Ok(bytes::Bytes::from(event))));
+        let (sender, _receiver) = channel(8);
+        let response = lenient
+            .collect_stream(stream, &sender)
+            .await
+            .expect("skip the malformed event");
+        assert_eq!(response.message.content.text(), "Hello");
+
+        // Nothing to keep if every event is malformed
+        let events = ["data: {not json}\n\n", "data: [DONE]\n\n"];
+        let stream = futures_util::stream::iter(events.map(|event| Ok(bytes::Bytes::from(event))));
+        assert!(lenient.collect_stream(stream, &sender).await.is_err());
+    }
+
+    #[test]
+    fn char_boundary_before_index() {
+        assert_eq!(floor_char_boundary("añb", 2), 1);
+        assert_eq!(floor_char_boundary("añb", 3), 3);
+        assert_eq!(floor_char_boundary("añb", 10), 4);
+        assert_eq!(floor_char_boundary("", 0), 0);
+    }
+
+    #[test]
+    fn find_event_separator() {
+        assert_eq!(find_separator(b"data: a\n\ndata: b\n\n"), Some(7));
+        assert_eq!(find_separator(b"data: a\ndata: b\n\n"), Some(15));
+        assert_eq!(find_separator(b"\n\n"), Some(0));
+        assert_eq!(find_separator(b"data: a\n"), None);
+        assert_eq!(find_separator(b""), None);
+    }
+
+    #[tokio::test]
+    async fn terminal_events() {
+        let last_event = |chunks: Vec<&'static str>| async move {
+            let (sender, mut receiver) = channel(4);
+            let stream = futures_util::stream::iter(chunks.into_iter().map(|chunk| Ok(bytes::Bytes::from(chunk))));
+            let _ = TestStreamer.handle_stream(stream, sender).await;
+
+            let mut last = None;
+            while let Some(event) = receiver.recv().await {
+                last = Some(event);
+            }
+            last
+        };
+
+        let done = last_event(vec![
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hi\"},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: [DONE]\n\n",
+        ])
+        .await;
+        assert_eq!(
+            done,
+            Some(StreamEvent::Done {
+                finish_reason: Some("stop".to_string())
+            })
+        );
+
+        let failed = last_event(vec!["data: {\"error\":{\"message\":\"quota exceeded\"}}\n\n"]).await;
+        assert_eq!(failed, Some(StreamEvent::Error("quota exceeded".to_string())));
+
+        // The error after the content, in the same read and without a read after it
+        let failed = last_event(vec![
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hi\"}}]}\n\n\
+             data: {\"error\":{\"message\":\"quota exceeded\"}}\n\n",
+        ])
+        .await;
+        assert_eq!(failed, Some(StreamEvent::Error("quota exceeded".to_string())));
+    }
+
+    #[tokio::test]
+    async fn first_token_timeout() {
+        let (sender, _receiver) = channel(1);
+        let result = ChatStreamer::default()
+            .with_first_token_timeout(Some(Duration::from_millis(10)))
+            .handle_stream(futures_util::stream::pending(), sender)
+            .await;
+
+        let error = result.expect_err("no token received");
+        assert!(error.to_string().contains("no response received"));
+    }
+
+    #[tokio::test]
+    async fn backpressure_stats() {
+        let (sender, mut receiver) = channel(1);
+        let mut stats = StreamStats::default();
+
+        stats.send(&sender, "a".to_string()).await.expect("send to the channel");
+        // The channel is full until the writer reads
+        let reader = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            while receiver.recv().await.is_some() {}
+        });
+        stats.send(&sender, "b".to_string()).await.expect("send to the channel");
+        drop(sender);
+        reader.await.expect("read the channel");
+
+        assert_eq!(stats.sends, 2);
+        assert_eq!(stats.blocked_sends, 1);
+        assert_eq!(stats.max_backlog, 1);
+    }
+
+    #[tokio::test]
+    async fn deadline_keeps_partial_content() {
+        let first = "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello\"}}]}\n\n";
+        let chunks = futures_util::stream::iter([Ok(bytes::Bytes::from(first))]).chain(futures_util::stream::pending());
+
+        let (sender, _receiver) = channel(2);
+        let result = ChatStreamer::default()
+            .with_deadline(Some(Instant::now() + Duration::from_millis(20)))
+            .handle_stream(std::pin::pin!(chunks), sender)
+            .await;
+
+        let error = result.expect_err("deadline exceeded").downcast::<DeadlineExceeded>();
+        assert_eq!(error.expect("deadline error").message.content, "Hello");
+    }
+
+    #[tokio::test]
+    async fn buffer_limit() {
+        let chunk = format!("data: {}", "x".repeat(2048));
+        let chunks = [Ok(bytes::Bytes::from(chunk))];
+
+        let (sender, _receiver) = channel(1);
+        let result = ChatStreamer::new(1024)
+            .handle_stream(futures_util::stream::iter(chunks), sender)
+            .await;
+
+        let error = result.expect_err("buffer limit exceeded");
+        assert!(error.to_string().contains("exceeds 1024 bytes"));
+    }
+
+    #[tokio::test]
+    async fn display_rate_paces_the_content() {
+        let (sender, receiver) = channel(4);
+        sender
+            .send(StreamEvent::Content("a".repeat(30)))
+            .await
+            .expect("send content");
+        sender
+            .send(StreamEvent::Content("é".repeat(10)))
+            .await
+            .expect("send content");
+        sender
+            .send(StreamEvent::Done { finish_reason: None })
+            .await
+            .expect("send the end");
+
+        let started = Instant::now();
+        let mut output = Vec::new();
+        ChatStreamer::default()
+            .with_display_rate(Some(400))
+            .write_at_end(&mut output, receiver)
+            .await
+            .expect("write the content");
+
+        // 40 characters at 400 per second, the buffered content is written after the end event
+        assert!(started.elapsed() >= Duration::from_millis(90));
+        assert_eq!(
+            String::from_utf8(output).expect("utf-8"),
+            "a".repeat(30) + &"é".repeat(10)
+        );
+    }
 }
@@ -0,0 +1,110 @@
+//! Token counting for the messages of a chat, selected by the tokenizer reported in the model metadata
+
+use super::Message;
+
+/// Tokens added by the chat format to each message, e.g. the role markers
+const TOKENS_PER_MESSAGE: usize = 4;
+/// Tokens that prime the reply of the assistant
+const TOKENS_PER_REPLY: usize = 3;
+
+/// Count the tokens of a text
+pub trait Tokenizer {
+    /// Name of the encoding used to count
+    fn name(&self) -> &str;
+
+    fn count(&self, text: &str) -> usize;
+}
+
+/// Approximation used when the tokenizer of the model is unknown: one token every four characters
+pub struct Heuristic;
+
+impl Tokenizer for Heuristic {
+    fn name(&self) -> &str {
+        "chars/4"
+    }
+
+    fn count(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(4)
+    }
+}
+
+/// The exact BPE encodings of the OpenAI models
+#[cfg(feature = "tiktoken")]
+pub struct Bpe {
+    name: String,
+    bpe: &'static tiktoken_rs::CoreBPE,
+}
+
+#[cfg(feature = "tiktoken")]
+impl Tokenizer for Bpe {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn count(&self, text: &str) -> usize {
+        self.bpe.encode_with_special_tokens(text).len()
+    }
+}
+
+/// Select the implementation of the `tokenizer` named in the model metadata, e.g. `cl100k_base`.
+/// Unknown tokenizers, or all of them without the `tiktoken` feature, use the [`Heuristic`].
+pub fn for_model(tokenizer: Option<&str>) -> Box<dyn Tokenizer> {
+    #[cfg(feature = "tiktoken")]
+    {
+        let bpe = match tokenizer {
+            Some("cl100k_base") => Some(tiktoken_rs::cl100k_base_singleton()),
+            Some("o200k_base") => Some(tiktoken_rs::o200k_base_singleton()),
+            _ => None,
+        };
+        if let (Some(name), Some(bpe)) = (tokenizer, bpe) {
+            return Box::new(Bpe {
+                name: name.to_string(),
+                bpe,
+            });
+        }
+    }
+
+    tracing::debug!(?tokenizer, "Using the heuristic tokenizer");
+    Box::new(Heuristic)
+}
+
+/// Estimate the prompt tokens of `messages`, including the overhead of the chat format
+pub fn estimate_tokens(tokenizer: &dyn Tokenizer, messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .map(|message| TOKENS_PER_MESSAGE + tokenizer.count(&message.content.text()))
+        .sum::<usize>()
+        + TOKENS_PER_REPLY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::Role;
+
+    #[test]
+    fn estimate_with_heuristic() {
+        let messages = [
+            Message {
+                role: Role::System,
+                content: "Be brief".into(),
+            },
+            Message {
+                role: Role::User,
+                content: "Explain this".into(),
+            },
+        ];
+
+        let tokenizer = for_model(Some("unknown"));
+        assert_eq!(tokenizer.name(), "chars/4");
+        assert_eq!(estimate_tokens(tokenizer.as_ref(), &messages), 4 + 2 + 4 + 3 + 3);
+    }
+
+    #[cfg(feature = "tiktoken")]
+    #[test]
+    fn estimate_with_bpe() {
+        let tokenizer = for_model(Some("cl100k_base"));
+        assert_eq!(tokenizer.name(), "cl100k_base");
+        assert_eq!(tokenizer.count("hello world"), 2);
+    }
+}
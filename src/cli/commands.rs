@@ -1,4 +1,21 @@
-use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use reqwest::header::HeaderName;
+
+use super::style::{ColorChoice, SeparatorStyle};
+use crate::{
+    chat::{
+        DEFAULT_MAX_BUFFER_SIZE,
+        history::HistoryPolicy,
+        prompts::{DEFAULT_BODY_MAX, DEFAULT_BODY_MIN, DEFAULT_SUBJECT_MAX},
+    },
+    client::{DEFAULT_IDEMPOTENCY_HEADER, DEFAULT_MAX_RETRIES},
+    tools::{
+        diff::DiffStyle,
+        files::{DEFAULT_DIFF_CONTEXT, DEFAULT_MAX_FILES, DEFAULT_TAIL_LINES},
+    },
+};
 
 /// Application that provides Copilot Chat in the CLI, offering amazing speed and maximum flexibility.
 #[derive(Parser, Debug)]
@@ -11,13 +28,55 @@ pub struct Cli {
     #[arg(short, long, value_delimiter = ',')]
     pub files: Option<Vec<String>>,
 
-    /// File or directory name to be excluded from `*` pattern matches
+    /// Images sent with the prompt (png, jpeg, gif or webp), the model must accept images
+    #[arg(long = "image", value_delimiter = ',', global = true)]
+    pub images: Option<Vec<String>>,
+
+    /// Directory the files and `*` patterns are relative to, the current directory by default
+    #[arg(long, global = true, value_parser = parse_root)]
+    pub root: Option<PathBuf>,
+
+    /// File or directory name to be excluded from `*` pattern matches and attached directories
     #[arg(short, long, value_delimiter = ',')]
     pub exclude: Option<Vec<String>>,
 
-    /// Token path
-    #[arg(short, long)]
-    token_path: Option<String>,
+    /// Extensions of the files included from the attached directories, e.g. `rs,toml`; all the
+    /// text files by default
+    #[arg(long = "ext", value_delimiter = ',')]
+    pub extensions: Option<Vec<String>>,
+
+    /// Attach the tree of the project (the root or the current directory) before the files, without
+    /// the excluded, hidden and git-ignored paths
+    #[arg(long, global = true)]
+    pub prepend_file_tree: bool,
+
+    /// Attach the text of the clipboard as context, along with the piped stdin if any
+    #[arg(long, global = true)]
+    pub attach_clipboard: bool,
+
+    /// Remove the ANSI escape sequences and control characters from the piped stdin, e.g. colored logs
+    #[arg(long, global = true)]
+    pub strip_ansi: bool,
+
+    /// Name of the file the piped stdin or clipboard content comes from, used to frame it as a file
+    #[arg(long, global = true)]
+    pub stdin_name: Option<String>,
+
+    /// Format of the errors printed to stderr
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub error_format: ErrorFormat,
+
+    /// Provider that answers the requests; `mock` streams canned responses without requesting Copilot
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub provider: ProviderKind,
+
+    /// JSON file with the canned responses of `--provider mock`, it echoes the prompt without it
+    #[arg(long, global = true)]
+    pub mock_responses: Option<PathBuf>,
+
+    /// Copilot config file with the token, `hosts.json` or `apps.json` of `~/.config/github-copilot` by default
+    #[arg(short, long, global = true)]
+    pub token_path: Option<String>,
 
     /// Prompt to send to Copilot
     #[arg(trailing_var_arg = true, global = true)]
@@ -26,6 +85,330 @@ pub struct Cli {
     /// Model to be used
     #[arg(short, long, global = true)]
     pub model: Option<String>,
+
+    /// Send the model as given, without checking that it is in the models list, e.g. for gateways
+    /// with their own models
+    #[arg(long, global = true)]
+    pub no_validate_model: bool,
+
+    /// Unchanged lines sent around each change when a tracked file is updated
+    #[arg(long, global = true, default_value_t = DEFAULT_DIFF_CONTEXT)]
+    pub diff_context: usize,
+
+    /// Format of the changes sent when a tracked file is updated
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub diff_style: DiffStyle,
+
+    /// Max tokens of the response, limited to the max output tokens of the model.
+    /// `--max-tokens` is a deprecated alias
+    #[arg(long, global = true, alias = "max-tokens")]
+    pub max_response_tokens: Option<i32>,
+
+    /// Show the response through `$PAGER` (`less -R` by default) once it is complete, only in a terminal
+    #[arg(long, global = true)]
+    pub pager: bool,
+
+    /// Also attach the local files included (Rust `mod`, Python imports) by the attached files,
+    /// following them up to DEPTH levels
+    #[arg(long, global = true, value_name = "DEPTH", num_args = 0..=1, require_equals = true, default_missing_value = "1")]
+    pub follow_includes: Option<usize>,
+
+    /// Max number of files attached in a request, including the followed includes
+    #[arg(long, global = true, default_value_t = DEFAULT_MAX_FILES)]
+    pub max_files: usize,
+
+    /// Use the piped stdin as the prompt instead of the content attached to it, the args are
+    /// placed before it
+    #[arg(long, global = true)]
+    pub stdin_as_prompt: bool,
+
+    /// Send the attached files without the number of each line
+    #[arg(long, global = true)]
+    pub no_line_numbers: bool,
+
+    /// Truncate the attached files longer than this, keeping their first and last lines
+    #[arg(long, global = true)]
+    pub max_file_lines: Option<usize>,
+
+    /// Lines kept from the end of a truncated file
+    #[arg(long, global = true, default_value_t = DEFAULT_TAIL_LINES)]
+    pub tail_lines: usize,
+
+    /// Skip the files that cannot be attached and exit with an error at the end, instead of failing
+    /// at the first one
+    #[arg(long, global = true)]
+    pub keep_going: bool,
+
+    /// Do not send the prompt of the task (code, commit, ...), only the general prompt and your message
+    #[arg(long, global = true)]
+    pub no_task_prompt: bool,
+
+    /// Print the SHA256 of the complete response to stderr, useful for reproducibility checks
+    #[arg(long, global = true)]
+    pub hash_output: bool,
+
+    /// Seconds to wait for the first token of the response before failing, no limit by default
+    #[arg(long, global = true, alias = "timeout-first-token")]
+    pub first_token_timeout: Option<u64>,
+
+    /// Seconds allowed for each response, from the request to its last token. When exceeded, the
+    /// partial response is kept in the chat and the program exits with code 124
+    #[arg(long, global = true)]
+    pub deadline: Option<u64>,
+
+    /// When a response is cut by the max tokens, ask the model to continue and append the
+    /// continuation to the same response, up to N times
+    #[arg(long, global = true, require_equals = true, num_args = 0..=1, default_missing_value = "3")]
+    pub reask: Option<u32>,
+
+    /// Previous assistant turns sent with each request: `all`, `last:N` or `summary` (the last turn
+    /// and the first line of the previous ones). The saved chat keeps all of them
+    #[arg(long, global = true, default_value = "all")]
+    pub assistant_history: HistoryPolicy,
+
+    /// Show the numbered content of the files attached without a range and ask for the range to
+    /// attach, once per file and only in a terminal
+    #[arg(long, global = true, alias = "select-range-interactive")]
+    pub interactive_range: bool,
+
+    /// Also write each response to a new markdown file in this directory, named with the time it
+    /// was received
+    #[arg(long, global = true)]
+    pub save_responses: Option<PathBuf>,
+
+    /// Print the `File:` blocks of the response as a unified diff against the working tree, instead
+    /// of the response itself, so it can be reviewed and applied with `git apply`
+    #[arg(long, global = true)]
+    pub patch: bool,
+
+    /// Override the `Editor-Version` header of the requests, e.g. `vscode/1.99.0`
+    #[arg(long, global = true)]
+    pub editor_version: Option<String>,
+
+    /// Override the `Editor-Plugin-Version` header of the requests
+    #[arg(long, global = true)]
+    pub plugin_version: Option<String>,
+
+    /// Override the `Copilot-Integration-Id` header of the requests
+    #[arg(long, global = true)]
+    pub integration_id: Option<String>,
+
+    /// Header of the key sent with each completion request, the same on its retries so the provider
+    /// can discard duplicates
+    #[arg(long, global = true, default_value = DEFAULT_IDEMPOTENCY_HEADER, value_parser = parse_header_name)]
+    pub idempotency_header: HeaderName,
+
+    /// Base URL of the Copilot API, for GitHub Enterprise or a compatible gateway
+    #[arg(long, global = true, env = "COPILOT_API_BASE", value_name = "URL", value_parser = parse_base_url)]
+    pub api_base: Option<String>,
+
+    /// Base URL of the GitHub API where the token is exchanged, for GitHub Enterprise
+    #[arg(long, global = true, env = "GITHUB_API_BASE", value_name = "URL", value_parser = parse_base_url)]
+    pub github_api_base: Option<String>,
+
+    /// Proxy of the requests to GitHub and Copilot, e.g. `http://proxy.corp:8080`. It takes
+    /// precedence over the `HTTPS_PROXY` and `HTTP_PROXY` environment variables, which are used
+    /// when it is not set
+    #[arg(long, global = true, value_name = "URL", value_parser = parse_proxy)]
+    pub proxy: Option<reqwest::Proxy>,
+
+    /// Print the file where the chat of the current directory is stored and exit
+    #[arg(long, global = true)]
+    pub print_cache_path: bool,
+
+    /// Print the messages of the request, with their roles, and exit without sending it
+    #[arg(long, global = true)]
+    pub dump_messages: bool,
+
+    /// Fail when the chat cannot be saved in the cache, instead of warning and keeping the response
+    #[arg(long, global = true)]
+    pub strict: bool,
+
+    /// Skip the malformed events of the response instead of failing, unless no event is valid
+    #[arg(long, global = true)]
+    pub lenient_parse: bool,
+
+    /// Write the prompt before the response, for self-contained transcripts of one-shot or piped runs
+    #[arg(long, global = true)]
+    pub echo_prompt: bool,
+
+    /// Wait for the complete response and write it at once instead of streaming it, e.g. for
+    /// piping it into other tools; the streaming options can't be combined with it
+    #[arg(long, global = true, conflicts_with_all = ["first_token_timeout", "stats", "display_rate"])]
+    pub no_stream: bool,
+
+    /// Print the streaming stats (chunks, blocked writes and max backlog) to stderr after each response
+    #[arg(long, global = true)]
+    pub stats: bool,
+
+    /// JSON schema file that the response must follow; it is sent to models with structured outputs
+    /// and the response is validated, failing if it does not follow it
+    #[arg(long, global = true, value_name = "FILE")]
+    pub schema: Option<String>,
+
+    /// Send the schema in strict mode, the model follows it exactly; the schema must require every
+    /// property and disallow additional ones
+    #[arg(long, global = true, requires = "schema")]
+    pub strict_schema: bool,
+
+    /// Write a footer with the model, temperature, token usage and finish reason after each
+    /// response, to stderr unless `--footer=stdout` is given
+    #[arg(long, global = true, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "stderr")]
+    pub footer: Option<FooterTarget>,
+
+    /// Max characters per second written to the terminal, for a smoother reading of fast responses;
+    /// pipes and files are written at full speed
+    #[arg(long, global = true, value_name = "CPS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub display_rate: Option<u32>,
+
+    /// Max bytes buffered from the response stream while waiting for a complete event
+    #[arg(long, global = true, default_value_t = DEFAULT_MAX_BUFFER_SIZE)]
+    pub max_buffer_size: usize,
+
+    /// Number of alternative responses generated, printed one after the other; only the first
+    /// one is kept in the chat
+    #[arg(long, global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    pub candidates: Option<u32>,
+
+    /// Sequence where the response ends, repeat it for several; the provider stops there and the
+    /// response is also truncated locally
+    #[arg(long, global = true, allow_hyphen_values = true, value_parser = parse_stop)]
+    pub stop: Vec<String>,
+
+    /// Times a rate-limited request is retried before failing
+    #[arg(long, global = true, default_value_t = DEFAULT_MAX_RETRIES)]
+    pub max_retries: u32,
+
+    /// Re-send the last request of the current directory that did not complete
+    #[arg(long)]
+    pub resume_last: bool,
+
+    /// Named chat of the current directory, to keep independent conversations in the same project
+    #[arg(long, global = true, value_parser = parse_thread)]
+    pub thread: Option<String>,
+
+    /// Minutes without input before the interactive session is saved and closed, disabled by default
+    #[arg(long, global = true)]
+    pub idle_timeout: Option<u64>,
+
+    /// End the interactive session on an empty line, besides `exit` and the end of the input (Ctrl-D)
+    #[arg(long, global = true)]
+    pub exit_on_empty: bool,
+
+    /// Responses after which the interactive or TCP session is saved and closed, unlimited by default
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_turns: Option<u32>,
+
+    /// Style of the separators between turns in interactive mode, only shown in a terminal unless `--color always`
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub separator: SeparatorStyle,
+
+    /// When to color the output; `auto` colors a terminal unless the `NO_COLOR` env var is set
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub color: ColorChoice,
+
+    /// Text placed before the prompt, e.g. `"In Rust, "`
+    #[arg(long, global = true)]
+    pub prompt_prefix: Option<String>,
+
+    /// Text placed after the prompt, e.g. `" Keep it under 10 lines."`
+    #[arg(long, global = true)]
+    pub prompt_suffix: Option<String>,
+}
+
+impl Cli {
+    /// Wrap the `prompt` typed by the user with the prompt prefix and suffix
+    pub fn wrap_prompt(&self, prompt: &str) -> String {
+        format!(
+            "{}{}{}",
+            self.prompt_prefix.as_deref().unwrap_or_default(),
+            prompt,
+            self.prompt_suffix.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+/// An empty sequence would stop every response
+fn parse_stop(stop: &str) -> Result<String, String> {
+    if stop.is_empty() {
+        Err("the stop sequence cannot be empty".to_string())
+    } else {
+        Ok(stop.to_string())
+    }
+}
+
+/// The name must be a valid HTTP header
+fn parse_header_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name `{}`", name))
+}
+
+/// The base of the endpoints must be an HTTP(S) URL
+fn parse_base_url(url: &str) -> Result<String, String> {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Ok(url.to_string()),
+        _ => Err(format!("`{}` is not an HTTP(S) URL", url)),
+    }
+}
+
+/// The proxy must be a valid URL
+fn parse_proxy(url: &str) -> Result<reqwest::Proxy, String> {
+    reqwest::Proxy::all(url).map_err(|e| format!("invalid proxy `{}`: {}", url, e))
+}
+
+/// The files are resolved in the root, so it must be an existing directory
+fn parse_root(root: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(root);
+    if path.is_dir() {
+        Ok(path)
+    } else {
+        Err(format!("{} is not a directory", root))
+    }
+}
+
+/// The thread is part of the name of the cache files, so only letters, digits, `-` and `_` are allowed
+fn parse_thread(thread: &str) -> Result<String, String> {
+    if !thread.is_empty()
+        && thread
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+    {
+        Ok(thread.to_string())
+    } else {
+        Err(format!(
+            "invalid thread `{}`, only letters, digits, `-` and `_` are allowed",
+            thread
+        ))
+    }
+}
+
+/// The provider that answers the requests
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum ProviderKind {
+    /// GitHub Copilot
+    #[default]
+    Copilot,
+    /// Offline canned responses, for testing integrations
+    Mock,
+}
+
+/// Where the footer of a response is written
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum FooterTarget {
+    /// Keep stdout clean for the response
+    #[default]
+    Stderr,
+    /// Inline after the response
+    Stdout,
+}
+
+/// How errors are reported
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum ErrorFormat {
+    /// `Error: <message>`
+    #[default]
+    Human,
+    /// `{"error":{"kind":"<kind>","message":"<message>"}}`
+    Json,
 }
 
 #[derive(Debug, Subcommand, PartialEq)]
@@ -34,14 +417,70 @@ pub enum Command {
     Tcp {
         /// Port to bind, default: 4000
         #[arg(short, long)]
-        port: Option<String>
+        port: Option<String>,
     },
     /// Write the commit message for the current directory
-    Commit,
+    Commit {
+        /// Describe all the changes since this reference (e.g. `main`) as a pull request instead
+        #[arg(long, conflicts_with = "diff_file")]
+        since: Option<String>,
+        /// Read the diff from this file instead of the staged changes, no repository is needed
+        #[arg(long)]
+        diff_file: Option<PathBuf>,
+        /// Describe the unstaged changes of the working tree instead of the staged ones
+        #[arg(long, conflicts_with_all = ["since", "diff_file"])]
+        unstaged: bool,
+        /// Max characters of the header of the message
+        #[arg(long, default_value_t = DEFAULT_SUBJECT_MAX)]
+        subject_max: usize,
+        /// Min characters of the body of the message
+        #[arg(long, default_value_t = DEFAULT_BODY_MIN)]
+        body_min: usize,
+        /// Max characters of the body of the message
+        #[arg(long, default_value_t = DEFAULT_BODY_MAX)]
+        body_max: usize,
+    },
+    /// Write a markdown pull request description for the changes of the current branch
+    PrDescription {
+        /// Branch or reference the changes are compared against
+        #[arg(long, default_value = "main")]
+        base: String,
+    },
+    /// Write a changelog from the git history
+    Changelog {
+        /// Tag or reference of the previous release, the latest tag by default
+        #[arg(long)]
+        since: Option<String>,
+    },
+    /// Ask for the Git commands that solve a task, e.g. `git how do I rebase onto main`
+    Git,
     /// List all the available models
-    Models,
-    /// Clear the chat history for the current directory
+    Models {
+        /// Print only the number of models
+        #[arg(long)]
+        count: bool,
+        /// Print the models with all their capabilities (limits, vision, tokenizer...) as a JSON array
+        #[arg(long, conflicts_with = "count")]
+        json: bool,
+    },
+    /// Print the prompt in effect for each message type
+    Prompts,
+    /// Show the GitHub account and Copilot plan of the active token
+    Whoami,
+    /// Sign in to GitHub with a device code and store the token in the Copilot config file
+    Login,
+    /// Clear the chat history for the current directory, of the `--thread` if it is set
     Clear,
+    /// List the chat threads saved for the current directory
+    Threads,
+    /// Continue the saved chat of the current directory in interactive mode
+    Resume,
+    /// Replace the chat history of the current directory with a summary written by the model
+    Compact {
+        /// Do not ask for confirmation before replacing the history
+        #[arg(long, short)]
+        yes: bool,
+    },
 }
 
 #[cfg(test)]
@@ -67,9 +506,184 @@ mod tests {
         let req = vec!["copilot-chat", "commit", "write", "a", "cool", "message"];
         let cli = Cli::parse_from(req);
 
-        assert_eq!(cli.command.expect("commit command"), Command::Commit);
+        assert_eq!(
+            cli.command.expect("commit command"),
+            Command::Commit {
+                since: None,
+                diff_file: None,
+                unstaged: false,
+                subject_max: DEFAULT_SUBJECT_MAX,
+                body_min: DEFAULT_BODY_MIN,
+                body_max: DEFAULT_BODY_MAX,
+            }
+        );
         assert!(cli.prompt.is_some());
 
         assert_eq!(cli.prompt.expect("prompt args"), vec!["write", "a", "cool", "message"]);
     }
+
+    #[test]
+    fn test_git_args() {
+        let cli = Cli::parse_from(["copilot-chat", "git", "how", "do", "I", "rebase"]);
+
+        assert_eq!(cli.command.expect("git command"), Command::Git);
+        assert_eq!(cli.prompt.expect("prompt args"), vec!["how", "do", "I", "rebase"]);
+    }
+
+    #[test]
+    fn test_wrap_prompt() {
+        let cli = Cli::parse_from(["copilot-chat", "--prompt-suffix", " Only the code.", "fix it"]);
+        assert_eq!(cli.wrap_prompt("fix it"), "fix it Only the code.");
+
+        let cli = Cli::parse_from(["copilot-chat", "fix it"]);
+        assert_eq!(cli.wrap_prompt("fix it"), "fix it");
+    }
+
+    #[test]
+    fn test_idempotency_header_args() {
+        let cli = Cli::parse_from(["copilot-chat"]);
+        assert_eq!(cli.idempotency_header, DEFAULT_IDEMPOTENCY_HEADER);
+
+        let cli = Cli::parse_from(["copilot-chat", "--idempotency-header", "X-Request-Id"]);
+        assert_eq!(cli.idempotency_header, "x-request-id");
+
+        assert!(Cli::try_parse_from(["copilot-chat", "--idempotency-header", "bad header"]).is_err());
+    }
+
+    #[test]
+    fn test_api_base_args() {
+        let cli = Cli::parse_from(["copilot-chat", "--api-base", "https://copilot-api.example.ghe.com"]);
+        assert_eq!(cli.api_base.as_deref(), Some("https://copilot-api.example.ghe.com"));
+
+        assert!(Cli::try_parse_from(["copilot-chat", "--api-base", "copilot-api.example.ghe.com"]).is_err());
+        assert!(Cli::try_parse_from(["copilot-chat", "--github-api-base", "ftp://example.com"]).is_err());
+    }
+
+    #[test]
+    fn test_footer_args() {
+        let cli = Cli::parse_from(["copilot-chat", "--footer", "explain", "this"]);
+        assert_eq!(cli.footer, Some(FooterTarget::Stderr));
+        assert_eq!(cli.prompt.expect("prompt"), vec!["explain", "this"]);
+
+        let cli = Cli::parse_from(["copilot-chat", "--footer=stdout", "explain"]);
+        assert_eq!(cli.footer, Some(FooterTarget::Stdout));
+
+        assert_eq!(Cli::parse_from(["copilot-chat"]).footer, None);
+    }
+
+    #[test]
+    fn test_no_stream_args() {
+        let cli = Cli::parse_from(["copilot-chat", "--no-stream", "--stop", "END", "explain"]);
+        assert!(cli.no_stream);
+
+        for flag in [
+            ["--stats", ""],
+            ["--display-rate", "300"],
+            ["--first-token-timeout", "5"],
+        ] {
+            let args = ["copilot-chat", "--no-stream", flag[0], flag[1]]
+                .into_iter()
+                .filter(|arg| !arg.is_empty());
+            assert!(Cli::try_parse_from(args).is_err(), "{} is a streaming option", flag[0]);
+        }
+    }
+
+    #[test]
+    fn test_proxy_args() {
+        let cli = Cli::parse_from(["copilot-chat", "models", "--proxy", "http://proxy.corp:8080"]);
+        assert!(cli.proxy.is_some());
+
+        assert!(Cli::try_parse_from(["copilot-chat", "--proxy", "not a url"]).is_err());
+    }
+
+    #[test]
+    fn test_compact_args() {
+        let cli = Cli::parse_from(["copilot-chat", "compact", "-y"]);
+        assert_eq!(cli.command.expect("compact command"), Command::Compact { yes: true });
+
+        let cli = Cli::parse_from(["copilot-chat", "compact"]);
+        assert_eq!(cli.command.expect("compact command"), Command::Compact { yes: false });
+    }
+
+    #[test]
+    fn test_models_args() {
+        let cli = Cli::parse_from(["copilot-chat", "models", "--count"]);
+        assert_eq!(
+            cli.command.expect("models command"),
+            Command::Models {
+                count: true,
+                json: false
+            }
+        );
+
+        let cli = Cli::parse_from(["copilot-chat", "models"]);
+        assert_eq!(
+            cli.command.expect("models command"),
+            Command::Models {
+                count: false,
+                json: false
+            }
+        );
+
+        let cli = Cli::parse_from(["copilot-chat", "models", "--json"]);
+        assert_eq!(
+            cli.command.expect("models command"),
+            Command::Models {
+                count: false,
+                json: true
+            }
+        );
+
+        assert!(Cli::try_parse_from(["copilot-chat", "models", "--json", "--count"]).is_err());
+    }
+
+    #[test]
+    fn test_thread_args() {
+        let cli = Cli::parse_from(["copilot-chat", "clear", "--thread", "refactor-2"]);
+        assert_eq!(cli.thread.as_deref(), Some("refactor-2"));
+
+        assert!(Cli::try_parse_from(["copilot-chat", "--thread", "../other"]).is_err());
+        assert!(Cli::try_parse_from(["copilot-chat", "--thread", ""]).is_err());
+    }
+
+    #[test]
+    fn test_commit_since_args() {
+        let req = vec!["copilot-chat", "commit", "--since", "main"];
+        let cli = Cli::parse_from(req);
+
+        assert_eq!(
+            cli.command.expect("commit command"),
+            Command::Commit {
+                since: Some("main".to_string()),
+                diff_file: None,
+                unstaged: false,
+                subject_max: DEFAULT_SUBJECT_MAX,
+                body_min: DEFAULT_BODY_MIN,
+                body_max: DEFAULT_BODY_MAX,
+            }
+        );
+        assert!(cli.prompt.is_none());
+    }
+
+    #[test]
+    fn test_commit_diff_file_args() {
+        let cli = Cli::parse_from(["copilot-chat", "commit", "--diff-file", "changes.patch"]);
+
+        assert_eq!(
+            cli.command.expect("commit command"),
+            Command::Commit {
+                since: None,
+                diff_file: Some(PathBuf::from("changes.patch")),
+                unstaged: false,
+                subject_max: DEFAULT_SUBJECT_MAX,
+                body_min: DEFAULT_BODY_MIN,
+                body_max: DEFAULT_BODY_MAX,
+            }
+        );
+        assert!(Cli::try_parse_from(["copilot-chat", "commit", "--diff-file", "a.patch", "--since", "main"]).is_err());
+
+        let cli = Cli::parse_from(["copilot-chat", "commit", "--unstaged"]);
+        assert!(matches!(cli.command, Some(Command::Commit { unstaged: true, .. })));
+        assert!(Cli::try_parse_from(["copilot-chat", "commit", "--unstaged", "--since", "main"]).is_err());
+    }
 }
@@ -1,11 +1,35 @@
 use crate::{
-    chat::{Chat, ChatStreamer, Message, MessageType, Role, errors::ChatError},
-    cli::commands::{Cli, Command},
-    client::{CopilotClient, provider::Provider},
+    chat::{Chat, ChatStreamer, Message, MessageType, Role, Usage, errors::ChatError, prompts},
+    cli::{
+        commands::{Cli, Command, FooterTarget},
+        input::LineReader,
+        signal::ShutdownSignal,
+        style::SeparatorStyle,
+    },
+    client::{
+        Backend,
+        auth::CopilotAuth,
+        provider::{ModelInfo, Provider, RequestOptions, TEMPERATURE},
+    },
+    tools::{
+        cli::CliExecutor,
+        files::{FileOptions, Truncation},
+        image::Image,
+        patch,
+        schema::Schema,
+        url::is_url,
+    },
 };
-use std::path::PathBuf;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fs::read_dir, io::Write};
-use tokio::{io::AsyncReadExt, net::TcpListener};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    time::Instant,
+};
 use tracing::{debug, info, warn};
 
 #[derive(Debug, PartialEq)]
@@ -13,15 +37,25 @@ use tracing::{debug, info, warn};
 pub enum ExecutionType {
     Once,
     Interactive,
+    Resume,
+    Compact,
     Exit,
 }
 
 impl From<&Command> for ExecutionType {
     fn from(value: &Command) -> Self {
         match value {
-            Command::Tcp { port: _ } => ExecutionType::Interactive,
-            Command::Commit => ExecutionType::Once,
-            Command::Models | Command::Clear => ExecutionType::Exit,
+            Command::Tcp { port: _ } | Command::Resume => ExecutionType::Interactive,
+            Command::Commit { .. } | Command::PrDescription { .. } | Command::Changelog { .. } | Command::Git => {
+                ExecutionType::Once
+            }
+            Command::Compact { .. } => ExecutionType::Compact,
+            Command::Models { .. }
+            | Command::Prompts
+            | Command::Whoami
+            | Command::Login
+            | Command::Clear
+            | Command::Threads => ExecutionType::Exit,
         }
     }
 }
@@ -39,15 +73,63 @@ impl<'a> CommandHandler<'a> {
         }
     }
 
-    pub async fn prepare(&mut self, client: CopilotClient) -> anyhow::Result<ExecutionHandler> {
+    pub async fn prepare(&mut self, client: Backend) -> anyhow::Result<ExecutionHandler> {
         let mut is_tcp = false;
         let mut final_port = "4000";
 
         match &self.cli_command.command {
-            Some(Command::Models) => {
-                client.get_models().await?;
+            Some(Command::Models { json: true, .. }) => {
+                println!("{}", serde_json::to_string_pretty(&client.models_json().await?)?);
+            }
+            Some(Command::Models { count, .. }) => {
+                let models = client.get_models().await?;
+                if *count {
+                    println!("{}", models.len());
+                } else {
+                    print!("{}", models_table(&models));
+                }
+            }
+            Some(Command::Prompts) => {
+                for (name, prompt) in prompts::ALL {
+                    // The commit prompt is a template of the length limits
+                    let prompt = match *name {
+                        "commit" => prompts::CommitLimits::default().commit_prompt(),
+                        _ => prompt.to_string(),
+                    };
+                    println!("== {name} (built-in) ==\n{}\n", prompt.trim());
+                }
             }
-            Some(Command::Clear) => match Chat::<CopilotClient>::try_load_chat(None)? {
+            Some(Command::Whoami) => {
+                let identity = client.copilot()?.whoami().await?;
+                match &identity.name {
+                    Some(name) => println!("Account: {} ({})", identity.login, name),
+                    None => println!("Account: {}", identity.login),
+                }
+                println!("Plan: {}", identity.plan.as_deref().unwrap_or("unknown"));
+                if let Some(expires_at) = identity.expires_at {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    println!("Token expires in: {}s", expires_at.saturating_sub(now));
+                }
+            }
+            Some(Command::Login) => {
+                let token = client
+                    .copilot()?
+                    .login(|code| {
+                        println!(
+                            "Open {} and enter the code {} (it expires in {}m)",
+                            code.verification_uri,
+                            code.user_code,
+                            code.expires_in / 60
+                        );
+                    })
+                    .await?;
+                let path = CopilotAuth::store_token(&token)?;
+                println!("Signed in, the token is stored in {}", path.display());
+            }
+            Some(Command::Clear) => match Chat::<Backend>::try_load_chat(None, self.cli_command.thread.as_deref())? {
                 Some(chat) => {
                     chat.remove_chat(None)?;
                     println!("Chat cleared successfully");
@@ -56,23 +138,55 @@ impl<'a> CommandHandler<'a> {
                     println!("Chat not found; skipping clearing.");
                 }
             },
+            Some(Command::Threads) => {
+                for thread in Chat::<Backend>::list_threads(None)? {
+                    println!("{}", thread.as_deref().unwrap_or("(default)"));
+                }
+            }
+            Some(Command::Commit { body_min, body_max, .. }) if body_min > body_max => {
+                return Err(anyhow::anyhow!(
+                    "--body-min ({}) cannot be greater than --body-max ({})",
+                    body_min,
+                    body_max
+                ));
+            }
             Some(Command::Tcp { port }) => {
                 if let Some(port) = port {
                     final_port = port
                 }
                 is_tcp = true;
             }
-            Some(Command::Commit) | None => {}
+            Some(
+                Command::Commit { .. }
+                | Command::PrDescription { .. }
+                | Command::Changelog { .. }
+                | Command::Git
+                | Command::Compact { .. }
+                | Command::Resume,
+            )
+            | None => {}
         };
 
-        let chat = self.resolve_chat(client);
-        let message_type = MessageType::from(&*self);
-        let execution_type = if let Some(command) = &self.cli_command.command {
+        let execution_type = if self.cli_command.resume_last {
+            ExecutionType::Resume
+        } else if let Some(command) = &self.cli_command.command {
             ExecutionType::from(command)
         } else {
             ExecutionType::Interactive
         };
 
+        // A typo in the model would fail in the middle of the first request
+        if let Some(model) = &self.cli_command.model
+            && execution_type != ExecutionType::Exit
+            && !self.cli_command.no_validate_model
+            && !self.cli_command.dump_messages
+        {
+            validate_model(&client, model).await?;
+        }
+
+        let chat = self.resolve_chat(client)?;
+        let message_type = MessageType::from(&*self);
+
         debug!(?message_type, "Received");
 
         Ok(ExecutionHandler {
@@ -81,6 +195,7 @@ impl<'a> CommandHandler<'a> {
             execution_type,
             is_tcp,
             port: final_port.to_string(),
+            cache: None,
         })
     }
 
@@ -89,7 +204,7 @@ impl<'a> CommandHandler<'a> {
     /// directory and child directories. Also exclude all the file or directory names that match
     /// with any of the `exclude` vector
     pub fn expand_files_from_dir(
-        cwd: &PathBuf,
+        root: &Path,
         files: Option<&Vec<String>>,
         exclude: Option<&Vec<String>>,
     ) -> std::io::Result<Option<Vec<String>>> {
@@ -99,9 +214,14 @@ impl<'a> CommandHandler<'a> {
                 if file.contains("*") {
                     // TODO: This handles `*` if it does not have an extension?
                     let ext = file.strip_prefix("*.").unwrap_or("");
-                    files_result.append(&mut Self::find_files_with_ext(cwd.clone(), ext, files, exclude)?);
+                    files_result.append(&mut Self::find_files_with_ext(
+                        root.to_path_buf(),
+                        Some(ext),
+                        files,
+                        exclude,
+                    )?);
                 } else {
-                    files_result.push(file.to_string())
+                    files_result.push(Self::resolve_file(root, file))
                 }
             }
             Ok(Some(files_result))
@@ -110,11 +230,21 @@ impl<'a> CommandHandler<'a> {
         }
     }
 
+    /// The path of `file` relative to `root`; the files relative to the current directory, absolute
+    /// or remote are kept as they are
+    fn resolve_file(root: &Path, file: &str) -> String {
+        if is_url(file) || Path::new(file).is_absolute() || std::env::current_dir().is_ok_and(|cwd| cwd == root) {
+            return file.to_string();
+        }
+        root.join(file).display().to_string()
+    }
+
     /// Walk through the directories recursively and look for all files that match the pattern
-    /// also exlude the files or directories that match with any element in `exlude`
-    fn find_files_with_ext(
+    /// also exlude the files or directories that match with any element in `exlude`. Every file
+    /// matches if `ext` is not set.
+    pub(crate) fn find_files_with_ext(
         dir: PathBuf,
-        ext: &str,
+        ext: Option<&str>,
         files: &Vec<String>,
         exclude: Option<&Vec<String>>,
     ) -> std::io::Result<Vec<String>> {
@@ -125,12 +255,11 @@ impl<'a> CommandHandler<'a> {
             let element = element?;
             let metadata = element.metadata()?;
 
-            if let Some(name) = element.file_name().to_str() {
-                if let Some(exclude) = exclude
-                    && exclude.iter().any(|ex| ex == name)
-                {
-                    continue;
-                }
+            if let Some(name) = element.file_name().to_str()
+                && let Some(exclude) = exclude
+                && exclude.iter().any(|ex| ex == name)
+            {
+                continue;
             }
 
             if metadata.is_dir() {
@@ -139,7 +268,8 @@ impl<'a> CommandHandler<'a> {
             } else if metadata.is_file() {
                 // TODO: Enhance this
                 let path = element.path().to_str().unwrap_or("").to_string();
-                if path.ends_with(&format!(".{}", ext)) && !files.contains(&path) {
+                let matches = ext.is_none_or(|ext| path.ends_with(&format!(".{}", ext)));
+                if matches && !files.contains(&path) {
                     files_result.push(path);
                 }
             }
@@ -148,28 +278,97 @@ impl<'a> CommandHandler<'a> {
         Ok(files_result)
     }
 
-    fn resolve_chat(&self, client: CopilotClient) -> Chat<CopilotClient> {
-        match self.cli_command.command {
-            Some(Command::Commit) => Chat::new(client),
-            Some(Command::Tcp { port: _ }) | None => match Chat::try_load_chat(None).unwrap_or_else(|e| {
-                warn!("Chat cannot be loaded: {e}");
-                None
-            }) {
-                Some(chat) => chat.with_provider(client),
-                None => Chat::new(client),
-            },
-            Some(Command::Models | Command::Clear) => Chat::new(CopilotClient::default()),
-        }
+    fn resolve_chat(&self, client: Backend) -> anyhow::Result<Chat<Backend>> {
+        let options = RequestOptions {
+            max_tokens: self.cli_command.max_response_tokens,
+            candidates: self.cli_command.candidates,
+            images: self
+                .cli_command
+                .images
+                .iter()
+                .flatten()
+                .map(|path| Image::load(path))
+                .collect::<anyhow::Result<_>>()?,
+            stop: self.cli_command.stop.clone(),
+            schema: self
+                .cli_command
+                .schema
+                .as_deref()
+                .map(|path| Schema::load(path).map(|schema| schema.with_strict(self.cli_command.strict_schema)))
+                .transpose()?,
+        };
+
+        let mut chat = match self.cli_command.command {
+            Some(Command::Commit { .. } | Command::PrDescription { .. } | Command::Changelog { .. } | Command::Git) => {
+                Chat::new(client)
+            }
+            Some(Command::Tcp { port: _ } | Command::Compact { .. }) | None => {
+                match Chat::try_load_chat(None, self.cli_command.thread.as_deref()).unwrap_or_else(|e| {
+                    warn!("Chat cannot be loaded: {e}");
+                    None
+                }) {
+                    Some(chat) => chat.with_provider(client),
+                    None => Chat::new(client),
+                }
+            }
+            Some(Command::Resume) => {
+                let thread = self.cli_command.thread.as_deref();
+                let chat = Chat::try_load_chat(None, thread)?.ok_or_else(|| match thread {
+                    Some(thread) => anyhow::anyhow!("There is no saved chat for the thread {} to resume", thread),
+                    None => anyhow::anyhow!("There is no saved chat for the current directory to resume"),
+                })?;
+                println!("Resuming the chat with {} messages", chat.messages().len());
+                chat.with_provider(client)
+            }
+            Some(
+                Command::Models { .. }
+                | Command::Prompts
+                | Command::Whoami
+                | Command::Login
+                | Command::Clear
+                | Command::Threads,
+            ) => Chat::new(Backend::default()),
+        };
+
+        chat.set_options(options);
+        chat.set_thread(self.cli_command.thread.clone());
+        chat.set_skip_task_prompt(self.cli_command.no_task_prompt);
+        chat.set_history_policy(self.cli_command.assistant_history);
+        chat.set_file_options(FileOptions {
+            diff_context: self.cli_command.diff_context,
+            diff_style: self.cli_command.diff_style,
+            follow_includes: self.cli_command.follow_includes,
+            max_files: self.cli_command.max_files,
+            keep_going: self.cli_command.keep_going,
+            truncation: self
+                .cli_command
+                .max_file_lines
+                .map(|max_lines| Truncation::new(max_lines, self.cli_command.tail_lines)),
+            line_numbers: !self.cli_command.no_line_numbers,
+            exclude: self.cli_command.exclude.clone().unwrap_or_default(),
+            extensions: self.cli_command.extensions.clone().unwrap_or_default(),
+            interactive_range: self.cli_command.interactive_range,
+            file_tree: self.cli_command.prepend_file_tree.then(|| {
+                self.cli_command
+                    .root
+                    .clone()
+                    .unwrap_or_else(|| std::env::current_dir().unwrap_or_default())
+            }),
+            ..Default::default()
+        });
+        Ok(chat)
     }
 }
 
 #[derive(Debug)]
 pub struct ExecutionHandler {
-    pub chat: Chat<CopilotClient>,
+    pub chat: Chat<Backend>,
     pub message_type: MessageType,
     pub execution_type: ExecutionType,
     pub is_tcp: bool,
     pub port: String,
+    /// Directory of the saved chats, the default cache if it is not set
+    pub cache: Option<String>,
 }
 
 impl ExecutionHandler {
@@ -182,46 +381,226 @@ impl ExecutionHandler {
     ) -> Result<(), ChatError> {
         let stdin_str = if !stdin_str.is_empty() { Some(stdin_str) } else { None };
 
-        // Process the first request directly if it is not a TCP request.
-        if !self.is_tcp {
-            debug!("Processing first message");
-            self.process_request(cli, streamer.clone(), writer, stdin_str).await?;
-            self.chat.save_chat(None)?;
-            self.message_type.clear_user_prompt();
-        }
+        let separators = cli.separator.with_color(cli.color);
+
+        // Listen before the first request so a termination request never kills an unsaved chat
+        let mut shutdown = ShutdownSignal::new().map_err(ChatError::Cache)?;
+
+        // A termination request cancels the current turn, keeping what the chat has so far
+        let turn = tokio::select! {
+            responded = self.first_turn(cli, streamer, writer, stdin_str, separators) => {
+                if responded? { Turn::Responded } else { Turn::Continue }
+            }
+            _ = shutdown.recv() => Turn::Shutdown,
+        };
+
+        let history_path = Chat::<Backend>::get_cache_path(self.cache.as_deref())
+            .ok()
+            .map(|cache| cache.join("history"));
+        let session = Session {
+            line_reader: LineReader::new(history_path),
+            separators,
+            idle_timeout: cli.idle_timeout.map(|minutes| Duration::from_secs(minutes * 60)),
+        };
+        self.run_session(cli, streamer, &session, &mut shutdown, turn).await
+    }
 
-        let mut stdout = std::io::stdout();
+    /// Read and answer the prompts of the session until it ends, starting after the first turn
+    async fn run_session(
+        &mut self,
+        cli: &Cli,
+        streamer: &ChatStreamer,
+        session: &Session,
+        shutdown: &mut ShutdownSignal,
+        mut turn: Turn,
+    ) -> Result<(), ChatError> {
+        let mut turns = 0;
 
         // Main interaction loop
         loop {
-            debug!("Capturing new message");
+            match turn {
+                Turn::Responded => turns += 1,
+                Turn::Continue => {}
+                Turn::Exit => break,
+                // The pending read of stdin can't be cancelled, the process exits without waiting for it
+                Turn::Idle if !self.is_tcp => exit_process(),
+                Turn::Idle => break,
+                Turn::Shutdown => {
+                    info!("Termination signal received, shutting down");
+                    saved(cli, self.chat.save_chat(self.cache.as_deref()))?;
+                    exit_process();
+                }
+            }
 
-            let req = if self.is_tcp {
-                // TCP mode - receive request over socket
-                read_from_socket(&self.port)
-                    .await
-                    .map_err(|e| ChatError::Request(e.to_string()))?
-            } else {
-                print!("\n\n> ");
-                stdout.flush().map_err(ChatError::Cache)?;
+            // The chat is saved after each turn
+            if let Some(max_turns) = cli.max_turns
+                && turns >= max_turns
+            {
+                info!(turns, "Max turns reached");
+                eprintln!("\nReached the max turns ({}); chat saved, exiting.", max_turns);
+                break;
+            }
 
-                read_from_stdin().await.map_err(|e| ChatError::Request(e.to_string()))?
+            turn = tokio::select! {
+                turn = self.next_turn(cli, streamer, session) => turn?,
+                _ = shutdown.recv() => Turn::Shutdown,
             };
+        }
+        Ok(())
+    }
 
-            if req.prompt.trim() == "exit" {
-                break;
+    /// Process the first request directly, before reading the next prompts. It is skipped over TCP
+    /// and when nothing was given to send, e.g. a bare launch or a resumed chat, so no empty
+    /// request is made. Returns whether the request was processed.
+    async fn first_turn(
+        &mut self,
+        cli: &Cli,
+        streamer: &ChatStreamer,
+        writer: tokio::io::Stdout,
+        stdin_str: Option<String>,
+        separators: SeparatorStyle,
+    ) -> Result<bool, ChatError> {
+        let has_files = matches!(&self.message_type, MessageType::Code { files: Some(files), .. } if !files.is_empty());
+        if self.is_tcp || (stdin_str.is_none() && !self.message_type.has_user_prompt() && !has_files) {
+            debug!("Nothing to send, waiting for the next prompt");
+            return Ok(false);
+        }
+
+        debug!("Processing first message");
+        echo_prompt(cli, &self.message_type);
+        print_separator(separators, &Role::Assistant);
+        self.process_request(cli, streamer.clone(), writer, stdin_str).await?;
+        saved(cli, self.chat.save_chat(self.cache.as_deref()))?;
+        self.write_footer(cli);
+        self.message_type.clear_user_prompt();
+
+        Ok(true)
+    }
+
+    /// Read the next request of the session and process it
+    async fn next_turn(&mut self, cli: &Cli, streamer: &ChatStreamer, session: &Session) -> Result<Turn, ChatError> {
+        debug!("Capturing new message");
+
+        if !self.is_tcp {
+            print!("\n\n");
+            print_separator(session.separators, &Role::User);
+        }
+
+        let read = async {
+            if self.is_tcp {
+                // TCP mode - receive request over socket
+                read_from_socket(&self.port).await.map(Some)
+            } else {
+                read_from_stdin(&session.line_reader).await
             }
+        };
 
-            self.message_type = MessageType::Code {
-                user_prompt: Some(req.prompt.trim().to_string()),
-                files: req.files,
-            };
+        let req = match session.idle_timeout {
+            Some(timeout) => tokio::select! {
+                req = read => req,
+                _ = tokio::time::sleep(timeout) => {
+                    info!(?timeout, "Idle timeout reached");
+                    saved(cli, self.chat.save_chat(self.cache.as_deref()))?;
+                    println!("\nNo input received in {}; chat saved, exiting.", format_duration(timeout));
+                    return Ok(Turn::Idle);
+                }
+            },
+            None => read.await,
+        }
+        .map_err(|e| ChatError::Request(e.to_string()))?;
 
-            let writer = tokio::io::stdout();
-            self.process_request(cli, streamer.clone(), writer, None).await?;
-            self.chat.save_chat(None)?;
+        let Some(req) = req else {
+            info!("End of the input, exiting");
+            return Ok(Turn::Exit);
+        };
+        if req.prompt.trim() == "exit" {
+            return Ok(Turn::Exit);
+        }
+        // An empty line (only Enter) has nothing to send
+        if req.prompt.trim().is_empty() && req.files.is_none() && !self.is_tcp {
+            return Ok(if cli.exit_on_empty { Turn::Exit } else { Turn::Continue });
+        }
+
+        if let Some(command) = SlashCommand::parse(&req.prompt) {
+            self.run_slash_command(command);
+            return Ok(Turn::Continue);
+        }
+
+        if let Some(role) = req.role.filter(|role| *role != Role::User) {
+            info!(?role, "Adding the message to the chat");
+            self.chat.add_instruction(
+                Message {
+                    role,
+                    content: req.prompt.trim().into(),
+                },
+                &self.message_type,
+            )?;
+            saved(cli, self.chat.save_chat(self.cache.as_deref()))?;
+            return Ok(Turn::Continue);
+        }
+
+        self.message_type = MessageType::Code {
+            user_prompt: Some(cli.wrap_prompt(req.prompt.trim())),
+            files: req.files,
+        };
+        // The prompts typed in the terminal are already visible
+        if self.is_tcp {
+            echo_prompt(cli, &self.message_type);
+        }
+
+        print_separator(session.separators, &Role::Assistant);
+
+        let writer = tokio::io::stdout();
+        self.process_request(cli, streamer.clone(), writer, None).await?;
+        saved(cli, self.chat.save_chat(self.cache.as_deref()))?;
+        self.write_footer(cli);
+
+        Ok(Turn::Responded)
+    }
+
+    /// Write the footer of the last response to the target of `--footer`, if it is set and a
+    /// request was made
+    pub fn write_footer(&self, cli: &Cli) {
+        let (Some(target), Some(model)) = (cli.footer, self.chat.model()) else {
+            return;
+        };
+
+        let footer = footer(model, self.chat.usage(), self.chat.finish_reason());
+        match target {
+            FooterTarget::Stderr => eprintln!("\n{}", footer),
+            FooterTarget::Stdout => println!("\n{}", footer),
+        }
+    }
+
+    /// Update the files attached to the requests of the session
+    fn run_slash_command(&mut self, command: SlashCommand) {
+        match command {
+            SlashCommand::Attach(files) if files.is_empty() => println!("Usage: /attach <file>[:start-end]..."),
+            SlashCommand::Attach(files) => {
+                for file in files {
+                    match self.chat.attach(&file) {
+                        Ok(()) => println!("Attached {}", file),
+                        Err(e) => eprintln!("Error: {}", e),
+                    }
+                }
+            }
+            SlashCommand::Detach(files) if files.is_empty() => println!("Usage: /detach <file>..."),
+            SlashCommand::Detach(files) => {
+                for file in files {
+                    if self.chat.detach(&file) {
+                        println!("Detached {}", file);
+                    } else {
+                        println!("{} is not attached", file);
+                    }
+                }
+            }
+            SlashCommand::Files if self.chat.attachments().is_empty() => println!("No files attached"),
+            SlashCommand::Files => {
+                for file in self.chat.attachments() {
+                    println!("{}", file);
+                }
+            }
         }
-        Ok(())
     }
 
     pub async fn process_request(
@@ -234,7 +613,7 @@ impl ExecutionHandler {
         let message = if let Some(stdin_str) = stdin_str {
             let message = Message {
                 role: Role::User,
-                content: stdin_str,
+                content: stdin_str.into(),
             };
             Some(message)
         } else {
@@ -243,28 +622,584 @@ impl ExecutionHandler {
 
         debug!(?self.message_type, "User message");
 
-        let response_message = self
-            .chat
-            .send_message_with_stream(
-                cli.model.as_deref(),
+        let use_pager = cli.pager && atty::is(atty::Stream::Stdout);
+        // The pager and the patch use the whole response, so nothing is streamed to stdout
+        let quiet = use_pager || cli.patch;
+        let streamer = streamer.with_deadline(cli.deadline.map(|secs| Instant::now() + Duration::from_secs(secs)));
+        let model = cli.model.as_deref();
+        let message_type = self.message_type.clone();
+
+        let result = self
+            .send(
+                cli,
+                model,
                 message,
-                self.message_type.clone(),
+                message_type,
                 streamer.clone(),
-                writer,
+                (!quiet).then_some(writer),
+            )
+            .await;
+
+        let mut response_message = match result {
+            Ok(message) => message,
+            Err(e) => {
+                // The chat contains the assembled request at this point, keep it for `--resume-last`
+                saved(
+                    cli,
+                    self.chat
+                        .save_pending_request(model, &self.message_type, self.cache.as_deref()),
+                )?;
+                // Otherwise the partial content is already in stdout
+                if use_pager && let ChatError::PartialStream { content, .. } | ChatError::Deadline { content } = &e {
+                    page(content).await?;
+                }
+                if let ChatError::PartialStream { content, .. } | ChatError::Deadline { content } = &e
+                    && !content.is_empty()
+                {
+                    self.chat.add_message(Message {
+                        role: Role::Assistant,
+                        content: content.as_str().into(),
+                    });
+                    // Only the code chats are kept in the history
+                    if matches!(self.message_type, MessageType::Code { .. }) {
+                        saved(cli, self.chat.save_chat(self.cache.as_deref()))?;
+                    }
+                }
+                return Err(e);
+            }
+        };
+
+        let mut reasks = 0;
+        while let Some(max_reasks) = cli.reask
+            && reasks < max_reasks
+            && self.chat.is_truncated()
+        {
+            reasks += 1;
+            info!(reasks, "The response was truncated, asking to continue");
+
+            self.chat.add_message(response_message.clone());
+            let len = self.chat.messages().len();
+            let message = Some(Message {
+                role: Role::User,
+                content: prompts::CONTINUE.into(),
+            });
+            let result = self
+                .send(
+                    cli,
+                    model,
+                    message,
+                    MessageType::default(),
+                    streamer.clone(),
+                    (!quiet).then(tokio::io::stdout),
+                )
+                .await;
+
+            // The partial response and the request to continue are merged into one response
+            self.chat.truncate_messages(len - 1);
+            match result {
+                Ok(continuation) => response_message.content.push_str(&continuation.content.text()),
+                Err(e) => {
+                    // Keep the response received so far, the continuation only adds to it
+                    if let ChatError::PartialStream { content, .. } | ChatError::Deadline { content } = &e {
+                        response_message.content.push_str(content);
+                    }
+                    if use_pager {
+                        page(&response_message.content.text()).await?;
+                    }
+                    self.chat.add_message(response_message);
+                    // Only the code chats are kept in the history
+                    if matches!(self.message_type, MessageType::Code { .. }) {
+                        saved(cli, self.chat.save_chat(self.cache.as_deref()))?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        if reasks > 0 && self.chat.is_truncated() {
+            eprintln!("\nThe response is still truncated after {} continuations", reasks);
+        }
+
+        if let Err(e) = self.chat.validate_response(&response_message) {
+            saved(
+                cli,
+                self.chat
+                    .save_pending_request(model, &self.message_type, self.cache.as_deref()),
+            )?;
+            return Err(e);
+        }
+
+        if cli.patch {
+            let blocks = patch::parse_file_blocks(&response_message.content.text());
+            if blocks.is_empty() {
+                eprintln!("The response does not contain `File:` blocks, there is nothing to patch");
+            }
+            print!(
+                "{}",
+                patch::to_patch(&blocks).map_err(|e| ChatError::Tool(e.to_string()))?
+            );
+        } else if use_pager {
+            page(&response_message.content.text()).await?;
+        }
+
+        if cli.hash_output {
+            eprintln!("\nsha256: {}", content_hash(&response_message.content.text()));
+        }
+
+        if let Some(dir) = &cli.save_responses {
+            let file = save_response(dir, &response_message.content.text(), SystemTime::now())?;
+            debug!(?file, "Response saved");
+        }
+
+        self.chat.add_message(response_message);
+        saved(
+            cli,
+            Chat::<Backend>::remove_pending_request(self.cache.as_deref(), self.chat.thread()),
+        )?;
+
+        Ok(())
+    }
+
+    /// Send the request, writing the response to `writer` if it is set. The response is streamed
+    /// unless `--no-stream` is given, then it is written at once.
+    async fn send(
+        &mut self,
+        cli: &Cli,
+        model: Option<&str>,
+        message: Option<Message>,
+        message_type: MessageType,
+        streamer: ChatStreamer,
+        writer: Option<tokio::io::Stdout>,
+    ) -> Result<Message, ChatError> {
+        let writer: Box<dyn AsyncWrite + Send + Unpin> = match writer {
+            Some(writer) => Box::new(writer),
+            None => Box::new(tokio::io::sink()),
+        };
+
+        if cli.no_stream {
+            self.chat
+                .send_message(model, message, message_type, &streamer, writer)
+                .await
+        } else {
+            self.chat
+                .send_message_with_stream(model, message, message_type, streamer, writer)
+                .await
+        }
+    }
+
+    /// Print the messages that the request would send, without sending it
+    pub async fn dump_messages(&mut self, model: Option<&str>, stdin_str: Option<String>) -> Result<(), ChatError> {
+        let message = stdin_str.map(|content| Message {
+            role: Role::User,
+            content: content.into(),
+        });
+        self.chat.assemble_messages(message, &self.message_type).await?;
+
+        for message in self.chat.messages() {
+            println!("── {} ──\n{}\n", message.role, message.content.text().trim_end());
+        }
+
+        let (tokens, tokenizer) = self.chat.estimate_tokens(model).await;
+        eprintln!("~{} prompt tokens ({})", tokens, tokenizer);
+        Ok(())
+    }
+
+    /// Ask the model to summarize the chat and replace the history with the summary, keeping the
+    /// general prompt. Unless `yes` is set, the user confirms before the history is replaced.
+    pub async fn compact(
+        &mut self,
+        cli: &Cli,
+        streamer: ChatStreamer,
+        writer: tokio::io::Stdout,
+        yes: bool,
+    ) -> Result<(), ChatError> {
+        let replaced = self.chat.messages().len();
+        // The opening prompts are kept, so there is nothing to gain without other messages
+        if replaced <= 2 {
+            println!("The chat is empty; nothing to compact.");
+            return Ok(());
+        }
+
+        if !yes
+            && !confirm(&format!(
+                "Replace the {} messages of the chat with a summary? [y/N] ",
+                replaced
+            ))?
+        {
+            println!("Compaction cancelled.");
+            return Ok(());
+        }
+
+        let message = Message {
+            role: Role::User,
+            content: prompts::COMPACT.into(),
+        };
+        let summary = self
+            .send(
+                cli,
+                cli.model.as_deref(),
+                Some(message),
+                MessageType::default(),
+                streamer,
+                Some(writer),
             )
             .await?;
+
+        // The prompts that open the chat are kept: the general one and the task prompt, if it was sent
+        let task_prompt = MessageType::default().to_string();
+        let mut messages: Vec<Message> = self
+            .chat
+            .messages()
+            .into_iter()
+            .take(2)
+            .take_while(|message| {
+                let content = message.content.text();
+                content == prompts::GENERAL || content == task_prompt
+            })
+            .collect();
+        messages.push(Message {
+            role: Role::System,
+            content: format!(
+                "Summary of the previous conversation:\n\n{}",
+                summary.content.text().trim()
+            )
+            .into(),
+        });
+        self.chat.set_messages(messages);
+        saved(cli, self.chat.save_chat(self.cache.as_deref()))?;
+        println!("\n\nChat compacted: {} messages replaced by a summary.", replaced);
+
+        Ok(())
+    }
+
+    /// Re-send verbatim the last request that did not complete and save the chat if it is a code chat
+    pub async fn resume_last(
+        &mut self,
+        cli: &Cli,
+        streamer: ChatStreamer,
+        writer: tokio::io::Stdout,
+    ) -> Result<(), ChatError> {
+        let Some(request) = Chat::<Backend>::try_load_pending_request(self.cache.as_deref(), self.chat.thread())?
+        else {
+            return Err(ChatError::Request("There is no pending request to resume".to_string()));
+        };
+
+        info!(messages = request.messages.len(), "Resuming the last request");
+        self.chat.set_messages(request.messages);
+        // The response ends at the stop sequences of the original request
+        let streamer = streamer.with_stop_sequences(request.options.stop.clone());
+        self.chat.set_options(RequestOptions {
+            max_tokens: cli.max_response_tokens.or(request.options.max_tokens),
+            ..request.options
+        });
+        self.message_type = MessageType::default();
+
+        // Prefer the model of the original request unless the user overrides it
+        let model = cli.model.as_deref().or(request.model.as_deref());
+        let response_message = self
+            .send(cli, model, None, self.message_type.clone(), streamer, Some(writer))
+            .await?;
         self.chat.add_message(response_message);
+        // Only the code chats are kept in the history, the others would replace it
+        if request.code_chat {
+            saved(cli, self.chat.save_chat(self.cache.as_deref()))?;
+        }
+        saved(
+            cli,
+            Chat::<Backend>::remove_pending_request(self.cache.as_deref(), self.chat.thread()),
+        )?;
 
         Ok(())
     }
 }
 
-#[derive(Default)]
+/// Settings of an interactive session
+struct Session {
+    line_reader: LineReader,
+    separators: SeparatorStyle,
+    idle_timeout: Option<Duration>,
+}
+
+/// What the interactive loop does after a turn
+#[derive(Debug, PartialEq)]
+enum Turn {
+    /// A request was answered, it counts for `--max-turns`
+    Responded,
+    Continue,
+    Exit,
+    /// Nothing was received before `--idle-timeout`, the chat is already saved
+    Idle,
+    Shutdown,
+}
+
+/// A command of the interactive session, handled without sending a request
+#[derive(Debug, PartialEq)]
+enum SlashCommand {
+    /// `/attach <file>[:start-end]...`
+    Attach(Vec<String>),
+    /// `/detach <file>...`
+    Detach(Vec<String>),
+    /// `/files`, list the attached files
+    Files,
+}
+
+impl SlashCommand {
+    fn parse(input: &str) -> Option<Self> {
+        let mut words = input.split_whitespace();
+        let command = match words.next()? {
+            "/attach" => Self::Attach(words.map(str::to_string).collect()),
+            "/detach" => Self::Detach(words.map(str::to_string).collect()),
+            "/files" => Self::Files,
+            _ => return None,
+        };
+
+        Some(command)
+    }
+}
+
+/// One model per line with its name, vendor and context window, aligned in columns
+fn models_table(models: &[ModelInfo]) -> String {
+    let id_width = models.iter().map(|model| model.id.len()).max().unwrap_or_default();
+    let name_width = models.iter().map(|model| model.name.len()).max().unwrap_or_default();
+    let vendor_width = models.iter().map(|model| model.vendor.len()).max().unwrap_or_default();
+
+    models
+        .iter()
+        .map(|model| {
+            let context = model
+                .context_window
+                .map_or_else(|| "-".to_string(), |tokens| tokens.to_string());
+            let line = format!(
+                "{:id_width$}  {:name_width$}  {:vendor_width$}  {}",
+                model.id, model.name, model.vendor, context
+            );
+            format!("{}\n", line.trim_end())
+        })
+        .collect()
+}
+
+/// Max models suggested when the requested one is not available
+const MAX_MODEL_SUGGESTIONS: usize = 3;
+
+/// Check that `model` is one of the models of the provider
+async fn validate_model(provider: &impl Provider, model: &str) -> anyhow::Result<()> {
+    let models = provider.get_models().await?;
+    let ids: Vec<String> = models.into_iter().map(|available| available.id).collect();
+    if ids.iter().any(|id| id == model) {
+        return Ok(());
+    }
+
+    warn!(%model, ?ids, "Unknown model");
+    Err(ChatError::UnknownModel {
+        model: model.to_string(),
+        suggestions: closest_models(model, &ids),
+    }
+    .into())
+}
+
+/// The models most similar to `model`; the ones that contain it first, e.g. `gpt-4` for `gpt-4o`,
+/// then the ones within a few edits of it
+fn closest_models(model: &str, models: &[String]) -> Vec<String> {
+    let model = model.to_lowercase();
+    let max_distance = (model.chars().count() / 3).max(2);
+
+    let mut candidates: Vec<(usize, &String)> = models
+        .iter()
+        .filter_map(|available| {
+            let lowercase = available.to_lowercase();
+            if lowercase.contains(&model) || model.contains(&lowercase) {
+                return Some((0, available));
+            }
+            let distance = edit_distance(&model, &lowercase);
+            (distance <= max_distance).then_some((distance, available))
+        })
+        .collect();
+    candidates.sort();
+
+    candidates
+        .into_iter()
+        .take(MAX_MODEL_SUGGESTIONS)
+        .map(|(_, available)| available.clone())
+        .collect()
+}
+
+/// Levenshtein distance between `a` and `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// The footer of a response, for comparing the outputs of several runs
+fn footer(model: &str, usage: Option<Usage>, finish_reason: Option<&str>) -> String {
+    let tokens = match usage {
+        Some(usage) => format!(
+            "{} prompt + {} completion = {}",
+            usage.prompt_tokens, usage.completion_tokens, usage.total_tokens
+        ),
+        None => "unknown".to_string(),
+    };
+    format!(
+        "model: {} | temperature: {} | tokens: {} | finish: {}",
+        model,
+        TEMPERATURE,
+        tokens,
+        finish_reason.unwrap_or("unknown")
+    )
+}
+
+/// Format `duration` for the messages, in whole minutes when possible, e.g. `1 minute`, `90 seconds`
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (value, unit) = if seconds >= 60 && seconds.is_multiple_of(60) {
+        (seconds / 60, "minute")
+    } else {
+        (seconds, "second")
+    };
+    format!("{} {}{}", value, unit, if value == 1 { "" } else { "s" })
+}
+
+/// Terminate the process once the chat is saved. A read of stdin can't be cancelled, it blocks a
+/// thread that the runtime would wait for before exiting.
+fn exit_process() -> ! {
+    std::io::stdout().flush().ok();
+    std::process::exit(0)
+}
+
+/// Hex-encoded SHA256 of `content`
+fn content_hash(content: &str) -> String {
+    Sha256::digest(content.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Write the response `content` to a new markdown file in `dir` named with the time it was
+/// received, creating the directory if it is missing. Returns the path of the file.
+fn save_response(dir: &Path, content: &str, received_at: SystemTime) -> Result<PathBuf, ChatError> {
+    std::fs::create_dir_all(dir)?;
+    let time = received_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let name = format!("response-{}-{:03}", time.as_secs(), time.subsec_millis());
+
+    let mut file = dir.join(format!("{}.md", name));
+    let mut suffix = 0;
+    loop {
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&file) {
+            Ok(mut handle) => {
+                handle.write_all(content.as_bytes())?;
+                return Ok(file);
+            }
+            // Responses received in the same millisecond get a suffix
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                suffix += 1;
+                file = dir.join(format!("{}-{}.md", name, suffix));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Show `content` through the pager of `$PAGER`, or `less -R` if it is not set
+async fn page(content: &str) -> Result<(), ChatError> {
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| "less -R".to_string());
+    let mut parts = pager.split_whitespace();
+    let command = parts.next().unwrap_or("less");
+    let args = parts.collect::<Vec<_>>();
+
+    debug!(%pager, "Paging the response");
+    CliExecutor::new()
+        .pipe(command, &args, content)
+        .await
+        .map_err(|e| ChatError::Tool(format!("cannot run the pager `{}`: {}", pager, e)))
+}
+
+/// Print the separator of `role` in its own line, if the style is enabled
+fn print_separator(style: SeparatorStyle, role: &Role) {
+    if let Some(separator) = style.render(role) {
+        println!("{}", separator);
+    }
+}
+
+/// The result of saving to the cache; a failure only loses the history, so it is a warning unless
+/// `--strict` is set
+fn saved(cli: &Cli, result: Result<(), ChatError>) -> Result<(), ChatError> {
+    match result {
+        Err(e) if !cli.strict => {
+            warn!(%e, "The chat cannot be saved");
+            eprintln!("\nWarning: the chat cannot be saved: {}", e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Print the user prompt of the request as a quote with `--echo-prompt`, the patch is kept clean
+fn echo_prompt(cli: &Cli, message_type: &MessageType) {
+    if !cli.echo_prompt || cli.patch {
+        return;
+    }
+    if let Some(prompt) = message_type.user_prompt() {
+        println!("{}\n", quote(prompt));
+    }
+}
+
+/// Prefix each line of `text` with `> `, like a Markdown quote
+fn quote(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            if line.is_empty() {
+                ">".to_string()
+            } else {
+                format!("> {}", line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Ask a yes/no `question` in the terminal, a piped stdin cannot answer so it means no
+fn confirm(question: &str) -> std::io::Result<bool> {
+    if !atty::is(atty::Stream::Stdin) {
+        eprintln!("Cannot ask for confirmation without a terminal, use --yes to skip it");
+        return Ok(false);
+    }
+
+    print!("{}", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// A request received in interactive mode. Over TCP it is either the legacy `file@prompt` text or
+/// a frame with the JSON of this struct, prefixed by its length as a 4-byte big-endian integer.
+#[derive(Default, Deserialize)]
 struct RequestProtocol {
     prompt: String,
+    #[serde(default)]
     files: Option<Vec<String>>,
+    /// Role of the message, `user` if it is not set. The messages of other roles are added to the
+    /// chat without sending a request, e.g. a system directive for the next prompts.
+    #[serde(default)]
+    role: Option<Role>,
 }
 
+/// Max bytes of a framed request, the largest length whose first byte is zero
+const MAX_FRAME_SIZE: u32 = (1 << 24) - 1;
+
+/// Max time waiting for the request of a connection
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
 impl RequestProtocol {
     fn from_input(raw_input: &str) -> Self {
         let (file_str, prompt) = match raw_input.split_once('@') {
@@ -279,50 +1214,116 @@ impl RequestProtocol {
         Self {
             prompt: prompt.to_string(),
             files,
+            role: None,
         }
     }
 }
 
-async fn read_from_stdin() -> anyhow::Result<RequestProtocol> {
-    let mut read_str = String::new();
-    let stdin = std::io::stdin();
-
+/// Read the next request typed by the user, `None` at the end of the input (EOF)
+async fn read_from_stdin(line_reader: &LineReader) -> anyhow::Result<Option<RequestProtocol>> {
     debug!("Reading from interactive mode");
-    stdin.read_line(&mut read_str).map_err(ChatError::Cache)?;
+    let read_str = line_reader.read_line("> ").await.map_err(ChatError::Cache)?;
+    if read_str.is_empty() {
+        return Ok(None);
+    }
 
-    Ok(RequestProtocol::from_input(&read_str))
+    Ok(Some(RequestProtocol::from_input(&read_str)))
 }
 
 async fn read_from_socket(port: &str) -> anyhow::Result<RequestProtocol> {
     let bind = format!("127.0.0.1:{}", port);
     let tcp = TcpListener::bind(&bind).await?;
     info!("Listening on {}", bind);
-    let (mut connection, addr) = tcp.accept().await?;
-    info!(%addr, "Connection received");
-    let mut input = String::new();
-    let mut buffer = [0u8; 1024];
-
-    let start = std::time::Instant::now();
-    let timeout = std::time::Duration::from_secs(5);
-
-    while input.is_empty() && start.elapsed() < timeout {
-        match connection.read(&mut buffer).await {
-            Ok(0) => break,
-            Ok(n) => input.push_str(std::str::from_utf8(&buffer[..n])?),
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    accept_request(&tcp).await
+}
+
+/// Read a length-prefixed JSON request
+async fn read_frame(connection: &mut TcpStream) -> anyhow::Result<RequestProtocol> {
+    let length = connection.read_u32().await?;
+    if length > MAX_FRAME_SIZE {
+        return Err(anyhow::anyhow!(
+            "the request of {} bytes exceeds the limit of {} bytes",
+            length,
+            MAX_FRAME_SIZE
+        ));
+    }
+
+    // The payload grows as it arrives, a length without data does not allocate it
+    let mut payload = Vec::new();
+    connection.take(length as u64).read_to_end(&mut payload).await?;
+    if payload.len() < length as usize {
+        return Err(anyhow::anyhow!(
+            "the connection closed after {} of {} bytes",
+            payload.len(),
+            length
+        ));
+    }
+    debug!(length, "Framed request received");
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+/// Wait for a connection that sends a request. The connections that close without data or only
+/// send whitespace, e.g. health checks and editor probes, are skipped.
+async fn accept_request(tcp: &TcpListener) -> anyhow::Result<RequestProtocol> {
+    'accept: loop {
+        let (mut connection, addr) = tcp.accept().await?;
+        info!(%addr, "Connection received");
+
+        // A frame length starts with a zero byte, which never starts a text request
+        let mut first = [0u8; 1];
+        match tokio::time::timeout(READ_TIMEOUT, connection.peek(&mut first)).await {
+            Ok(Ok(0)) | Err(_) => {
+                info!(%addr, "No data received, waiting for the next connection");
+                continue;
+            }
+            Ok(Ok(_)) if first[0] == 0 => match tokio::time::timeout(READ_TIMEOUT, read_frame(&mut connection)).await {
+                Ok(Ok(request)) => return Ok(request),
+                Ok(Err(e)) => {
+                    warn!(%addr, %e, "Invalid framed request, waiting for the next connection");
+                    continue;
+                }
+                Err(_) => {
+                    warn!(%addr, "The framed request is not complete, waiting for the next connection");
+                    continue;
+                }
+            },
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                warn!(%addr, %e, "The connection failed, waiting for the next connection");
                 continue;
             }
-            Err(e) => return Err(e.into()),
         }
 
-        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
-    }
-    if input.is_empty() {
-        return Err(anyhow::anyhow!("No data received within timeout"));
+        let mut input = String::new();
+        let mut buffer = [0u8; 1024];
+
+        let start = std::time::Instant::now();
+
+        while input.trim().is_empty() && start.elapsed() < READ_TIMEOUT {
+            match connection.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(n) => input.push_str(std::str::from_utf8(&buffer[..n])?),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    continue;
+                }
+                Err(e) => {
+                    warn!(%addr, %e, "The connection failed, waiting for the next connection");
+                    continue 'accept;
+                }
+            }
+
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+
+        if input.trim().is_empty() {
+            info!(%addr, "No data received, waiting for the next connection");
+            continue;
+        }
+
+        debug!(%input, "Received");
+        return Ok(RequestProtocol::from_input(&input));
     }
-    debug!(%input, "Received");
-    Ok(RequestProtocol::from_input(&input))
 }
 
 #[cfg(test)]
@@ -332,6 +1333,417 @@ mod tests {
     use tempfile::tempdir;
 
     use super::*;
+    use crate::client::mock::{MOCK_MODEL, MockProvider, MockResponse};
+
+    #[test]
+    fn cache_failures_are_warnings() {
+        let failure = || {
+            Err(ChatError::Cache(std::io::Error::from(
+                std::io::ErrorKind::ReadOnlyFilesystem,
+            )))
+        };
+
+        let cli = Cli::parse_from(["copilot-chat"]);
+        assert!(saved(&cli, failure()).is_ok());
+
+        let cli = Cli::parse_from(["copilot-chat", "--strict"]);
+        assert!(matches!(saved(&cli, failure()), Err(ChatError::Cache(_))));
+    }
+
+    #[tokio::test]
+    async fn skip_empty_first_turn() {
+        // Any request fails, so the turn would return an error if it was sent
+        let failing = MockProvider::new(vec![MockResponse {
+            status: Some(500),
+            ..Default::default()
+        }]);
+        let mut handler = ExecutionHandler {
+            chat: Chat::new(Backend::Mock(failing)),
+            message_type: MessageType::default(),
+            execution_type: ExecutionType::Interactive,
+            is_tcp: false,
+            port: String::new(),
+            cache: None,
+        };
+        let cli = Cli::parse_from(["copilot-chat"]);
+
+        let processed = handler
+            .first_turn(
+                &cli,
+                &ChatStreamer::default(),
+                tokio::io::stdout(),
+                None,
+                SeparatorStyle::None,
+            )
+            .await
+            .expect("no request is made");
+        assert!(!processed);
+        assert!(handler.chat.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keep_the_response_when_the_continuation_fails() {
+        let provider = MockProvider::new(vec![
+            MockResponse {
+                chunks: vec!["partial".to_string()],
+                finish_reason: Some("length".to_string()),
+                ..Default::default()
+            },
+            MockResponse {
+                status: Some(500),
+                ..Default::default()
+            },
+        ]);
+        // Not a code chat, so nothing is saved to the cache
+        let mut handler = ExecutionHandler {
+            chat: Chat::new(Backend::Mock(provider)),
+            message_type: MessageType::Git(Some("how do I rebase".to_string())),
+            execution_type: ExecutionType::Once,
+            is_tcp: false,
+            port: String::new(),
+            cache: None,
+        };
+        let cli = Cli::parse_from(["copilot-chat", "--provider", "mock", "--reask", "1"]);
+
+        let result = handler
+            .process_request(&cli, ChatStreamer::default(), tokio::io::stdout(), None)
+            .await;
+
+        assert!(result.is_err());
+        let last = handler.chat.messages().last().cloned().expect("the response is kept");
+        assert_eq!(last.role, Role::Assistant);
+        assert_eq!(last.content.text(), "partial");
+    }
+
+    #[tokio::test]
+    async fn keep_the_partial_response_when_the_stream_fails() {
+        let cache = tempdir().expect("create temp dir");
+        let provider = MockProvider::new(vec![MockResponse {
+            chunks: vec!["partial".to_string()],
+            error: Some("quota exceeded".to_string()),
+            ..Default::default()
+        }]);
+        let mut handler = ExecutionHandler {
+            chat: Chat::new(Backend::Mock(provider)),
+            message_type: MessageType::Git(Some("how do I rebase".to_string())),
+            execution_type: ExecutionType::Once,
+            is_tcp: false,
+            port: String::new(),
+            cache: Some(cache.path().to_string_lossy().into_owned()),
+        };
+        let cli = Cli::parse_from(["copilot-chat", "--provider", "mock"]);
+
+        let result = handler
+            .process_request(&cli, ChatStreamer::default(), tokio::io::stdout(), None)
+            .await;
+
+        assert!(matches!(result, Err(ChatError::PartialStream { .. })));
+        let last = handler.chat.messages().last().cloned().expect("the response is kept");
+        assert_eq!(last.role, Role::Assistant);
+        assert_eq!(last.content.text(), "partial");
+    }
+
+    #[tokio::test]
+    async fn exit_at_the_end_of_the_input() {
+        // Any request fails, so the turn would return an error if it was sent
+        let failing = MockProvider::new(vec![MockResponse {
+            status: Some(500),
+            ..Default::default()
+        }]);
+        let mut handler = ExecutionHandler {
+            chat: Chat::new(Backend::Mock(failing)),
+            message_type: MessageType::default(),
+            execution_type: ExecutionType::Interactive,
+            is_tcp: false,
+            port: String::new(),
+            cache: None,
+        };
+        let session = |input: &'static str| Session {
+            line_reader: LineReader::from_reader(std::io::Cursor::new(input)),
+            separators: SeparatorStyle::None,
+            idle_timeout: None,
+        };
+        let cli = Cli::parse_from(["copilot-chat"]);
+        let streamer = ChatStreamer::default();
+
+        let eof = session("");
+        assert_eq!(handler.next_turn(&cli, &streamer, &eof).await.expect("eof"), Turn::Exit);
+
+        let empty_line = session("\n");
+        assert_eq!(
+            handler
+                .next_turn(&cli, &streamer, &empty_line)
+                .await
+                .expect("empty line"),
+            Turn::Continue
+        );
+        assert_eq!(
+            handler.next_turn(&cli, &streamer, &empty_line).await.expect("eof"),
+            Turn::Exit
+        );
+
+        let cli = Cli::parse_from(["copilot-chat", "--exit-on-empty"]);
+        let empty_line = session("  \nnot read\n");
+        assert_eq!(
+            handler
+                .next_turn(&cli, &streamer, &empty_line)
+                .await
+                .expect("empty line"),
+            Turn::Exit
+        );
+        assert!(handler.chat.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stop_after_the_max_turns() {
+        let cache = tempfile::tempdir().expect("create temp dir");
+        // Without canned responses the mock echoes each prompt
+        let mut handler = ExecutionHandler {
+            chat: Chat::new(Backend::Mock(MockProvider::default())),
+            message_type: MessageType::default(),
+            execution_type: ExecutionType::Interactive,
+            is_tcp: false,
+            port: String::new(),
+            cache: Some(cache.path().to_string_lossy().into_owned()),
+        };
+        let session = Session {
+            line_reader: LineReader::from_reader(std::io::Cursor::new("one\ntwo\nthree\n")),
+            separators: SeparatorStyle::None,
+            idle_timeout: None,
+        };
+        let cli = Cli::parse_from(["copilot-chat", "--provider", "mock", "--max-turns", "2"]);
+        let mut shutdown = ShutdownSignal::new().expect("listen for the signal");
+
+        handler
+            .run_session(&cli, &ChatStreamer::default(), &session, &mut shutdown, Turn::Continue)
+            .await
+            .expect("run the session");
+
+        let responses: Vec<_> = handler
+            .chat
+            .messages()
+            .iter()
+            .filter(|message| message.role == Role::Assistant)
+            .map(|message| message.content.text().into_owned())
+            .collect();
+        assert_eq!(responses, ["one", "two"]);
+        // The third prompt is left unread
+        assert_eq!(session.line_reader.read_line("").await.expect("read"), "three\n");
+        assert!(
+            Chat::<Backend>::try_load_chat(cache.path().to_str(), None)
+                .expect("load the chat")
+                .is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn validate_requested_model() {
+        let client = Backend::Mock(MockProvider::default());
+        assert!(validate_model(&client, MOCK_MODEL).await.is_ok());
+
+        let error = validate_model(&client, "mokc").await.expect_err("unknown model");
+        assert_eq!(
+            error.to_string(),
+            format!("The model mokc is not available, did you mean {}?", MOCK_MODEL)
+        );
+
+        let models = ["gpt-4o", "gpt-4.1", "claude-sonnet-4", "o3-mini"].map(String::from);
+        assert_eq!(closest_models("gpt4o", &models), ["gpt-4o"]);
+        assert_eq!(closest_models("sonnet", &models), ["claude-sonnet-4"]);
+        assert!(closest_models("llama-3", &models).is_empty());
+    }
+
+    #[test]
+    fn render_models_table() {
+        let models = [
+            ModelInfo {
+                id: "gpt-4o".to_string(),
+                name: "GPT-4o".to_string(),
+                vendor: "Azure OpenAI".to_string(),
+                context_window: Some(128000),
+            },
+            ModelInfo {
+                id: "claude-sonnet-4".to_string(),
+                name: "Claude Sonnet 4".to_string(),
+                vendor: "Anthropic".to_string(),
+                context_window: None,
+            },
+        ];
+
+        assert_eq!(
+            models_table(&models),
+            "gpt-4o           GPT-4o           Azure OpenAI  128000\nclaude-sonnet-4  Claude Sonnet 4  Anthropic     -\n"
+        );
+        assert_eq!(models_table(&[]), "");
+    }
+
+    #[test]
+    fn parse_slash_commands() {
+        assert_eq!(
+            SlashCommand::parse("/attach src/main.rs:10-40 Cargo.toml\n"),
+            Some(SlashCommand::Attach(vec![
+                "src/main.rs:10-40".to_string(),
+                "Cargo.toml".to_string()
+            ]))
+        );
+        assert_eq!(
+            SlashCommand::parse(" /detach src/main.rs"),
+            Some(SlashCommand::Detach(vec!["src/main.rs".to_string()]))
+        );
+        assert_eq!(SlashCommand::parse("/files"), Some(SlashCommand::Files));
+        assert_eq!(SlashCommand::parse("/attachments"), None);
+        assert_eq!(SlashCommand::parse("explain /files"), None);
+    }
+
+    #[test]
+    fn render_footer() {
+        let usage = Usage {
+            prompt_tokens: 120,
+            completion_tokens: 45,
+            total_tokens: 165,
+        };
+        assert_eq!(
+            footer("gpt-4.1", Some(usage), Some("stop")),
+            "model: gpt-4.1 | temperature: 0.1 | tokens: 120 prompt + 45 completion = 165 | finish: stop"
+        );
+        assert_eq!(
+            footer("mock", None, None),
+            "model: mock | temperature: 0.1 | tokens: unknown | finish: unknown"
+        );
+    }
+
+    #[test]
+    fn format_idle_timeout() {
+        assert_eq!(format_duration(Duration::from_secs(60)), "1 minute");
+        assert_eq!(format_duration(Duration::from_secs(600)), "10 minutes");
+        assert_eq!(format_duration(Duration::from_secs(90)), "90 seconds");
+        assert_eq!(format_duration(Duration::from_secs(1)), "1 second");
+    }
+
+    #[test]
+    fn quote_prompt() {
+        assert_eq!(quote("explain this"), "> explain this");
+        assert_eq!(quote("first\n\nsecond"), "> first\n>\n> second");
+    }
+
+    #[tokio::test]
+    async fn skip_empty_connections() {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.expect("bind the listener");
+        let addr = tcp.local_addr().expect("listener address");
+
+        let client = tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+
+            // A probe that closes immediately, one that resets and another that only sends whitespace
+            drop(tokio::net::TcpStream::connect(addr).await.expect("connect"));
+            let reset = tokio::net::TcpStream::connect(addr).await.expect("connect");
+            reset.set_linger(Some(Duration::ZERO)).expect("set linger");
+            drop(reset);
+            let mut probe = tokio::net::TcpStream::connect(addr).await.expect("connect");
+            probe.write_all(b" \n").await.expect("write");
+            drop(probe);
+
+            let mut stream = tokio::net::TcpStream::connect(addr).await.expect("connect");
+            stream.write_all(b"main.rs@explain this").await.expect("write");
+        });
+
+        let request = accept_request(&tcp).await.expect("receive a request");
+        client.await.expect("client finished");
+
+        assert_eq!(request.prompt, "explain this");
+        assert_eq!(request.files, Some(vec!["main.rs".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn length_prefixed_request() {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.expect("bind the listener");
+        let addr = tcp.local_addr().expect("listener address");
+
+        let client = tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+
+            let payload = br#"{"prompt":"explain @ this","files":["a.rs","b.rs"]}"#;
+            let mut stream = tokio::net::TcpStream::connect(addr).await.expect("connect");
+            stream.write_u32(payload.len() as u32).await.expect("write the length");
+            // Split the payload in several segments
+            for part in payload.chunks(8) {
+                stream.write_all(part).await.expect("write the payload");
+                stream.flush().await.expect("flush");
+            }
+        });
+
+        let request = accept_request(&tcp).await.expect("receive a request");
+        client.await.expect("client finished");
+
+        assert_eq!(request.prompt, "explain @ this");
+        assert_eq!(request.files, Some(vec!["a.rs".to_string(), "b.rs".to_string()]));
+        assert_eq!(request.role, None);
+    }
+
+    #[tokio::test]
+    async fn skip_malformed_frames() {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.expect("bind the listener");
+        let addr = tcp.local_addr().expect("listener address");
+
+        let client = tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+
+            let send = |length: u32, payload: &'static [u8]| async move {
+                let mut stream = tokio::net::TcpStream::connect(addr).await.expect("connect");
+                stream.write_u32(length).await.expect("write the length");
+                stream.write_all(payload).await.expect("write the payload");
+            };
+
+            // The connection closes before the announced length, then a payload that is not JSON
+            send(100, b"{\"prompt\"").await;
+            send(8, b"not json").await;
+            let payload = br#"{"prompt":"explain this"}"#;
+            send(payload.len() as u32, payload).await;
+        });
+
+        let request = accept_request(&tcp).await.expect("receive a request");
+        client.await.expect("client finished");
+
+        assert_eq!(request.prompt, "explain this");
+    }
+
+    #[test]
+    fn frame_size_boundary() {
+        // The requests are detected as framed by the zero byte that starts their length
+        assert_eq!(MAX_FRAME_SIZE.to_be_bytes()[0], 0);
+        assert_ne!((MAX_FRAME_SIZE + 1).to_be_bytes()[0], 0);
+    }
+
+    #[test]
+    fn request_role() {
+        let request: RequestProtocol =
+            serde_json::from_str(r#"{"prompt":"answer in Spanish","role":"system"}"#).expect("parse the request");
+        assert_eq!(request.role, Some(Role::System));
+
+        assert!(serde_json::from_str::<RequestProtocol>(r#"{"prompt":"hi","role":"tool"}"#).is_err());
+    }
+
+    #[test]
+    fn hash_response_content() {
+        assert_eq!(
+            content_hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn save_unique_responses() {
+        let temp = tempdir().expect("create temp dir");
+        let dir = temp.path().join("responses");
+        let now = SystemTime::now();
+
+        let first = save_response(&dir, "first", now).expect("save the first response");
+        let second = save_response(&dir, "second", now).expect("save the second response");
+
+        assert_ne!(first, second);
+        assert_eq!(std::fs::read_to_string(first).expect("read the first"), "first");
+        assert_eq!(std::fs::read_to_string(second).expect("read the second"), "second");
+    }
 
     // Test the usage of the `*.rs` pattern in the files argument.
     #[test]
@@ -341,14 +1753,14 @@ mod tests {
         let dir = temp.path();
         let mut expected_files = vec![];
 
-        for d in vec!["subdir1", "subdir2"].iter() {
+        for d in ["subdir1", "subdir2"].iter() {
             let subdir = dir.join(d);
             fs::create_dir_all(&subdir).expect("create dir");
-            for f in vec!["file1.rs", "file2.rs"].iter() {
+            for f in ["file1.rs", "file2.rs"].iter() {
                 let file = subdir.join(f);
                 fs::File::create(&file).expect("create file");
 
-                fs::File::create(subdir.join(format!("{}_ignored.go", f.to_string()))).expect("create file");
+                fs::File::create(subdir.join(format!("{}_ignored.go", f))).expect("create file");
                 expected_files.push(file);
             }
             fs::File::create(subdir.join("should_be_ignored.py")).expect("create file");
@@ -368,9 +1780,7 @@ mod tests {
         cli.files = Some(vec!["*.rs".into()]);
         cli.exclude = Some(vec!["ignored.rs".into()]);
 
-        let result =
-            CommandHandler::expand_files_from_dir(&dir.to_path_buf(), cli.files.as_ref(), cli.exclude.as_ref())
-                .unwrap();
+        let result = CommandHandler::expand_files_from_dir(dir, cli.files.as_ref(), cli.exclude.as_ref()).unwrap();
         let mut expected = expected_files
             .iter()
             .map(|f| f.to_str().expect("convert to str").to_string())
@@ -382,4 +1792,32 @@ mod tests {
 
         assert_eq!(result, expected)
     }
+
+    #[test]
+    fn resolve_files_in_root() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let files = vec![
+            "src/lib.rs:1-3".to_string(),
+            "https://example.com/a.rs".to_string(),
+            "/etc/hosts".to_string(),
+        ];
+
+        let result = CommandHandler::expand_files_from_dir(dir.path(), Some(&files), None)
+            .expect("expand files")
+            .expect("files");
+        assert_eq!(
+            result,
+            [
+                dir.path().join("src/lib.rs:1-3").display().to_string(),
+                files[1].clone(),
+                files[2].clone()
+            ]
+        );
+
+        let cwd = std::env::current_dir().expect("current dir");
+        let result = CommandHandler::expand_files_from_dir(&cwd, Some(&files), None)
+            .expect("expand files")
+            .expect("files");
+        assert_eq!(result, files);
+    }
 }
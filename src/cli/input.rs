@@ -0,0 +1,127 @@
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use tracing::debug;
+#[cfg(feature = "readline")]
+use tracing::warn;
+
+/// The source of the lines when they are not read from stdin
+type Source = Arc<Mutex<Box<dyn BufRead + Send>>>;
+
+/// Read lines typed by the user in interactive mode. When stdin is a TTY and the `readline`
+/// feature is enabled, it provides line editing and a history persisted in `history_path`;
+/// otherwise it falls back to a plain `read_line`.
+pub struct LineReader {
+    #[cfg(feature = "readline")]
+    editor: Option<Arc<Mutex<rustyline::DefaultEditor>>>,
+    #[cfg(feature = "readline")]
+    history_path: Option<PathBuf>,
+    /// Lines are read from it instead of stdin, if it is set
+    source: Option<Source>,
+}
+
+impl LineReader {
+    /// Create a new reader, loading the history from `history_path` if it exists
+    pub fn new(history_path: Option<PathBuf>) -> Self {
+        #[cfg(feature = "readline")]
+        {
+            let editor = if atty::is(atty::Stream::Stdin) {
+                match rustyline::DefaultEditor::new() {
+                    Ok(mut editor) => {
+                        if let Some(path) = &history_path
+                            && path.exists()
+                        {
+                            editor
+                                .load_history(path)
+                                .unwrap_or_else(|e| warn!(%e, "History cannot be loaded"));
+                        }
+                        Some(Arc::new(Mutex::new(editor)))
+                    }
+                    Err(e) => {
+                        warn!(%e, "Readline is not available, using plain input");
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            Self {
+                editor,
+                history_path,
+                source: None,
+            }
+        }
+
+        #[cfg(not(feature = "readline"))]
+        {
+            let _ = history_path;
+            Self { source: None }
+        }
+    }
+
+    /// Read the lines from `reader` instead of stdin, without line editing nor history
+    pub fn from_reader(reader: impl BufRead + Send + 'static) -> Self {
+        Self {
+            #[cfg(feature = "readline")]
+            editor: None,
+            #[cfg(feature = "readline")]
+            history_path: None,
+            source: Some(Arc::new(Mutex::new(Box::new(reader)))),
+        }
+    }
+
+    /// Print the `prompt` and read a line from stdin. An empty string means EOF.
+    ///
+    /// The line is read in a blocking thread so the caller can race the read against other
+    /// futures, e.g. a timer.
+    pub async fn read_line(&self, prompt: &str) -> std::io::Result<String> {
+        #[cfg(feature = "readline")]
+        if let Some(editor) = &self.editor {
+            let editor = editor.clone();
+            let history_path = self.history_path.clone();
+            let prompt = prompt.to_string();
+
+            return tokio::task::spawn_blocking(move || {
+                let mut editor = editor.lock().map_err(|e| std::io::Error::other(e.to_string()))?;
+                match editor.readline(&prompt) {
+                    Ok(line) => {
+                        if !line.trim().is_empty() {
+                            editor.add_history_entry(line.as_str()).map_err(std::io::Error::other)?;
+                            if let Some(path) = &history_path {
+                                editor
+                                    .save_history(path)
+                                    .unwrap_or_else(|e| warn!(%e, "History cannot be saved"));
+                            }
+                        }
+                        Ok(line + "\n")
+                    }
+                    // Behave as the user typed `exit`, the session is saved before leaving
+                    Err(rustyline::error::ReadlineError::Interrupted) => Ok("exit\n".to_string()),
+                    Err(rustyline::error::ReadlineError::Eof) => Ok(String::new()),
+                    Err(e) => Err(std::io::Error::other(e)),
+                }
+            })
+            .await?;
+        }
+
+        debug!("Reading from plain stdin");
+        print!("{}", prompt);
+        std::io::stdout().flush()?;
+
+        let source = self.source.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut read_str = String::new();
+            match source {
+                Some(source) => source
+                    .lock()
+                    .map_err(|e| std::io::Error::other(e.to_string()))?
+                    .read_line(&mut read_str),
+                None => std::io::stdin().read_line(&mut read_str),
+            }
+            .map(|_| read_str)
+        })
+        .await?
+    }
+}
@@ -1,2 +1,5 @@
 pub mod commands;
 pub mod handlers;
+pub mod input;
+pub mod signal;
+pub mod style;
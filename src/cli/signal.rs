@@ -0,0 +1,29 @@
+/// Listen for the termination request of a process supervisor (`SIGTERM`). On platforms without
+/// Unix signals, it never fires.
+pub struct ShutdownSignal {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl ShutdownSignal {
+    /// Start listening for the signal
+    pub fn new() -> std::io::Result<Self> {
+        #[cfg(unix)]
+        {
+            let signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+            Ok(Self { signal })
+        }
+
+        #[cfg(not(unix))]
+        Ok(Self {})
+    }
+
+    /// Wait until the signal is received
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
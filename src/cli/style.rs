@@ -0,0 +1,116 @@
+use clap::ValueEnum;
+
+use crate::chat::Role;
+
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+const RULE: &str = "────";
+
+/// When the output is colored
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum ColorChoice {
+    /// Only in a terminal, unless `NO_COLOR` is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether the colored output is enabled for stdout, all the colored output must check it
+    pub fn enabled(&self) -> bool {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        self.resolve(no_color, atty::is(atty::Stream::Stdout))
+    }
+
+    fn resolve(&self, no_color: bool, is_tty: bool) -> bool {
+        match self {
+            Self::Auto => is_tty && !no_color,
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+/// How the boundaries between the turns of an interactive transcript are rendered
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum SeparatorStyle {
+    /// A dim rule with a colored role label
+    #[default]
+    Rule,
+    /// The same rule without colors
+    Plain,
+    /// Do not print separators
+    None,
+}
+
+impl SeparatorStyle {
+    /// The style printed to stdout with the `color` choice. The rule loses its colors when they
+    /// are disabled, and a pipe only gets separators when the colors are forced with `always`.
+    pub fn with_color(self, color: ColorChoice) -> Self {
+        self.resolve(color, color.enabled(), atty::is(atty::Stream::Stdout))
+    }
+
+    fn resolve(self, color: ColorChoice, colored: bool, is_tty: bool) -> Self {
+        match self {
+            _ if !is_tty && color != ColorChoice::Always => Self::None,
+            Self::Rule if !colored => Self::Plain,
+            style => style,
+        }
+    }
+
+    /// Render the separator placed before a message of `role`, returns `None` if the style
+    /// is disabled.
+    pub fn render(&self, role: &Role) -> Option<String> {
+        let (label, color) = match role {
+            Role::User => ("you", "\x1b[1;36m"),
+            Role::Assistant => ("assistant", "\x1b[1;32m"),
+            Role::System => ("system", "\x1b[1;33m"),
+        };
+
+        match self {
+            Self::Rule => Some(format!("{DIM}{RULE}{RESET} {color}{label}{RESET} {DIM}{RULE}{RESET}")),
+            Self::Plain => Some(format!("{RULE} {label} {RULE}")),
+            Self::None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_separators() {
+        assert_eq!(
+            SeparatorStyle::Plain.render(&Role::Assistant),
+            Some("──── assistant ────".to_string())
+        );
+        assert_eq!(SeparatorStyle::None.render(&Role::User), None);
+
+        let rule = SeparatorStyle::Rule.render(&Role::User).expect("rule separator");
+        assert!(rule.contains("you"));
+        assert!(rule.starts_with(DIM));
+    }
+
+    #[test]
+    fn resolve_separators() {
+        use ColorChoice::*;
+
+        assert_eq!(SeparatorStyle::Rule.resolve(Auto, true, true), SeparatorStyle::Rule);
+        assert_eq!(SeparatorStyle::Rule.resolve(Never, false, true), SeparatorStyle::Plain);
+        assert_eq!(SeparatorStyle::Rule.resolve(Auto, false, false), SeparatorStyle::None);
+        assert_eq!(SeparatorStyle::Plain.resolve(Never, false, false), SeparatorStyle::None);
+        assert_eq!(SeparatorStyle::Rule.resolve(Always, true, false), SeparatorStyle::Rule);
+        assert_eq!(SeparatorStyle::None.resolve(Always, true, true), SeparatorStyle::None);
+    }
+
+    #[test]
+    fn resolve_colors() {
+        assert!(ColorChoice::Auto.resolve(false, true));
+        assert!(!ColorChoice::Auto.resolve(true, true));
+        assert!(!ColorChoice::Auto.resolve(false, false));
+        assert!(ColorChoice::Always.resolve(true, false));
+        assert!(!ColorChoice::Never.resolve(false, true));
+    }
+}
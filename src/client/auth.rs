@@ -1,33 +1,179 @@
 use anyhow::anyhow;
-use std::{fs::File, io::Read};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
 
 use serde::Deserialize;
 use tracing::{debug, error, trace};
 
+use super::login::COPILOT_CLIENT_ID;
+
+/// Environment variable with the token, it takes precedence over the config file
+pub const TOKEN_ENV: &str = "GH_COPILOT_TOKEN";
+
+/// Host of the accounts used for Copilot
+const GITHUB_HOST: &str = "github.com";
+
+/// Config files of Copilot in order of preference: `hosts.json` of the newer installs, then `apps.json`
+const CONFIG_FILES: [&str; 2] = ["hosts.json", "apps.json"];
+
+/// An account of a Copilot config file
+#[derive(Debug, Deserialize)]
+struct ConfigEntry {
+    oauth_token: Option<String>,
+}
+
+/// A Copilot config file, the accounts are keyed by host, e.g. `github.com` in `hosts.json`,
+/// or by host and app, e.g. `github.com:Iv1.b507a08c87ecfe98` in `apps.json`
+type ConfigFile = BTreeMap<String, ConfigEntry>;
+
+/// Where the token was read from
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenSource {
+    /// The [`TOKEN_ENV`] environment variable
+    Env,
+    /// A Copilot config file
+    File(PathBuf),
+}
+
+impl Display for TokenSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Env => write!(f, "the {} environment variable", TOKEN_ENV),
+            Self::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
 /// Handle all related authorizations features of Copilot
-#[derive(Debug, Deserialize, Default, Clone)]
+#[derive(Debug, Default, Clone)]
 pub struct CopilotAuth {
     oauth_token: Option<String>,
+    source: Option<TokenSource>,
 }
 
 impl CopilotAuth {
-    /// Try to get the main token and return an instance.
-    /// If the token cannot be found, it will be None.
+    /// Try to get the main token and return an instance, from the [`TOKEN_ENV`] environment
+    /// variable or else the config file. If the token cannot be found, it will be None.
     pub fn new() -> Self {
-        let mut auth = Self { oauth_token: None };
-        auth.get_token_from_file().unwrap();
+        if let Some(auth) = std::env::var(TOKEN_ENV).ok().and_then(Self::from_token) {
+            debug!("Token found in {}", TOKEN_ENV);
+            return auth;
+        }
+
+        let mut auth = Self::default();
+        let Some(copilot_file) = Self::default_path() else {
+            error!("The home directory cannot be resolved");
+            return auth;
+        };
+        if let Err(e) = auth.get_token_from_file(&copilot_file) {
+            error!(%e, ?copilot_file, "Token cannot be read");
+        }
 
         auth
     }
 
+    /// Read the token from the Copilot config file in `path`, instead of the default location
+    pub fn from_path(path: &str) -> anyhow::Result<Self> {
+        let path = Path::new(path);
+        if !path.is_file() {
+            return Err(anyhow!("The token file {} does not exist", path.display()));
+        }
+
+        let mut auth = Self::default();
+        auth.get_token_from_file(path)
+            .map_err(|e| anyhow!("Cannot read the token from {}: {}", path.display(), e))?;
+
+        Ok(auth)
+    }
+
+    /// Use the `token` of the environment, if it is not blank
+    fn from_token(token: String) -> Option<Self> {
+        let token = token.trim();
+        (!token.is_empty()).then(|| Self {
+            oauth_token: Some(token.to_string()),
+            source: Some(TokenSource::Env),
+        })
+    }
+
+    /// The config file of Copilot in `~/.config/github-copilot`
+    fn default_path() -> Option<PathBuf> {
+        dirs::home_dir().map(|home| Self::config_file(&home.join(".config").join("github-copilot")))
+    }
+
+    /// The preferred config file of `dir` that exists, or the last one if there is none
+    fn config_file(dir: &Path) -> PathBuf {
+        CONFIG_FILES
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.is_file())
+            .unwrap_or_else(|| dir.join(CONFIG_FILES[CONFIG_FILES.len() - 1]))
+    }
+
+    /// The token of the GitHub account in `config`, the first one with a token otherwise
+    fn token_from_config(config: ConfigFile) -> Option<String> {
+        let is_github = |host: &str| host == GITHUB_HOST || host.starts_with(&format!("{}:", GITHUB_HOST));
+        let (github, others): (Vec<_>, Vec<_>) = config.into_iter().partition(|(host, _)| is_github(host));
+
+        github
+            .into_iter()
+            .chain(others)
+            .find_map(|(_, entry)| entry.oauth_token.filter(|token| !token.is_empty()))
+    }
+
+    /// Save `token` as the GitHub account of the Copilot config file and return its path
+    pub fn store_token(token: &str) -> anyhow::Result<PathBuf> {
+        let path = Self::default_path().ok_or_else(|| anyhow!("The home directory cannot be resolved"))?;
+        Self::write_token(&path, token)?;
+
+        Ok(path)
+    }
+
+    /// Write `token` to the config file in `path`, keeping its other accounts
+    fn write_token(path: &Path, token: &str) -> anyhow::Result<()> {
+        let mut config = match std::fs::read_to_string(path) {
+            Ok(content) if !content.trim().is_empty() => {
+                serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&content)
+                    .map_err(|e| anyhow!("Invalid config file {}: {}", path.display(), e))?
+            }
+            _ => serde_json::Map::new(),
+        };
+
+        // `hosts.json` is keyed by host and `apps.json` by host and app
+        let key = match path.file_name().and_then(|name| name.to_str()) {
+            Some("hosts.json") => GITHUB_HOST.to_string(),
+            _ => format!("{}:{}", GITHUB_HOST, COPILOT_CLIENT_ID),
+        };
+        config.insert(
+            key,
+            serde_json::json!({"oauth_token": token, "githubAppId": COPILOT_CLIENT_ID}),
+        );
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(&config)?)?;
+        debug!(?path, "Token stored");
+
+        Ok(())
+    }
+
     /// Return an [`Option`], maybe with the token
     pub fn get_token(&self) -> Option<&str> {
         self.oauth_token.as_deref()
     }
 
-    /// Retrieve the Copilot token from known directories
-    /// By default, it looks in `~/.config/github-copilot/apps.json`
-    fn get_token_from_file(&mut self) -> anyhow::Result<Option<&str>> {
+    /// Where the token was read from, if it was found
+    pub fn source(&self) -> Option<&TokenSource> {
+        self.source.as_ref()
+    }
+
+    /// Retrieve the Copilot token from the config file in `copilot_file`
+    fn get_token_from_file(&mut self, copilot_file: &Path) -> anyhow::Result<Option<&str>> {
         // Return an existent token if exists
         if self.oauth_token.is_some() {
             return Ok(self.oauth_token.as_deref());
@@ -35,32 +181,107 @@ impl CopilotAuth {
 
         debug!("Token not found; searching for it in the file.");
 
-        let config_path = dirs::home_dir().expect("path is resolved");
-        let copilot_file = config_path.join(".config").join("github-copilot").join("apps.json");
-
         debug!(?copilot_file, "Looking for token");
 
         let mut file = File::open(copilot_file)?;
         let mut file_str = String::new();
         let n = file.read_to_string(&mut file_str)?;
         if n == 0 {
-            error!("Emptyt config file");
+            error!("Empty config file");
             return Err(anyhow!("Empty config file"));
         }
 
         trace!(%file_str, "File found");
 
-        let clean_str = match file_str[14..].split_once(":") {
-            Some(substr) => substr.1.trim()[..substr.1.trim().len() - 1].to_string(),
-            None => file_str.clone(),
-        };
+        let config = serde_json::from_str::<ConfigFile>(&file_str)?;
+        let token = Self::token_from_config(config).ok_or_else(|| anyhow!("No token found in the config file"))?;
+        trace!(?token, "Token found");
+
+        self.oauth_token = Some(token);
+        self.source = Some(TokenSource::File(copilot_file.to_path_buf()));
+        Ok(self.oauth_token.as_deref())
+    }
+}
 
-        trace!(%clean_str);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let copilot_auth = serde_json::from_str::<CopilotAuth>(&clean_str)?;
-        trace!(?copilot_auth.oauth_token, "Token found");
+    #[test]
+    fn token_from_path() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let path = dir.path().join("apps.json");
+        std::fs::write(
+            &path,
+            r#"{"github.com:Iv1.b507a08c87ecfe98": {"user": "octocat", "oauth_token": "gho_token", "githubAppId": "Iv1.b507a08c87ecfe98"}}"#,
+        )
+        .expect("write the config");
 
-        self.oauth_token = copilot_auth.oauth_token;
-        Ok(self.oauth_token.as_deref())
+        let auth = CopilotAuth::from_path(path.to_str().expect("utf-8 path")).expect("read the token");
+        assert_eq!(auth.get_token(), Some("gho_token"));
+        assert_eq!(auth.source(), Some(&TokenSource::File(path.clone())));
+
+        let missing = dir.path().join("missing.json");
+        let error = CopilotAuth::from_path(missing.to_str().expect("utf-8 path")).expect_err("missing file");
+        assert!(error.to_string().contains("does not exist"));
+    }
+
+    #[test]
+    fn token_from_hosts_file() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        std::fs::write(
+            dir.path().join("apps.json"),
+            r#"{"github.com:Iv1.b507a08c87ecfe98": {"oauth_token": "gho_apps"}}"#,
+        )
+        .expect("write apps.json");
+        assert_eq!(CopilotAuth::config_file(dir.path()), dir.path().join("apps.json"));
+
+        // The newer config file is preferred
+        let hosts = dir.path().join("hosts.json");
+        std::fs::write(
+            &hosts,
+            r#"{"enterprise.example.com": {"oauth_token": "ghu_enterprise"}, "github.com": {"user": "octocat", "oauth_token": "ghu_hosts"}}"#,
+        )
+        .expect("write hosts.json");
+        assert_eq!(CopilotAuth::config_file(dir.path()), hosts);
+
+        let auth = CopilotAuth::from_path(hosts.to_str().expect("utf-8 path")).expect("read the token");
+        assert_eq!(auth.get_token(), Some("ghu_hosts"));
+
+        std::fs::write(&hosts, r#"{"github.com": {"user": "octocat"}}"#).expect("write hosts.json");
+        let error = CopilotAuth::from_path(hosts.to_str().expect("utf-8 path")).expect_err("no token");
+        assert!(error.to_string().contains("No token found"));
+    }
+
+    #[test]
+    fn store_token() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let path = dir.path().join("github-copilot").join("apps.json");
+
+        CopilotAuth::write_token(&path, "ghu_first").expect("write a new file");
+        CopilotAuth::write_token(&path, "ghu_login").expect("replace the token");
+        let auth = CopilotAuth::from_path(path.to_str().expect("utf-8 path")).expect("read the token");
+        assert_eq!(auth.get_token(), Some("ghu_login"));
+
+        let hosts = dir.path().join("hosts.json");
+        std::fs::write(
+            &hosts,
+            r#"{"enterprise.example.com": {"oauth_token": "ghu_enterprise"}}"#,
+        )
+        .expect("write hosts.json");
+        CopilotAuth::write_token(&hosts, "ghu_login").expect("add the account");
+        let config: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&hosts).expect("read hosts.json")).expect("parse");
+        assert_eq!(config["github.com"]["oauth_token"], "ghu_login");
+        assert_eq!(config["enterprise.example.com"]["oauth_token"], "ghu_enterprise");
+    }
+
+    #[test]
+    fn token_from_env() {
+        let auth = CopilotAuth::from_token(" gho_env\n".to_string()).expect("token");
+        assert_eq!(auth.get_token(), Some("gho_env"));
+        assert_eq!(auth.source(), Some(&TokenSource::Env));
+
+        assert!(CopilotAuth::from_token("  ".to_string()).is_none());
     }
 }
@@ -0,0 +1,126 @@
+use std::cell::RefCell;
+
+use anyhow::anyhow;
+use futures_util::{Stream, StreamExt};
+
+use crate::chat::Message;
+
+use super::{
+    connector::CopilotClient,
+    mock::MockProvider,
+    provider::{Completion, ModelInfo, Provider, RequestOptions, Vision},
+};
+
+/// The provider selected with `--provider`, the requests are answered by Copilot or by the
+/// canned responses of the mock
+#[derive(Debug, Clone)]
+pub enum Backend {
+    Copilot(Box<CopilotClient>),
+    Mock(MockProvider),
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self::Copilot(Box::default())
+    }
+}
+
+impl Backend {
+    /// The Copilot client, the account commands have no meaning for the mock
+    pub fn copilot(&self) -> anyhow::Result<&CopilotClient> {
+        match self {
+            Self::Copilot(client) => Ok(client),
+            Self::Mock(_) => Err(anyhow!("This command is not available with --provider mock")),
+        }
+    }
+
+    /// The models of the provider as it describes them, with all their capabilities
+    pub async fn models_json(&self) -> anyhow::Result<serde_json::Value> {
+        match self {
+            Self::Copilot(client) => client.models_json().await,
+            Self::Mock(mock) => Ok(mock.models_json()),
+        }
+    }
+}
+
+impl Provider for Backend {
+    async fn request(
+        &self,
+        model: &str,
+        messages: &RefCell<Vec<Message>>,
+        options: &RequestOptions,
+    ) -> anyhow::Result<impl Stream<Item = reqwest::Result<bytes::Bytes>>> {
+        Ok(match self {
+            Self::Copilot(client) => client.request(model, messages, options).await?.left_stream(),
+            Self::Mock(mock) => mock.request(model, messages, options).await?.right_stream(),
+        })
+    }
+
+    async fn complete(
+        &self,
+        model: &str,
+        messages: &RefCell<Vec<Message>>,
+        options: &RequestOptions,
+    ) -> anyhow::Result<Completion> {
+        match self {
+            Self::Copilot(client) => client.complete(model, messages, options).await,
+            Self::Mock(mock) => mock.complete(model, messages, options).await,
+        }
+    }
+
+    async fn get_models(&self) -> anyhow::Result<Vec<ModelInfo>> {
+        match self {
+            Self::Copilot(client) => client.get_models().await,
+            Self::Mock(mock) => mock.get_models().await,
+        }
+    }
+
+    async fn max_output_tokens(&self, model: &str) -> anyhow::Result<Option<i32>> {
+        match self {
+            Self::Copilot(client) => client.max_output_tokens(model).await,
+            Self::Mock(mock) => mock.max_output_tokens(model).await,
+        }
+    }
+
+    async fn supports_candidates(&self, model: &str) -> anyhow::Result<Option<bool>> {
+        match self {
+            Self::Copilot(client) => client.supports_candidates(model).await,
+            Self::Mock(mock) => mock.supports_candidates(model).await,
+        }
+    }
+
+    async fn tokenizer(&self, model: &str) -> anyhow::Result<Option<String>> {
+        match self {
+            Self::Copilot(client) => client.tokenizer(model).await,
+            Self::Mock(mock) => mock.tokenizer(model).await,
+        }
+    }
+
+    async fn vision(&self, model: &str) -> anyhow::Result<Option<Vision>> {
+        match self {
+            Self::Copilot(client) => client.vision(model).await,
+            Self::Mock(mock) => mock.vision(model).await,
+        }
+    }
+
+    async fn structured_outputs(&self, model: &str) -> anyhow::Result<Option<bool>> {
+        match self {
+            Self::Copilot(client) => client.structured_outputs(model).await,
+            Self::Mock(mock) => mock.structured_outputs(model).await,
+        }
+    }
+
+    fn default_model(&self) -> &str {
+        match self {
+            Self::Copilot(client) => client.default_model(),
+            Self::Mock(mock) => mock.default_model(),
+        }
+    }
+
+    fn http_client(&self) -> reqwest::Client {
+        match self {
+            Self::Copilot(client) => client.http_client(),
+            Self::Mock(mock) => mock.http_client(),
+        }
+    }
+}
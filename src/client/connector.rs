@@ -1,13 +1,27 @@
-use std::cell::RefCell;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use futures_util::Stream;
+use reqwest::{
+    StatusCode,
+    header::{HeaderName, HeaderValue, RETRY_AFTER},
+};
 
-use crate::chat::Message;
+use crate::chat::{Message, Role, Usage};
+use crate::tools::image::Image;
 use serde::{Deserialize, Serialize};
 
-use super::{auth::CopilotAuth, provider::Provider};
-use anyhow::anyhow;
-use tracing::{debug, error, info, trace};
+use super::{
+    auth::{CopilotAuth, TOKEN_ENV},
+    errors::ProviderError,
+    login::{self, DeviceCode},
+    provider::{Completion, DEFAULT_MAX_TOKENS, ModelInfo, Provider, RequestOptions, TEMPERATURE, Vision},
+};
+use tracing::{Instrument, debug, debug_span, error, info, instrument, trace, warn};
 
 /// # Endpoints
 /// Endpoint where the auth token is retrieved for use it in completions
@@ -18,6 +32,8 @@ static COMPLETION_URL: &str = "https://api.githubcopilot.com/chat/completions";
 static USER_AGENT: &str = "curl/8.7.1";
 /// Endpoint for retrieving the availables models
 static MODELS: &str = "https://api.githubcopilot.com/models";
+/// Endpoint that returns the GitHub account of the token
+static USER_URL: &str = "https://api.github.com/user";
 
 // /// # Mock endpoints for testing
 // /// Endpoint where the auth token is retrieved for use it in completions
@@ -29,29 +45,159 @@ static MODELS: &str = "https://api.githubcopilot.com/models";
 // /// Endpoint for retrieving the availables models
 // static MODELS: &str = "http://localhost:9090/models";
 
+/// The endpoints of the GitHub and Copilot APIs, the public ones by default. GitHub Enterprise
+/// and Copilot-compatible gateways have their own hosts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopilotEndpoints {
+    /// Endpoint where the auth token is exchanged for the token of the completions
+    pub headers: String,
+    /// Endpoint where Copilot returns a response
+    pub completion: String,
+    /// Endpoint for retrieving the available models
+    pub models: String,
+    /// Endpoint that returns the GitHub account of the token
+    pub user: String,
+}
+
+impl Default for CopilotEndpoints {
+    fn default() -> Self {
+        Self {
+            headers: HEADERS_URL.to_string(),
+            completion: COMPLETION_URL.to_string(),
+            models: MODELS.to_string(),
+            user: USER_URL.to_string(),
+        }
+    }
+}
+
+impl CopilotEndpoints {
+    /// Use the Copilot API in `base` for the completions and models, e.g. `https://copilot-api.example.ghe.com`
+    pub fn with_api_base(mut self, base: &str) -> Self {
+        let base = base.trim_end_matches('/');
+        self.completion = format!("{}/chat/completions", base);
+        self.models = format!("{}/models", base);
+        self
+    }
+
+    /// Use the GitHub API in `base` for the token and the account, e.g. `https://api.example.ghe.com`
+    pub fn with_github_api_base(mut self, base: &str) -> Self {
+        let base = base.trim_end_matches('/');
+        self.headers = format!("{}/copilot_internal/v2/token", base);
+        self.user = format!("{}/user", base);
+        self
+    }
+}
+
+/// Default number of times a rate-limited request is retried
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// Default header of the key that identifies a completion request across its retries
+pub const DEFAULT_IDEMPOTENCY_HEADER: &str = "idempotency-key";
+
+/// A rate-limited request that is going to be retried
+#[derive(Debug)]
+pub struct RetryEvent {
+    /// Number of the retry, starting at 1
+    pub attempt: u32,
+    pub max_attempts: u32,
+    /// Time to wait before retrying
+    pub delay: Duration,
+}
+
+/// Identification of the editor sent with the Copilot requests, the API can reject some combinations
+#[derive(Debug, Clone)]
+pub struct EditorHeaders {
+    /// `Editor-Version` header
+    pub editor_version: String,
+    /// `Editor-Plugin-Version` header
+    pub plugin_version: String,
+    /// `Copilot-Integration-Id` header
+    pub integration_id: String,
+}
+
+impl Default for EditorHeaders {
+    fn default() -> Self {
+        Self {
+            editor_version: "Neovim/0.11.1".to_string(),
+            plugin_version: "copilot-chat".to_string(),
+            integration_id: "vscode-chat".to_string(),
+        }
+    }
+}
+
 /// Main Copilot client
-#[derive(Default, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct CopilotClient {
     auth: CopilotAuth,
     client: reqwest::Client,
+    max_retries: u32,
+    on_retry: Option<fn(&RetryEvent)>,
+    editor: EditorHeaders,
+    idempotency_header: HeaderName,
+    /// The completion token is reused until it is about to expire, shared by the clones
+    token_cache: Arc<Mutex<Option<CachedToken>>>,
+    /// The models are fetched once, the capabilities of a model are read several times per request
+    models_cache: Arc<Mutex<Option<Vec<ModelsResponse>>>>,
+    endpoints: CopilotEndpoints,
+}
+
+impl Default for CopilotClient {
+    fn default() -> Self {
+        Self::new(CopilotAuth::default())
+    }
+}
+
+/// The account that authenticates the requests
+#[derive(Debug)]
+pub struct Identity {
+    pub login: String,
+    pub name: Option<String>,
+    /// Copilot plan (sku) of the account
+    pub plan: Option<String>,
+    /// Unix time when the Copilot token expires
+    pub expires_at: Option<u64>,
 }
 
-/// Struct used for retrieving the token from `HEADERS_URL`
+#[derive(Deserialize, Debug)]
+struct UserResponse {
+    login: String,
+    name: Option<String>,
+}
+
+/// Struct used for retrieving the token from the headers endpoint
 #[derive(Deserialize, Debug)]
 struct HeadersResponse {
     token: String,
+    /// Unix time when the token expires
+    expires_at: Option<u64>,
 }
 
-#[derive(Deserialize, Debug)]
-#[allow(dead_code)]
+/// Seconds before the expiration when a cached token is already refreshed
+const TOKEN_REFRESH_MARGIN: u64 = 60;
+
+/// A completion token and its expiration
+#[derive(Debug, Clone)]
+struct CachedToken {
+    token: String,
+    /// Unix time when the token expires
+    expires_at: u64,
+}
+
+impl CachedToken {
+    /// Whether the token can still be used at the unix time `now`
+    fn is_fresh(&self, now: u64) -> bool {
+        now + TOKEN_REFRESH_MARGIN < self.expires_at
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 struct ModelVision {
     max_prompt_image_size: Option<i32>,
     max_prompt_images: Option<i32>,
     supported_media_types: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, Debug)]
-#[allow(dead_code)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 struct ModelLimits {
     max_context_window_tokens: Option<i32>,
     max_output_tokens: Option<i32>,
@@ -59,8 +205,7 @@ struct ModelLimits {
     vision: Option<ModelVision>,
 }
 
-#[derive(Deserialize, Debug)]
-#[allow(dead_code)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 struct ModelSupport {
     parallel_tool_calls: Option<bool>,
     streaming: Option<bool>,
@@ -68,8 +213,7 @@ struct ModelSupport {
     tool_calls: Option<bool>,
 }
 
-#[derive(Deserialize, Debug)]
-#[allow(dead_code)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 struct ModelCapabilites {
     family: String,
     limits: Option<ModelLimits>,
@@ -78,8 +222,7 @@ struct ModelCapabilites {
     r#type: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
-#[allow(dead_code)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 struct ModelsResponse {
     capabilities: ModelCapabilites,
     id: String,
@@ -97,43 +240,254 @@ struct ModelsRawResponse {
 
 impl Provider for CopilotClient {
     /// Make a request to copilot, passing the message provided by the user
+    #[instrument(level = "debug", skip_all, fields(%model))]
     async fn request(
         &self,
         model: &str,
         messages: &RefCell<Vec<Message>>,
+        options: &RequestOptions,
     ) -> anyhow::Result<impl Stream<Item = reqwest::Result<bytes::Bytes>>> {
+        let resp = self.send_completion(model, messages, options, true).await?;
+
+        // Stream for processing the response
+        let stream = resp.bytes_stream();
+        Ok(stream)
+    }
+
+    /// Make a request to copilot without streaming, the response is read once it is complete
+    #[instrument(level = "debug", skip_all, fields(%model))]
+    async fn complete(
+        &self,
+        model: &str,
+        messages: &RefCell<Vec<Message>>,
+        options: &RequestOptions,
+    ) -> anyhow::Result<Completion> {
+        let resp = self.send_completion(model, messages, options, false).await?;
+        let body = resp.text().await.map_err(ProviderError::from)?;
+        trace!(%body, "Completion received");
+
+        parse_completion(&body)
+    }
+
+    async fn get_models(&self) -> anyhow::Result<Vec<ModelInfo>> {
+        let models = self.fetch_models().await?;
+
+        Ok(models
+            .into_iter()
+            .map(|model| ModelInfo {
+                context_window: model
+                    .capabilities
+                    .limits
+                    .and_then(|limits| limits.max_context_window_tokens),
+                id: model.id,
+                name: model.name,
+                vendor: model.vendor,
+            })
+            .collect())
+    }
+
+    async fn max_output_tokens(&self, model: &str) -> anyhow::Result<Option<i32>> {
+        let models = self.fetch_models().await?;
+
+        Ok(models
+            .into_iter()
+            .find(|m| m.id == model)
+            .and_then(|m| m.capabilities.limits)
+            .and_then(|limits| limits.max_output_tokens))
+    }
+
+    async fn supports_candidates(&self, model: &str) -> anyhow::Result<Option<bool>> {
+        let models = self.fetch_models().await?;
+
+        // Only the OpenAI models accept the `n` parameter
+        Ok(models
+            .into_iter()
+            .find(|m| m.id == model)
+            .map(|m| m.vendor.contains("OpenAI")))
+    }
+
+    async fn tokenizer(&self, model: &str) -> anyhow::Result<Option<String>> {
+        let models = self.fetch_models().await?;
+
+        Ok(models
+            .into_iter()
+            .find(|m| m.id == model)
+            .and_then(|m| m.capabilities.tokenizer))
+    }
+
+    async fn vision(&self, model: &str) -> anyhow::Result<Option<Vision>> {
+        let models = self.fetch_models().await?;
+
+        Ok(models
+            .into_iter()
+            .find(|m| m.id == model)
+            .and_then(|m| m.capabilities.limits)
+            .and_then(|limits| limits.vision)
+            .map(|vision| Vision {
+                max_image_size: vision.max_prompt_image_size.map(|size| size as usize),
+                max_images: vision.max_prompt_images.map(|images| images as usize),
+                media_types: vision.supported_media_types.unwrap_or_default(),
+            }))
+    }
+
+    async fn structured_outputs(&self, model: &str) -> anyhow::Result<Option<bool>> {
+        let models = self.fetch_models().await?;
+
+        Ok(models
+            .into_iter()
+            .find(|m| m.id == model)
+            .and_then(|m| m.capabilities.supports)
+            .and_then(|supports| supports.structured_outputs))
+    }
+
+    fn http_client(&self) -> reqwest::Client {
+        self.client.clone()
+    }
+}
+
+impl CopilotClient {
+    /// Create a new client
+    pub fn new(auth: CopilotAuth) -> Self {
+        Self {
+            auth,
+            client: reqwest::Client::new(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            on_retry: None,
+            editor: EditorHeaders::default(),
+            idempotency_header: HeaderName::from_static(DEFAULT_IDEMPOTENCY_HEADER),
+            token_cache: Arc::new(Mutex::new(None)),
+            models_cache: Arc::new(Mutex::new(None)),
+            endpoints: CopilotEndpoints::default(),
+        }
+    }
+
+    /// Set how many times a rate-limited request is retried
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Identify the requests with the `editor` headers instead of the default ones
+    pub fn with_editor_headers(mut self, editor: EditorHeaders) -> Self {
+        self.editor = editor;
+        self
+    }
+
+    /// Call `on_retry` each time a rate-limited request is going to be retried
+    pub fn with_retry_notifier(mut self, on_retry: fn(&RetryEvent)) -> Self {
+        self.on_retry = Some(on_retry);
+        self
+    }
+
+    /// Send the idempotency key of the completion requests in the `header` instead of the default one
+    pub fn with_idempotency_header(mut self, header: HeaderName) -> Self {
+        self.idempotency_header = header;
+        self
+    }
+
+    /// Send the requests to `endpoints` instead of the public APIs
+    pub fn with_endpoints(mut self, endpoints: CopilotEndpoints) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    /// Send all the requests through `proxy` instead of the one of the environment, if any
+    pub fn with_proxy(mut self, proxy: reqwest::Proxy) -> anyhow::Result<Self> {
+        // A proxy set in the builder disables the proxies of the environment
+        self.client = reqwest::Client::builder().proxy(proxy).build()?;
+        Ok(self)
+    }
+
+    /// Send the completion request, retrying it while it is rate limited. Returns the response
+    /// once it is successful; it is streamed if `stream` is set.
+    async fn send_completion(
+        &self,
+        model: &str,
+        messages: &RefCell<Vec<Message>>,
+        options: &RequestOptions,
+        stream: bool,
+    ) -> anyhow::Result<reqwest::Response> {
         let headers = self.get_headers().await?;
 
         info!("Making request");
         trace!(?headers);
-        let body = CopilotBody {
-            temperature: 0.1,
-            max_tokens: 4096,
-            model: model.to_string(),
-            messages,
-            stream: true,
+        let body = {
+            let history = messages.borrow();
+            let body = CopilotBody {
+                temperature: TEMPERATURE,
+                max_tokens: options.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+                n: options.candidates,
+                stop: options.stop.clone(),
+                response_format: options.schema.as_ref().map(|schema| schema.response_format()),
+                model: model.to_string(),
+                messages: body_messages(&history, &options.images),
+                stream,
+            };
+
+            trace!(?body);
+            serde_json::to_string(&body)?
+        };
+        let mut attempt = 0;
+        // The same key on every retry lets the server discard the duplicates of the request
+        let idempotency_key = uuid::Uuid::new_v4().to_string();
+        debug!(%idempotency_key);
+
+        let resp = loop {
+            let mut req = self
+                .client
+                .post(&self.endpoints.completion)
+                .header("Authorization", format!("Bearer {}", headers.auth_token))
+                .header(&self.idempotency_header, &idempotency_key)
+                .header("Copilot-Integration-Id", &headers.copilot_integration_id)
+                .header("Editor-Version", &headers.editor_version)
+                .header("Editor-Plugin-Version", &headers.editor_plugin_version)
+                .header("User-Agent", USER_AGENT)
+                .body(body.clone());
+            if !options.images.is_empty() {
+                req = req.header("Copilot-Vision-Request", "true");
+            }
+
+            let resp = req
+                .send()
+                .instrument(debug_span!("send", attempt))
+                .await
+                .map_err(ProviderError::from)?;
+            debug!(?resp);
+
+            if resp.status() != StatusCode::TOO_MANY_REQUESTS || attempt >= self.max_retries {
+                break resp;
+            }
+
+            attempt += 1;
+            let event = RetryEvent {
+                attempt,
+                max_attempts: self.max_retries,
+                delay: retry_delay(attempt, resp.headers().get(RETRY_AFTER)),
+            };
+            warn!(?event, "Rate limited, retrying");
+            if let Some(on_retry) = self.on_retry {
+                on_retry(&event);
+            }
+            tokio::time::sleep(event.delay).await;
         };
 
-        trace!(?body);
-        let req = self
-            .client
-            .post(COMPLETION_URL)
-            .header("Authorization", format!("Bearer {}", headers.auth_token))
-            .header("Copilot-Integration-Id", headers.copilot_integration_id)
-            .header("Editor-Version", headers.editor_version)
-            .header("Editor-Plugin-Version", headers.editor_plugin_version)
-            .header("User-Agent", USER_AGENT)
-            .body(serde_json::to_string(&body)?);
-
-        let resp = req.send().await?;
-        debug!(?resp);
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            error!(%status, %body, "Completion request failed");
+            return Err(ProviderError::from_status(status, body.trim()).into());
+        }
 
-        // Stream for processing the response
-        let stream = resp.bytes_stream();
-        Ok(stream)
+        Ok(resp)
     }
 
-    async fn get_models(&self) -> anyhow::Result<Vec<String>> {
+    /// Retrieve the models available for the account
+    async fn fetch_models(&self) -> anyhow::Result<Vec<ModelsResponse>> {
+        if let Some(models) = self.models_cache.lock().expect("models cache lock").as_ref() {
+            debug!("Using the cached models");
+            return Ok(models.clone());
+        }
+
         let headers = self.get_headers().await?;
 
         info!("Making request for retrieving models");
@@ -141,75 +495,218 @@ impl Provider for CopilotClient {
 
         let req = self
             .client
-            .get(MODELS)
+            .get(&self.endpoints.models)
             .header("Authorization", format!("Bearer {}", headers.auth_token))
             .header("Copilot-Integration-Id", headers.copilot_integration_id)
             .header("Editor-Version", headers.editor_version)
             .header("Editor-Plugin-Version", headers.editor_plugin_version)
             .header("User-Agent", USER_AGENT);
-        let resp = req.send().await?;
-        let resp_body = resp.json::<ModelsRawResponse>().await?;
-        debug!("{:#?}", resp_body.data);
+        let resp = req.send().await.map_err(ProviderError::from)?;
+        let models = parse_models_response(resp).await?;
+        debug!("{:#?}", models);
+        *self.models_cache.lock().expect("models cache lock") = Some(models.clone());
 
-        for model in resp_body.data.iter() {
-            println!("{}", model.id);
-        }
+        Ok(models)
+    }
 
-        Ok(vec![])
+    /// The available models with all their capabilities as a JSON array, as returned by the API
+    pub async fn models_json(&self) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.fetch_models().await?)?)
     }
-}
 
-impl CopilotClient {
-    /// Create a new client
-    pub fn new(auth: CopilotAuth) -> Self {
-        Self {
-            auth,
-            client: reqwest::Client::new(),
+    /// Retrieve the GitHub account of the token and the Copilot plan from the claims of the
+    /// exchanged token
+    pub async fn whoami(&self) -> anyhow::Result<Identity> {
+        let headers = self.get_headers().await?;
+        let claims = token_claims(&headers.auth_token);
+
+        info!("Making request for retrieving the user");
+        let resp = self
+            .client
+            .get(&self.endpoints.user)
+            .header(
+                "Authorization",
+                format!("token {}", self.auth.get_token().expect("token string")),
+            )
+            .header("User-Agent", USER_AGENT)
+            .send()
+            .await
+            .map_err(ProviderError::from)?;
+
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(ProviderError::from_status(status, body.trim()).into());
         }
+
+        let user = resp.json::<UserResponse>().await?;
+        debug!(?user);
+
+        Ok(Identity {
+            login: user.login,
+            name: user.name,
+            plan: claims.get("sku").map(|sku| sku.to_string()),
+            expires_at: claims.get("exp").and_then(|exp| exp.parse().ok()),
+        })
+    }
+
+    /// Sign in to GitHub with the device flow, see [`login::device_login`]
+    pub async fn login(&self, on_code: impl FnOnce(&DeviceCode)) -> anyhow::Result<String> {
+        login::device_login(&self.client, on_code).await
     }
 
     /// Get the headers and token for use in requests
+    #[instrument(level = "debug", skip_all)]
     async fn get_headers(&self) -> anyhow::Result<CopilotHeaders> {
         // Main auth token is required
         if self.auth.get_token().is_none() {
-            let token = self.auth.get_token();
-            error!(?token, "token not found");
-            return Err(anyhow!("Token not found"));
+            error!("token not found in {} nor the config file", TOKEN_ENV);
+            return Err(ProviderError::other(format!(
+                "Token not found; set {} or sign in to Copilot in your editor",
+                TOKEN_ENV
+            ))
+            .into());
+        }
+        debug!(source = ?self.auth.source().map(|source| source.to_string()), "Using the token");
+
+        let headers = CopilotHeaders {
+            auth_token: self.completion_token().await?,
+            editor_version: self.editor.editor_version.clone(),
+            editor_plugin_version: self.editor.plugin_version.clone(),
+            copilot_integration_id: self.editor.integration_id.clone(),
+        };
+        debug!(?headers, "Effective headers");
+
+        Ok(headers)
+    }
+
+    /// The short-lived token of the completions, it is cached until it is about to expire
+    async fn completion_token(&self) -> anyhow::Result<String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if let Some(cached) = self.token_cache.lock().expect("token cache lock").as_ref()
+            && cached.is_fresh(now)
+        {
+            debug!(expires_in = cached.expires_at - now, "Using the cached token");
+            return Ok(cached.token.clone());
         }
 
-        trace!(%HEADERS_URL, "retrieving headers");
+        trace!(url = %self.endpoints.headers, "retrieving headers");
 
         let req = self
             .client
-            .get(HEADERS_URL)
+            .get(&self.endpoints.headers)
             .header(
                 "Authorization",
                 format!("token {}", self.auth.get_token().expect("token string")),
             )
             .header("User-Agent", USER_AGENT);
 
-        let resp = req.send().await?;
+        let resp = req.send().await.map_err(ProviderError::from)?;
         trace!(?resp, "raw response");
 
-        if !resp.status().is_success() {
-            return Err(anyhow!("error in request, status code {:?}", resp.status()));
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            error!(%status, %body, "Token request failed");
+            return Err(ProviderError::from_status(status, body.trim()).into());
         }
 
         let resp = resp.json::<HeadersResponse>().await?;
 
-        trace!(?resp);
+        // The token also carries its expiration in the `exp` claim
+        let expires_at = resp
+            .expires_at
+            .or_else(|| token_claims(&resp.token).get("exp").and_then(|exp| exp.parse().ok()));
+        debug!(?expires_at, "Token retrieved");
+        match expires_at {
+            Some(expires_at) => {
+                *self.token_cache.lock().expect("token cache lock") = Some(CachedToken {
+                    token: resp.token.clone(),
+                    expires_at,
+                })
+            }
+            None => warn!("The token has no expiration, it is not cached"),
+        }
 
-        Ok(CopilotHeaders {
-            auth_token: resp.token,
-            editor_version: "Neovim/0.11.1".to_string(),
-            editor_plugin_version: "copilot-chat".to_string(),
-            copilot_integration_id: "vscode-chat".to_string(),
-        })
+        Ok(resp.token)
     }
 }
 
+/// Read the models of the response, failing with a [`ProviderError`] if it is not successful
+async fn parse_models_response(resp: reqwest::Response) -> anyhow::Result<Vec<ModelsResponse>> {
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        error!(%status, %body, "Models request failed");
+        return Err(ProviderError::from_status(status, body.trim()).into());
+    }
+
+    Ok(resp.json::<ModelsRawResponse>().await?.data)
+}
+
+/// The response of a completion without streaming
+#[derive(Deserialize, Debug)]
+struct CompletionResponse {
+    choices: Vec<CompletionChoice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionChoice {
+    #[serde(default)]
+    index: usize,
+    message: Option<CompletionMessage>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionMessage {
+    content: Option<String>,
+}
+
+/// Read the choices of a completion without streaming, sorted by their index
+fn parse_completion(body: &str) -> anyhow::Result<Completion> {
+    let mut response: CompletionResponse =
+        serde_json::from_str(body).map_err(|e| ProviderError::other(format!("Invalid completion response: {}", e)))?;
+    response.choices.sort_by_key(|choice| choice.index);
+
+    Ok(Completion {
+        finish_reason: response.choices.first().and_then(|choice| choice.finish_reason.clone()),
+        choices: response
+            .choices
+            .into_iter()
+            .map(|choice| Message {
+                role: Role::Assistant,
+                content: choice
+                    .message
+                    .and_then(|message| message.content)
+                    .unwrap_or_default()
+                    .into(),
+            })
+            .collect(),
+        usage: response.usage,
+    })
+}
+
+/// The `key=value` claims of a Copilot token, separated by `;`
+fn token_claims(token: &str) -> HashMap<&str, &str> {
+    token.split(';').filter_map(|claim| claim.split_once('=')).collect()
+}
+
+/// Time to wait before the retry number `attempt`: the `Retry-After` seconds if the server sends
+/// them, otherwise an exponential backoff starting at one second.
+fn retry_delay(attempt: u32, retry_after: Option<&HeaderValue>) -> Duration {
+    retry_after
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_else(|| Duration::from_secs(1 << (attempt.saturating_sub(1)).min(6)))
+}
+
 /// Contain all the required headers for making a request
-#[derive(Debug)]
 struct CopilotHeaders {
     auth_token: String,
     editor_version: String,
@@ -217,12 +714,218 @@ struct CopilotHeaders {
     copilot_integration_id: String,
 }
 
+/// The token is redacted, so the headers can be logged
+impl std::fmt::Debug for CopilotHeaders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CopilotHeaders")
+            .field("auth_token", &"<redacted>")
+            .field("editor_version", &self.editor_version)
+            .field("editor_plugin_version", &self.editor_plugin_version)
+            .field("copilot_integration_id", &self.copilot_integration_id)
+            .finish()
+    }
+}
+
 /// Contain the commons parameters of the model for use in requests
 #[derive(Serialize, Debug)]
-struct CopilotBody<'a> {
+struct CopilotBody {
     temperature: f32,
     max_tokens: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<serde_json::Value>,
     model: String,
     stream: bool,
-    messages: &'a RefCell<Vec<Message>>,
+    messages: Vec<Message>,
+}
+
+/// The messages of the request body, with the `images` attached to the last user message
+fn body_messages(messages: &[Message], images: &[Image]) -> Vec<Message> {
+    let last_user = messages.iter().rposition(|message| message.role == Role::User);
+
+    messages
+        .iter()
+        .enumerate()
+        .map(|(i, message)| {
+            if Some(i) != last_user {
+                return message.clone();
+            }
+            Message {
+                role: message.role.clone(),
+                content: message.content.clone().with_images(images),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::mock::MockProvider;
+
+    #[test]
+    fn parse_token_claims() {
+        let claims = token_claims("tid=abc;exp=1751000792;sku=copilot_for_business_seat;chat=1:signature");
+
+        assert_eq!(claims.get("sku"), Some(&"copilot_for_business_seat"));
+        assert_eq!(claims.get("exp"), Some(&"1751000792"));
+        assert_eq!(claims.get("missing"), None);
+    }
+
+    #[test]
+    fn parse_complete_response() {
+        let completion = parse_completion(
+            r#"{
+                "choices": [
+                    {"index": 1, "message": {"role": "assistant", "content": "second"}, "finish_reason": "length"},
+                    {"index": 0, "message": {"role": "assistant", "content": "first"}, "finish_reason": "stop"}
+                ],
+                "usage": {"prompt_tokens": 12, "completion_tokens": 4, "total_tokens": 16}
+            }"#,
+        )
+        .expect("parse the completion");
+
+        let choices: Vec<_> = completion
+            .choices
+            .iter()
+            .map(|choice| choice.content.text().into_owned())
+            .collect();
+        assert_eq!(choices, ["first", "second"]);
+        assert_eq!(completion.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(completion.usage.map(|usage| usage.total_tokens), Some(16));
+
+        let error = parse_completion("data: {}").expect_err("streamed response");
+        assert!(error.downcast::<ProviderError>().is_ok());
+    }
+
+    #[tokio::test]
+    async fn models_error_response() {
+        let resp = http::Response::builder()
+            .status(401)
+            .body(r#"{"message": "Bad credentials"}"#)
+            .expect("build the response");
+
+        let error = parse_models_response(resp.into()).await.expect_err("unauthorized");
+        let error = error.downcast::<ProviderError>().expect("provider error");
+        assert_eq!(error.status, Some(401));
+        assert!(error.is_auth());
+        assert_eq!(error.to_string(), r#"401 Unauthorized: {"message": "Bad credentials"}"#);
+
+        let resp = http::Response::builder()
+            .body(r#"{"data": []}"#)
+            .expect("build the response");
+        assert!(parse_models_response(resp.into()).await.expect("models").is_empty());
+    }
+
+    #[tokio::test]
+    async fn mock_models_json_matches_copilot() {
+        let resp = http::Response::builder()
+            .body(
+                r#"{"data": [{
+                    "capabilities": {
+                        "family": "gpt-4o",
+                        "limits": {"max_context_window_tokens": 128000, "max_output_tokens": 4096, "max_prompt_tokens": 64000},
+                        "supports": {"streaming": true, "tool_calls": true},
+                        "tokenizer": "o200k_base",
+                        "type": "chat"
+                    },
+                    "id": "gpt-4o",
+                    "name": "GPT-4o",
+                    "preview": false,
+                    "vendor": "Azure OpenAI",
+                    "version": "gpt-4o-2024-11-20"
+                }]}"#,
+            )
+            .expect("build the response");
+        let models = parse_models_response(resp.into()).await.expect("models");
+        let copilot = serde_json::to_value(models).expect("serialize the models");
+        let mock = MockProvider::default().models_json();
+
+        // The same keys at every level, the values may differ
+        fn shape(value: &serde_json::Value) -> serde_json::Value {
+            match value {
+                serde_json::Value::Object(map) => map.iter().map(|(key, value)| (key.clone(), shape(value))).collect(),
+                serde_json::Value::Array(items) => items.iter().map(shape).collect(),
+                _ => serde_json::Value::Null,
+            }
+        }
+        assert_eq!(shape(&copilot), shape(&mock));
+    }
+
+    #[test]
+    fn attach_images_to_last_user_message() {
+        let message = |role: Role, content: &str| Message {
+            role,
+            content: content.into(),
+        };
+        let messages = [
+            message(Role::User, "first"),
+            message(Role::User, "what is this?"),
+            message(Role::Assistant, "a pixel"),
+        ];
+        let image = Image {
+            path: "pixel.png".to_string(),
+            media_type: "image/png".to_string(),
+            data: vec![0],
+        };
+
+        let body = serde_json::to_value(body_messages(&messages, &[image])).expect("serialize the messages");
+        assert_eq!(body[0], serde_json::json!({"role": "user", "content": "first"}));
+        assert_eq!(
+            body[1],
+            serde_json::json!({"role": "user", "content": [
+                {"type": "text", "text": "what is this?"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,AA=="}}
+            ]})
+        );
+        assert_eq!(body[2], serde_json::json!({"role": "assistant", "content": "a pixel"}));
+    }
+
+    #[test]
+    fn enterprise_endpoints() {
+        let endpoints = CopilotEndpoints::default()
+            .with_api_base("https://copilot-api.example.ghe.com/")
+            .with_github_api_base("https://api.example.ghe.com");
+
+        assert_eq!(
+            endpoints.completion,
+            "https://copilot-api.example.ghe.com/chat/completions"
+        );
+        assert_eq!(endpoints.models, "https://copilot-api.example.ghe.com/models");
+        assert_eq!(
+            endpoints.headers,
+            "https://api.example.ghe.com/copilot_internal/v2/token"
+        );
+        assert_eq!(endpoints.user, "https://api.example.ghe.com/user");
+    }
+
+    #[test]
+    fn cached_token_expiration() {
+        let cached = CachedToken {
+            token: "tid=abc;exp=1000".to_string(),
+            expires_at: 1000,
+        };
+        assert!(cached.is_fresh(900));
+        // It is refreshed a bit before it expires, so it doesn't expire in the middle of a request
+        assert!(!cached.is_fresh(1000 - TOKEN_REFRESH_MARGIN));
+        assert!(!cached.is_fresh(1200));
+    }
+
+    #[test]
+    fn retry_delay_backoff() {
+        assert_eq!(retry_delay(1, None), Duration::from_secs(1));
+        assert_eq!(retry_delay(3, None), Duration::from_secs(4));
+        assert_eq!(
+            retry_delay(2, Some(&HeaderValue::from_static("7"))),
+            Duration::from_secs(7)
+        );
+        // Dates are not supported, fallback to the backoff
+        assert_eq!(
+            retry_delay(2, Some(&HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"))),
+            Duration::from_secs(2)
+        );
+    }
 }
@@ -0,0 +1,82 @@
+use std::fmt::Display;
+
+use reqwest::StatusCode;
+use thiserror::Error;
+
+/// An error returned by the provider, keeping the HTTP status for deciding how to handle it
+#[derive(Debug, Error, Clone, PartialEq)]
+pub struct ProviderError {
+    /// HTTP status of the response, `None` if the request didn't get a response
+    pub status: Option<u16>,
+    /// Whether the same request can succeed if it is retried, e.g. rate limits or server errors
+    pub retriable: bool,
+    /// The message of the provider, usually the body of the response
+    pub message: String,
+}
+
+impl ProviderError {
+    /// Build the error from an unsuccessful response; 429 and 5xx are retriable
+    pub fn from_status(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status: Some(status.as_u16()),
+            retriable: status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+            message: message.into(),
+        }
+    }
+
+    /// An error that is not related to a response, e.g. a missing token
+    pub fn other(message: impl Into<String>) -> Self {
+        Self {
+            status: None,
+            retriable: false,
+            message: message.into(),
+        }
+    }
+
+    /// Whether the provider rejected the credentials
+    pub fn is_auth(&self) -> bool {
+        matches!(self.status, Some(401 | 403))
+    }
+}
+
+impl From<reqwest::Error> for ProviderError {
+    fn from(error: reqwest::Error) -> Self {
+        Self {
+            status: error.status().map(|status| status.as_u16()),
+            retriable: error.is_timeout() || error.is_connect(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status.and_then(|status| StatusCode::from_u16(status).ok()) {
+            Some(status) => write!(f, "{}: {}", status, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retriable_statuses() {
+        let error = ProviderError::from_status(StatusCode::TOO_MANY_REQUESTS, "slow down");
+        assert!(error.retriable);
+        assert!(!error.is_auth());
+        assert_eq!(error.to_string(), "429 Too Many Requests: slow down");
+
+        assert!(ProviderError::from_status(StatusCode::BAD_GATEWAY, "").retriable);
+
+        let error = ProviderError::from_status(StatusCode::UNAUTHORIZED, "Bad credentials");
+        assert!(!error.retriable);
+        assert!(error.is_auth());
+
+        let error = ProviderError::other("Token not found");
+        assert_eq!(error.status, None);
+        assert_eq!(error.to_string(), "Token not found");
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use super::errors::ProviderError;
+
+/// OAuth app of the Copilot plugins, the tokens of other apps are rejected by Copilot
+pub const COPILOT_CLIENT_ID: &str = "Iv1.b507a08c87ecfe98";
+
+/// Endpoint where the device code is requested
+static DEVICE_CODE_URL: &str = "https://github.com/login/device/code";
+/// Endpoint that is polled for the token while the user authorizes the device
+static ACCESS_TOKEN_URL: &str = "https://github.com/login/oauth/access_token";
+/// Grant type of the device flow
+static DEVICE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// Seconds added to the polling interval when GitHub asks to slow down
+const SLOW_DOWN_SECS: u64 = 5;
+
+/// The code the user enters in GitHub for authorizing this device
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeviceCode {
+    device_code: String,
+    /// The code the user enters in `verification_uri`
+    pub user_code: String,
+    pub verification_uri: String,
+    /// Seconds until the codes expire
+    pub expires_in: u64,
+    /// Min seconds between polls
+    interval: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct AccessTokenResponse {
+    access_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// The state of the authorization after a poll
+#[derive(Debug, PartialEq)]
+enum PollState {
+    Authorized(String),
+    Pending,
+    SlowDown,
+    Failed(String),
+}
+
+impl From<AccessTokenResponse> for PollState {
+    fn from(response: AccessTokenResponse) -> Self {
+        if let Some(token) = response.access_token {
+            return Self::Authorized(token);
+        }
+
+        match response.error.as_deref() {
+            Some("authorization_pending") => Self::Pending,
+            Some("slow_down") => Self::SlowDown,
+            error => Self::Failed(
+                response
+                    .error_description
+                    .or_else(|| error.map(|error| error.to_string()))
+                    .unwrap_or_else(|| "The token was not returned".to_string()),
+            ),
+        }
+    }
+}
+
+/// Authorize this device with the GitHub device-code flow and return the OAuth token.
+/// `on_code` is called with the code that the user must enter in GitHub.
+pub async fn device_login(client: &reqwest::Client, on_code: impl FnOnce(&DeviceCode)) -> anyhow::Result<String> {
+    info!("Requesting a device code");
+    let resp = client
+        .post(DEVICE_CODE_URL)
+        .header("Accept", "application/json")
+        .form(&[("client_id", COPILOT_CLIENT_ID), ("scope", "read:user")])
+        .send()
+        .await
+        .map_err(ProviderError::from)?;
+
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(ProviderError::from_status(status, body.trim()).into());
+    }
+
+    let code = resp.json::<DeviceCode>().await?;
+    debug!(user_code = %code.user_code, expires_in = code.expires_in, "Device code received");
+    on_code(&code);
+
+    let mut interval = Duration::from_secs(code.interval);
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(code.expires_in);
+    while tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(interval).await;
+
+        let resp = client
+            .post(ACCESS_TOKEN_URL)
+            .header("Accept", "application/json")
+            .form(&[
+                ("client_id", COPILOT_CLIENT_ID),
+                ("device_code", code.device_code.as_str()),
+                ("grant_type", DEVICE_GRANT_TYPE),
+            ])
+            .send()
+            .await
+            .map_err(ProviderError::from)?;
+
+        match PollState::from(resp.json::<AccessTokenResponse>().await?) {
+            PollState::Authorized(token) => return Ok(token),
+            PollState::Pending => debug!("Authorization pending"),
+            PollState::SlowDown => interval += Duration::from_secs(SLOW_DOWN_SECS),
+            PollState::Failed(message) => return Err(ProviderError::other(message).into()),
+        }
+    }
+
+    Err(ProviderError::other("The device code expired before it was authorized").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_states() {
+        let state = |json: &str| PollState::from(serde_json::from_str::<AccessTokenResponse>(json).expect("parse"));
+
+        assert_eq!(
+            state(r#"{"access_token": "ghu_token", "token_type": "bearer", "scope": "read:user"}"#),
+            PollState::Authorized("ghu_token".to_string())
+        );
+        assert_eq!(state(r#"{"error": "authorization_pending"}"#), PollState::Pending);
+        assert_eq!(state(r#"{"error": "slow_down", "interval": 10}"#), PollState::SlowDown);
+        assert_eq!(
+            state(r#"{"error": "access_denied", "error_description": "The user has denied your application access."}"#),
+            PollState::Failed("The user has denied your application access.".to_string())
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    cell::RefCell,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use bytes::Bytes;
+use futures_util::{Stream, stream};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use crate::chat::{Message, Role};
+
+use super::{
+    errors::ProviderError,
+    provider::{Completion, ModelInfo, Provider, RequestOptions, Vision},
+};
+
+/// Model reported by the mock provider
+pub const MOCK_MODEL: &str = "mock";
+
+/// A canned response of the mock provider
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MockResponse {
+    /// Content chunks, each one is sent as an event of the stream
+    #[serde(default)]
+    pub chunks: Vec<String>,
+    /// Finish reason of the last event, `stop` if it is not set
+    pub finish_reason: Option<String>,
+    /// Fail the request with this HTTP status instead of streaming the chunks
+    pub status: Option<u16>,
+    /// End the stream with an error event with this message after the chunks
+    pub error: Option<String>,
+}
+
+/// An offline provider that streams canned responses, for testing integrations without
+/// requests to Copilot. The responses are used in order and it starts over after the last one;
+/// without responses it echoes the last user message.
+#[derive(Debug, Clone, Default)]
+pub struct MockProvider {
+    responses: Vec<MockResponse>,
+    next: Arc<AtomicUsize>,
+}
+
+impl MockProvider {
+    pub fn new(responses: Vec<MockResponse>) -> Self {
+        Self {
+            responses,
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Read the responses from a JSON file with an array of [`MockResponse`]
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let responses = serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid mock responses file {}: {}", path.display(), e))?;
+
+        Ok(Self::new(responses))
+    }
+
+    /// The mock model described as Copilot describes its models, for `models --json`
+    pub fn models_json(&self) -> serde_json::Value {
+        serde_json::json!([{
+            "capabilities": {
+                "family": MOCK_MODEL,
+                "limits": {
+                    "max_context_window_tokens": null,
+                    "max_output_tokens": null,
+                    "max_prompt_tokens": null,
+                    "vision": null
+                },
+                "supports": {
+                    "parallel_tool_calls": false,
+                    "streaming": true,
+                    "structured_outputs": null,
+                    "tool_calls": false
+                },
+                "tokenizer": null,
+                "type": "chat"
+            },
+            "id": MOCK_MODEL,
+            "model_picker_enabled": false,
+            "name": "Mock",
+            "preview": false,
+            "vendor": "copilot-chat",
+            "version": MOCK_MODEL
+        }])
+    }
+
+    /// The response for the next request, it fails with the status of the response if it is set
+    fn next_response(&self, messages: &[Message]) -> anyhow::Result<MockResponse> {
+        if self.responses.is_empty() {
+            let prompt = messages
+                .iter()
+                .rev()
+                .find(|message| message.role == Role::User)
+                .map(|message| message.content.text().into_owned())
+                .unwrap_or_default();
+            return Ok(MockResponse {
+                chunks: vec![prompt],
+                ..Default::default()
+            });
+        }
+
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.responses.len();
+        let response = self.responses[index].clone();
+        debug!(?response);
+
+        if let Some(status) = response.status {
+            let status = StatusCode::from_u16(status)?;
+            let message = response.chunks.concat();
+            return Err(ProviderError::from_status(status, message).into());
+        }
+
+        Ok(response)
+    }
+}
+
+/// Encode the response as the events of a streamed completion
+fn sse_events(response: MockResponse) -> Vec<Bytes> {
+    let event = |data: serde_json::Value| Bytes::from(format!("data: {}\n\n", data));
+
+    let mut events: Vec<Bytes> = response
+        .chunks
+        .into_iter()
+        .map(|chunk| event(serde_json::json!({"choices": [{"index": 0, "delta": {"content": chunk}}]})))
+        .collect();
+    if let Some(error) = response.error {
+        events.push(event(serde_json::json!({"error": {"message": error}})));
+        return events;
+    }
+    let finish_reason = response.finish_reason.unwrap_or_else(|| "stop".to_string());
+    events.push(event(
+        serde_json::json!({"choices": [{"index": 0, "delta": {}, "finish_reason": finish_reason}]}),
+    ));
+    events.push(Bytes::from_static(b"data: [DONE]\n\n"));
+
+    events
+}
+
+impl Provider for MockProvider {
+    async fn request(
+        &self,
+        model: &str,
+        messages: &RefCell<Vec<Message>>,
+        _options: &RequestOptions,
+    ) -> anyhow::Result<impl Stream<Item = reqwest::Result<bytes::Bytes>>> {
+        info!(%model, "Making mock request");
+        let response = self.next_response(&messages.borrow())?;
+
+        Ok(stream::iter(sse_events(response).into_iter().map(Ok)))
+    }
+
+    async fn complete(
+        &self,
+        model: &str,
+        messages: &RefCell<Vec<Message>>,
+        _options: &RequestOptions,
+    ) -> anyhow::Result<Completion> {
+        info!(%model, "Making mock request without streaming");
+        let response = self.next_response(&messages.borrow())?;
+        if let Some(error) = response.error {
+            return Err(anyhow::anyhow!(error));
+        }
+
+        Ok(Completion {
+            choices: vec![Message {
+                role: Role::Assistant,
+                content: response.chunks.concat().into(),
+            }],
+            finish_reason: Some(response.finish_reason.unwrap_or_else(|| "stop".to_string())),
+            usage: None,
+        })
+    }
+
+    async fn get_models(&self) -> anyhow::Result<Vec<ModelInfo>> {
+        Ok(vec![ModelInfo {
+            id: MOCK_MODEL.to_string(),
+            name: "Mock".to_string(),
+            vendor: "copilot-chat".to_string(),
+            context_window: None,
+        }])
+    }
+
+    async fn max_output_tokens(&self, _model: &str) -> anyhow::Result<Option<i32>> {
+        Ok(None)
+    }
+
+    async fn supports_candidates(&self, _model: &str) -> anyhow::Result<Option<bool>> {
+        Ok(Some(false))
+    }
+
+    async fn tokenizer(&self, _model: &str) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+
+    async fn vision(&self, _model: &str) -> anyhow::Result<Option<Vision>> {
+        Ok(None)
+    }
+
+    async fn structured_outputs(&self, _model: &str) -> anyhow::Result<Option<bool>> {
+        Ok(None)
+    }
+
+    fn default_model(&self) -> &str {
+        MOCK_MODEL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn stream_canned_responses_in_order() {
+        let responses: Vec<MockResponse> =
+            serde_json::from_str(r#"[{"chunks": ["Hello", ", world"]}, {"chunks": ["rate limited"], "status": 429}]"#)
+                .expect("parse responses");
+        let provider = MockProvider::new(responses);
+        let messages = RefCell::new(vec![]);
+        let options = RequestOptions::default();
+
+        let stream = provider
+            .request(MOCK_MODEL, &messages, &options)
+            .await
+            .expect("first response");
+        let events: Vec<_> = stream.map(|event| event.expect("event")).collect().await;
+        assert_eq!(events.len(), 4);
+        assert_eq!(
+            events[1],
+            r#"data: {"choices":[{"delta":{"content":", world"},"index":0}]}"#.to_string() + "\n\n"
+        );
+        assert_eq!(events[3], "data: [DONE]\n\n");
+
+        let error = provider
+            .request(MOCK_MODEL, &messages, &options)
+            .await
+            .err()
+            .expect("second response fails");
+        let error = error.downcast::<ProviderError>().expect("provider error");
+        assert!(error.retriable);
+
+        // Starts over after the last response
+        assert!(provider.request(MOCK_MODEL, &messages, &options).await.is_ok());
+    }
+}
@@ -1,5 +1,13 @@
 pub mod auth;
+mod backend;
 mod connector;
+mod errors;
+pub mod login;
+pub mod mock;
 pub mod provider;
 
-pub use connector::CopilotClient;
+pub use backend::Backend;
+pub use connector::{
+    CopilotClient, CopilotEndpoints, DEFAULT_IDEMPOTENCY_HEADER, DEFAULT_MAX_RETRIES, EditorHeaders, RetryEvent,
+};
+pub use errors::ProviderError;
@@ -1,7 +1,65 @@
 use std::cell::RefCell;
 
-use crate::chat::{Builder, Message};
+use crate::chat::{Builder, DEFAULT_MODEL, Message, Usage};
+use crate::tools::{image::Image, schema::Schema};
 use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+
+/// Default length limit of the completion
+pub const DEFAULT_MAX_TOKENS: i32 = 4096;
+
+/// Sampling temperature of the completions, low for focused answers
+pub const TEMPERATURE: f32 = 0.1;
+
+/// Tunable parameters of a completion request
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestOptions {
+    /// Max tokens of the completion, [`DEFAULT_MAX_TOKENS`] if it is not set
+    pub max_tokens: Option<i32>,
+    /// Number of completions generated for the request, one if it is not set
+    pub candidates: Option<u32>,
+    /// Images sent with the last user message, the model must accept them
+    pub images: Vec<Image>,
+    /// Sequences where the model stops generating
+    pub stop: Vec<String>,
+    /// JSON schema of the response, for models with structured outputs
+    pub schema: Option<Schema>,
+}
+
+/// The images accepted by a vision-capable model
+#[derive(Debug, Clone, Default)]
+pub struct Vision {
+    /// Max size in bytes of each image
+    pub max_image_size: Option<usize>,
+    /// Max number of images in a request
+    pub max_images: Option<usize>,
+    /// MIME types of the images, any of them if it is empty
+    pub media_types: Vec<String>,
+}
+
+/// A model available for the account
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModelInfo {
+    /// Identifier sent in the requests, e.g. `gpt-4o`
+    pub id: String,
+    /// Display name of the model
+    pub name: String,
+    pub vendor: String,
+    /// Max tokens of the prompt and the response together, if the provider knows it
+    pub context_window: Option<i32>,
+}
+
+/// A response received at once, without streaming
+#[derive(Debug, Clone)]
+pub struct Completion {
+    /// One message per candidate, in order
+    pub choices: Vec<Message>,
+    /// Why the first candidate finished
+    pub finish_reason: Option<String>,
+    /// Tokens used by the request, if the provider reports them
+    pub usage: Option<Usage>,
+}
 
 /// A message provider from the Copilot API
 pub trait Provider {
@@ -9,8 +67,17 @@ pub trait Provider {
         &self,
         model: &str,
         messages: &RefCell<Vec<Message>>,
+        options: &RequestOptions,
     ) -> anyhow::Result<impl Stream<Item = reqwest::Result<bytes::Bytes>>>;
 
+    /// Make the request without streaming, returning the response once it is complete
+    async fn complete(
+        &self,
+        model: &str,
+        messages: &RefCell<Vec<Message>>,
+        options: &RequestOptions,
+    ) -> anyhow::Result<Completion>;
+
     fn builder<'a>(&'a self, messages: &'a RefCell<Vec<Message>>) -> Builder<'a, Self>
     where
         Self: Sized,
@@ -18,7 +85,34 @@ pub trait Provider {
         Builder::new(self, messages)
     }
 
-    async fn get_models(&self) -> anyhow::Result<Vec<String>>;
+    /// The models available for the account
+    async fn get_models(&self) -> anyhow::Result<Vec<ModelInfo>>;
+
+    /// Max output tokens allowed by `model`, if the provider knows it
+    async fn max_output_tokens(&self, model: &str) -> anyhow::Result<Option<i32>>;
+
+    /// Whether `model` can generate several completions for a request, if the provider knows it
+    async fn supports_candidates(&self, model: &str) -> anyhow::Result<Option<bool>>;
+
+    /// Name of the tokenizer used by `model`, e.g. `cl100k_base`, if the provider knows it
+    async fn tokenizer(&self, model: &str) -> anyhow::Result<Option<String>>;
+
+    /// The images accepted by `model`, `None` if it does not accept images
+    async fn vision(&self, model: &str) -> anyhow::Result<Option<Vision>>;
+
+    /// Whether `model` can follow a JSON schema for the response, if the provider knows it
+    async fn structured_outputs(&self, model: &str) -> anyhow::Result<Option<bool>>;
+
+    /// Model of the requests that don't set one
+    fn default_model(&self) -> &str {
+        DEFAULT_MODEL
+    }
+
+    /// Client of the other requests of the chat, e.g. the attached URLs, with the same settings
+    /// as the provider's
+    fn http_client(&self) -> reqwest::Client {
+        reqwest::Client::new()
+    }
 }
 
 #[cfg(test)]
@@ -31,15 +125,18 @@ pub(crate) mod tests {
     use bytes::{BufMut, Bytes, BytesMut};
     use futures_util::Stream;
 
-    use crate::chat::Message;
+    use crate::chat::{Message, Role};
 
-    use super::Provider;
+    use super::{Completion, ModelInfo, Provider, RequestOptions, Vision};
 
     #[derive(Default)]
     pub struct TestProvider<'a> {
         chunks: usize,
         content: &'a str,
         pub input_messages: RefCell<Vec<Message>>,
+        pub input_options: RefCell<RequestOptions>,
+        pub max_output_tokens: Option<i32>,
+        pub vision: Option<Vision>,
     }
 
     impl<'a> TestProvider<'a> {
@@ -48,6 +145,9 @@ pub(crate) mod tests {
                 chunks,
                 content,
                 input_messages: RefCell::new(vec![]),
+                input_options: RefCell::new(RequestOptions::default()),
+                max_output_tokens: None,
+                vision: None,
             }
         }
     }
@@ -87,14 +187,54 @@ pub(crate) mod tests {
             &self,
             _model: &str,
             messages: &RefCell<Vec<Message>>,
+            options: &RequestOptions,
         ) -> anyhow::Result<impl Stream<Item = reqwest::Result<bytes::Bytes>>> {
             let stream = TestStreamProvider::new(self.chunks, self.content);
             self.input_messages.replace(messages.borrow().to_owned());
+            self.input_options.replace(options.clone());
             Ok(stream)
         }
 
-        async fn get_models(&self) -> anyhow::Result<Vec<String>> {
+        async fn complete(
+            &self,
+            _model: &str,
+            messages: &RefCell<Vec<Message>>,
+            options: &RequestOptions,
+        ) -> anyhow::Result<Completion> {
+            self.input_messages.replace(messages.borrow().to_owned());
+            self.input_options.replace(options.clone());
+            Ok(Completion {
+                choices: vec![Message {
+                    role: Role::Assistant,
+                    content: self.content.repeat(self.chunks).into(),
+                }],
+                finish_reason: Some("stop".to_string()),
+                usage: None,
+            })
+        }
+
+        async fn get_models(&self) -> anyhow::Result<Vec<ModelInfo>> {
             Ok(vec![])
         }
+
+        async fn max_output_tokens(&self, _model: &str) -> anyhow::Result<Option<i32>> {
+            Ok(self.max_output_tokens)
+        }
+
+        async fn supports_candidates(&self, _model: &str) -> anyhow::Result<Option<bool>> {
+            Ok(None)
+        }
+
+        async fn tokenizer(&self, _model: &str) -> anyhow::Result<Option<String>> {
+            Ok(None)
+        }
+
+        async fn vision(&self, _model: &str) -> anyhow::Result<Option<Vision>> {
+            Ok(self.vision.clone())
+        }
+
+        async fn structured_outputs(&self, _model: &str) -> anyhow::Result<Option<bool>> {
+            Ok(None)
+        }
     }
 }
@@ -0,0 +1,10 @@
+//! Copilot Chat in the CLI. The modules of the binary are exposed as a library so they can be
+//! benchmarked.
+
+// The traits are only implemented and used in this crate
+#![allow(async_fn_in_trait)]
+
+pub mod chat;
+pub mod cli;
+pub mod client;
+pub mod tools;
@@ -1,33 +1,66 @@
-use chat::ChatStreamer;
+use anyhow::anyhow;
 use clap::Parser;
-use cli::commands::Cli;
+use copilot_chat::{
+    chat::{Chat, ChatStreamer, errors::ChatError},
+    cli::{
+        commands::{Cli, Command, ErrorFormat, ProviderKind},
+        handlers::{CommandHandler, ExecutionType},
+    },
+    client::{self, Backend, CopilotClient, CopilotEndpoints, EditorHeaders, RetryEvent, mock::MockProvider},
+    tools::{clipboard, git},
+};
 use std::io::{self, Read};
-use tools::cli::CliExecutor;
+use std::time::Duration;
 use tracing::debug;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
-use crate::cli::{
-    commands::Command,
-    handlers::{CommandHandler, ExecutionType},
-};
-
-mod chat;
-mod cli;
-mod client;
-mod tools;
+/// Exit code when a response exceeds `--deadline`, the same as `timeout(1)`
+const DEADLINE_EXIT_CODE: i32 = 124;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     init_logging()?;
     let cli = Cli::parse();
 
-    // Dependencies
-    let auth = client::auth::CopilotAuth::new();
-    let client = client::CopilotClient::new(auth);
-    let streamer = ChatStreamer;
-    let mut stdin_str = String::new();
+    if let Err(e) = run(&cli).await {
+        report_error(cli.error_format, &e);
+        let code = match e.downcast_ref::<ChatError>() {
+            Some(ChatError::Deadline { .. }) => DEADLINE_EXIT_CODE,
+            _ => 1,
+        };
+        std::process::exit(code);
+    }
+
+    Ok(())
+}
+
+async fn run(cli: &Cli) -> anyhow::Result<()> {
+    if cli.print_cache_path {
+        println!(
+            "{}",
+            Chat::<Backend>::chat_file_path(None, cli.thread.as_deref())?.display()
+        );
+        return Ok(());
+    }
+
+    // Dependencies, the mock provider doesn't need the credentials
+    let provider = match (cli.provider, &cli.mock_responses) {
+        (ProviderKind::Mock, Some(path)) => Backend::Mock(MockProvider::from_file(path)?),
+        (ProviderKind::Mock, None) => Backend::Mock(MockProvider::default()),
+        (ProviderKind::Copilot, Some(_)) => return Err(anyhow!("--mock-responses requires --provider mock")),
+        (ProviderKind::Copilot, None) => Backend::Copilot(Box::new(copilot_client(cli)?)),
+    };
     let is_tcp = matches!(cli.command, Some(Command::Tcp { port: _ }));
+    // Only the terminal is paced, the TCP clients and pipes read at full speed
+    let display_rate = cli.display_rate.filter(|_| !is_tcp && atty::is(atty::Stream::Stdout));
+    let streamer = ChatStreamer::new(cli.max_buffer_size)
+        .with_first_token_timeout(cli.first_token_timeout.map(Duration::from_secs))
+        .with_stats(cli.stats)
+        .with_lenient_parse(cli.lenient_parse)
+        .with_stop_sequences(cli.stop.clone())
+        .with_display_rate(display_rate);
+    let mut stdin_str = String::new();
 
     // Read only from piped stdin
     if !atty::is(atty::Stream::Stdin) && !is_tcp {
@@ -37,61 +70,281 @@ async fn main() -> anyhow::Result<()> {
         let mut stdin_buf = vec![];
         stdin.lock().read_to_end(&mut stdin_buf)?;
         stdin_str = String::from_utf8_lossy(&stdin_buf).to_string();
+
+        if cli.strip_ansi {
+            stdin_str = strip_ansi(&stdin_str);
+        }
+    }
+
+    // By default the piped stdin is the content and the args are the prompt
+    let stdin_prompt = if cli.stdin_as_prompt && !stdin_str.trim().is_empty() {
+        Some(std::mem::take(&mut stdin_str).trim().to_string())
+    } else {
+        None
+    };
+
+    if cli.attach_clipboard {
+        let clipboard = clipboard::read_text()?;
+
+        if stdin_str.is_empty() {
+            stdin_str = clipboard;
+        } else {
+            stdin_str = format!("{}\n\n{}", stdin_str, clipboard);
+        }
+    }
+
+    if let Some(name) = &cli.stdin_name
+        && !stdin_str.is_empty()
+    {
+        stdin_str = format!("File: {}\n\n{}", name, stdin_str);
     }
 
     debug! {%stdin_str, "Received"};
 
     // Parse the user prompt from CLI if exist
-    let user_prompt = cli.prompt.as_ref().map(|prompt| prompt.join(" "));
+    let user_prompt = match (cli.prompt.as_ref().map(|prompt| prompt.join(" ")), stdin_prompt) {
+        (Some(args), Some(stdin)) => Some(format!("{}\n\n{}", args, stdin)),
+        (args, stdin) => args.or(stdin),
+    };
 
     debug!(?user_prompt);
 
     // Resolve the commit stdin if it exists.
-    if matches!(cli.command, Some(Command::Commit)) {
-        if stdin_str.is_empty() {
-            stdin_str = CliExecutor::new().execute("git", &["diff", "--staged"]).await?;
+    if let Some(Command::Commit {
+        since,
+        diff_file,
+        unstaged,
+        ..
+    }) = &cli.command
+        && (stdin_str.is_empty() || diff_file.is_some())
+    {
+        match (diff_file, since) {
+            (Some(diff_file), _) => stdin_str = git::read_diff_file(diff_file)?,
+            (None, Some(since)) => {
+                stdin_str = git::branch_changes(since).await?;
+
+                if stdin_str.is_empty() {
+                    return Err(anyhow!("There are no changes between {} and HEAD.", since));
+                }
+            }
+            (None, None) if *unstaged => {
+                stdin_str = git::unstaged_diff().await?;
 
-            if stdin_str.is_empty() {
-                eprintln!("Git diff is empty. Ensure you are in a repository and that the changes are staged.");
-                std::process::exit(1);
+                if stdin_str.is_empty() {
+                    return Err(anyhow!("There are no unstaged changes."));
+                }
             }
+            (None, None) => {
+                stdin_str = git::staged_diff().await?;
+
+                if stdin_str.is_empty() {
+                    // A common mistake is to forget `git add`
+                    if !git::unstaged_diff().await.unwrap_or_default().is_empty() {
+                        return Err(anyhow!(
+                            "Nothing is staged, but you have unstaged changes; stage them or use --unstaged."
+                        ));
+                    }
+                    return Err(anyhow!(
+                        "Git diff is empty. Ensure you are in a repository and that the changes are staged."
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Some(Command::PrDescription { base }) = &cli.command
+        && stdin_str.is_empty()
+    {
+        stdin_str = git::branch_changes(base).await?;
+
+        if stdin_str.is_empty() {
+            return Err(anyhow!("There are no changes between {} and HEAD.", base));
+        }
+    }
+
+    if let Some(Command::Changelog { since }) = &cli.command
+        && stdin_str.is_empty()
+    {
+        stdin_str = git::commit_log(since.as_deref()).await?;
+
+        if stdin_str.is_empty() {
+            println!("There are no new commits; the changelog is empty.");
+            std::process::exit(0);
         }
     }
 
-    let mut handler = CommandHandler::new(&cli, user_prompt.as_deref());
-    let mut attr = handler.prepare(client).await?;
+    if matches!(cli.command, Some(Command::Git)) && user_prompt.is_none() && stdin_str.is_empty() {
+        return Err(anyhow!(
+            "Describe the Git task, e.g. `copilot-chat git how do I rebase onto main`."
+        ));
+    }
+
+    let mut handler = CommandHandler::new(cli, user_prompt.as_deref());
+    let mut attr = handler.prepare(provider).await?;
+
+    if cli.dump_messages {
+        return Ok(attr
+            .dump_messages(cli.model.as_deref(), (!stdin_str.is_empty()).then_some(stdin_str))
+            .await?);
+    }
 
     let writer = tokio::io::stdout();
 
     match attr.execution_type {
         ExecutionType::Once => {
-            if let Err(e) = attr
-                .process_request(&cli, streamer.clone(), writer, Some(stdin_str))
-                .await
-            {
-                eprintln!("Error: {}", e);
-            }
+            attr.process_request(
+                cli,
+                streamer.clone(),
+                writer,
+                (!stdin_str.is_empty()).then_some(stdin_str),
+            )
+            .await?;
+            attr.write_footer(cli);
         }
-        ExecutionType::Interactive => {
-            if let Err(e) = attr.process_loop(&cli, &streamer, writer, stdin_str).await {
-                eprintln!("Error: {}", e);
-            }
+        ExecutionType::Interactive => attr.process_loop(cli, &streamer, writer, stdin_str).await?,
+        ExecutionType::Resume => {
+            attr.resume_last(cli, streamer.clone(), writer).await?;
+            attr.write_footer(cli);
+        }
+        ExecutionType::Compact => {
+            let yes = matches!(cli.command, Some(Command::Compact { yes: true }));
+            attr.compact(cli, streamer.clone(), writer, yes).await?
         }
         ExecutionType::Exit => {
             std::process::exit(0);
         }
     }
 
+    let failed_files = attr.chat.failed_files();
+    if !failed_files.is_empty() {
+        return Err(anyhow!(
+            "{} file(s) could not be attached: {}",
+            failed_files.len(),
+            failed_files.join(", ")
+        ));
+    }
+
     Ok(())
 }
 
+/// Build the Copilot client with the credentials, endpoints and network settings of the CLI
+fn copilot_client(cli: &Cli) -> anyhow::Result<CopilotClient> {
+    let auth = match &cli.token_path {
+        Some(token_path) => client::auth::CopilotAuth::from_path(token_path)?,
+        None => client::auth::CopilotAuth::new(),
+    };
+    let editor = EditorHeaders::default();
+    let editor = EditorHeaders {
+        editor_version: cli.editor_version.clone().unwrap_or(editor.editor_version),
+        plugin_version: cli.plugin_version.clone().unwrap_or(editor.plugin_version),
+        integration_id: cli.integration_id.clone().unwrap_or(editor.integration_id),
+    };
+    let mut client = client::CopilotClient::new(auth)
+        .with_max_retries(cli.max_retries)
+        .with_editor_headers(editor)
+        .with_idempotency_header(cli.idempotency_header.clone())
+        .with_retry_notifier(notify_retry);
+    let mut endpoints = CopilotEndpoints::default();
+    if let Some(base) = &cli.api_base {
+        endpoints = endpoints.with_api_base(base);
+    }
+    if let Some(base) = &cli.github_api_base {
+        endpoints = endpoints.with_github_api_base(base);
+    }
+    client = client.with_endpoints(endpoints);
+    if let Some(proxy) = cli.proxy.clone() {
+        client = client.with_proxy(proxy)?;
+    }
+    Ok(client)
+}
+
+/// Remove the ANSI escape sequences (CSI, OSC and short escapes) and the control characters
+/// other than newlines and tabs from `text`
+fn strip_ansi(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI: parameters until the final byte, e.g. `\x1b[1;31m`
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // OSC: until BEL or the string terminator `\x1b\\`, e.g. hyperlinks
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                // Character set designations, e.g. `\x1b(B`
+                Some(' '..='/') => {
+                    chars.next();
+                }
+                _ => {}
+            },
+            '\n' | '\t' => result.push(c),
+            c if c.is_control() => {}
+            c => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// Let the user know that the request is waiting because of a rate limit
+fn notify_retry(event: &RetryEvent) {
+    eprintln!(
+        "Rate limited, retrying in {}s (attempt {}/{})",
+        event.delay.as_secs(),
+        event.attempt,
+        event.max_attempts
+    );
+}
+
+/// Print the error to stderr in the format requested by the user
+fn report_error(format: ErrorFormat, error: &anyhow::Error) {
+    match format {
+        ErrorFormat::Human => eprintln!("Error: {}", error),
+        ErrorFormat::Json => {
+            let kind = error.downcast_ref::<ChatError>().map_or("other", ChatError::kind);
+            let body = serde_json::json!({ "error": { "kind": kind, "message": error.to_string() } });
+            eprintln!("{}", body);
+        }
+    }
+}
+
 fn init_logging() -> std::io::Result<()> {
     let file = std::fs::File::create("/tmp/copilot-chat.log")?;
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new(
             std::env::var("RUST_LOG").unwrap_or_else(|_| "copilot_chat=info".into()),
         ))
-        .with(tracing_subscriber::fmt::layer().with_writer(file))
+        // The closed spans log their duration, a timeline of each request
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(file)
+                .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE),
+        )
         .init();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_ansi_sequences() {
+        let colored =
+            "\x1b[1;32mtest ok\x1b[0m\r\n\tdone\x07 \x1b]8;;https://example.com\x1b\\link\x1b]8;;\x1b\\\x1b(B";
+        assert_eq!(strip_ansi(colored), "test ok\n\tdone link");
+        assert_eq!(strip_ansi("plain text\n"), "plain text\n");
+    }
+}
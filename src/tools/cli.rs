@@ -1,6 +1,9 @@
-use tokio::process::Command;
+use std::process::Stdio;
+
+use tokio::{io::AsyncWriteExt, process::Command};
 
 /// Execute and handle command line executions
+#[derive(Default)]
 pub struct CliExecutor;
 
 impl CliExecutor {
@@ -18,4 +21,25 @@ impl CliExecutor {
 
         Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
     }
+
+    /// Execute a CLI command writing `input` to its stdin; the output goes directly to the terminal
+    pub async fn pipe(&self, command: &str, args: &[&str], input: &str) -> anyhow::Result<()> {
+        let mut child = Command::new(command).args(args).stdin(Stdio::piped()).spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            // The command can exit before reading everything, e.g. quitting a pager early
+            if let Err(e) = stdin.write_all(input.as_bytes()).await
+                && e.kind() != std::io::ErrorKind::BrokenPipe
+            {
+                return Err(e.into());
+            }
+        }
+
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(anyhow::anyhow!("Error executing command"));
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,18 @@
+/// Read the text content of the system clipboard
+#[cfg(feature = "clipboard")]
+pub fn read_text() -> anyhow::Result<String> {
+    let mut clipboard = arboard::Clipboard::new()
+        .map_err(|e| anyhow::anyhow!("Clipboard is not available, is there a display server running? {}", e))?;
+
+    clipboard
+        .get_text()
+        .map_err(|e| anyhow::anyhow!("Clipboard text cannot be read: {}", e))
+}
+
+/// Read the text content of the system clipboard
+#[cfg(not(feature = "clipboard"))]
+pub fn read_text() -> anyhow::Result<String> {
+    Err(anyhow::anyhow!(
+        "Clipboard support is not enabled, build with `--features clipboard`"
+    ))
+}
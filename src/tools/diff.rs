@@ -1,3 +1,4 @@
+use clap::ValueEnum;
 use std::fmt::Display;
 use std::time::Instant;
 use std::{collections::HashMap, ops::Index, str::Lines};
@@ -24,6 +25,36 @@ impl Display for Diff {
     }
 }
 
+/// How a difference is rendered
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum DiffStyle {
+    /// The change marker and the line number, e.g. `+ 12 line`
+    #[default]
+    Numbered,
+    /// `git`-style change marker without line numbers, e.g. `+line`
+    Unified,
+    /// Only the resulting lines, deletions are omitted
+    Plain,
+}
+
+impl Diff {
+    /// Render the difference with the given `style`, returns `None` if the style omits it
+    pub fn to_string_with(&self, style: DiffStyle) -> Option<String> {
+        match style {
+            DiffStyle::Numbered => Some(self.to_string()),
+            DiffStyle::Unified => Some(match self {
+                Self::Match((_, line)) => format!(" {line}"),
+                Self::Insert((_, line)) => format!("+{line}"),
+                Self::Delete((_, line)) => format!("-{line}"),
+            }),
+            DiffStyle::Plain => match self {
+                Self::Match((_, line)) | Self::Insert((_, line)) => Some(line.to_string()),
+                Self::Delete(_) => None,
+            },
+        }
+    }
+}
+
 /// Manage all the differences between two readable resources. Uses `Myers' Algorithm` to
 /// calculate the shortest path for retrieving the differences, storing all the results and their types.
 #[derive(Debug)]
@@ -41,13 +72,13 @@ impl DiffsManager {
         // Store states for backtracking
         let mut trace: Vec<SignedArray> = vec![];
 
-        let mut v = SignedArray::new(max as usize);
+        let mut v = SignedArray::new(max as usize + 1);
         v.set(1, 0);
 
         let mut final_d = 0;
 
         'outer: for d in 0..=max {
-            let mut v_current = SignedArray::new(max as usize);
+            let mut v_current = SignedArray::new(max as usize + 1);
 
             for k in (-d..=d).step_by(2) {
                 let x_start = if k == -d || (k != d && v.get(k - 1) < v.get(k + 1)) {
@@ -113,6 +144,13 @@ impl DiffsManager {
             }
         }
 
+        // The common lines at the start of the sequences
+        while x > 0 && y > 0 {
+            edits.push(Diff::Match((x, seq1.lines[x - 1].to_string())));
+            x -= 1;
+            y -= 1;
+        }
+
         edits.reverse();
 
         debug!(
@@ -126,6 +164,109 @@ impl DiffsManager {
     }
 }
 
+impl DiffsManager {
+    /// Group the changes in hunks, each one including up to `context` unchanged lines before and
+    /// after the changes. Hunks whose context overlaps are merged.
+    pub fn hunks(&self, context: usize) -> Vec<&[Diff]> {
+        self.hunk_ranges(context)
+            .into_iter()
+            .map(|(start, end)| &self.diffs[start..end])
+            .collect()
+    }
+
+    /// The bounds of the hunks in the differences, see [`Self::hunks`]
+    fn hunk_ranges(&self, context: usize) -> Vec<(usize, usize)> {
+        let mut hunks = vec![];
+        let mut current: Option<(usize, usize)> = None;
+
+        for (i, diff) in self.diffs.iter().enumerate() {
+            if let Diff::Match(_) = diff {
+                continue;
+            }
+
+            let start = i.saturating_sub(context);
+            let end = (i + context + 1).min(self.diffs.len());
+
+            current = match current {
+                Some((hunk_start, hunk_end)) if start <= hunk_end => Some((hunk_start, end.max(hunk_end))),
+                Some(hunk) => {
+                    hunks.push(hunk);
+                    Some((start, end))
+                }
+                None => Some((start, end)),
+            };
+        }
+
+        if let Some(hunk) = current {
+            hunks.push(hunk);
+        }
+
+        hunks
+    }
+
+    /// Render the hunks of the changes with the given `style`, one line per difference and a `...`
+    /// line between hunks.
+    pub fn to_string_with(&self, style: DiffStyle, context: usize) -> String {
+        let mut content = String::new();
+
+        for (i, hunk) in self.hunks(context).iter().enumerate() {
+            if i > 0 {
+                content.push_str("...\n");
+            }
+
+            for line in hunk.iter().filter_map(|diff| diff.to_string_with(style)) {
+                content.push_str(&line);
+                content.push('\n');
+            }
+        }
+
+        content
+    }
+
+    /// Render the changes as the hunks of a unified diff, with their `@@ -a,b +c,d @@` headers,
+    /// so they can be applied with `git apply` or `patch`
+    pub fn to_patch_hunks(&self, context: usize) -> String {
+        // Lines of each sequence before every difference
+        let mut positions = Vec::with_capacity(self.diffs.len() + 1);
+        let (mut old, mut new) = (0, 0);
+        for diff in &self.diffs {
+            positions.push((old, new));
+            match diff {
+                Diff::Match(_) => (old, new) = (old + 1, new + 1),
+                Diff::Delete(_) => old += 1,
+                Diff::Insert(_) => new += 1,
+            }
+        }
+        positions.push((old, new));
+
+        let mut content = String::new();
+        for (start, end) in self.hunk_ranges(context) {
+            let (old_start, new_start) = positions[start];
+            let (old_end, new_end) = positions[end];
+            let (old_len, new_len) = (old_end - old_start, new_end - new_start);
+            // An empty range points to the line before it
+            let header_start = |start: usize, len: usize| if len == 0 { start } else { start + 1 };
+
+            content.push_str(&format!(
+                "@@ -{},{} +{},{} @@\n",
+                header_start(old_start, old_len),
+                old_len,
+                header_start(new_start, new_len),
+                new_len
+            ));
+            for line in self.diffs[start..end]
+                .iter()
+                .filter_map(|diff| diff.to_string_with(DiffStyle::Unified))
+            {
+                content.push_str(&line);
+                content.push('\n');
+            }
+        }
+
+        content
+    }
+}
+
 /// The lines range of the file
 #[derive(Debug, Clone)]
 pub struct Range {
@@ -287,7 +428,7 @@ additional line
         let (seq1, seq2) = LineSequence::from_lines(lines1, lines2);
         let diffs = DiffsManager::from_myers_algorithm(seq1, seq2);
 
-        let expected = vec![
+        let expected = [
             Diff::Delete((1, "hello, this is a test".to_string())),
             Diff::Insert((1, "bye, this is a test".to_string())),
             Diff::Match((2, "and this is a Myers' Algorithm".to_string())),
@@ -304,4 +445,78 @@ additional line
             assert_eq!(*edit, expected[i]);
         }
     }
+
+    #[test]
+    fn myers_empty_contents() {
+        // The initial state is stored in the diagonal 1, beyond `max` when both contents are empty
+        let (seq1, seq2) = LineSequence::from_lines("".lines(), "".lines());
+        assert!(DiffsManager::from_myers_algorithm(seq1, seq2).diffs.is_empty());
+
+        let (seq1, seq2) = LineSequence::from_lines("".lines(), "a\n".lines());
+        let diffs = DiffsManager::from_myers_algorithm(seq1, seq2);
+        assert_eq!(diffs.diffs, [Diff::Insert((1, "a".to_string()))]);
+    }
+
+    #[test]
+    fn myers_leading_matches() {
+        // The backtrack stops at the end of the first snake, the lines before it are also matches
+        let (seq1, seq2) = LineSequence::from_lines("a\nb\nc\n".lines(), "a\nb\nC\n".lines());
+        let diffs = DiffsManager::from_myers_algorithm(seq1, seq2);
+
+        assert_eq!(
+            diffs.diffs,
+            [
+                Diff::Match((1, "a".to_string())),
+                Diff::Match((2, "b".to_string())),
+                Diff::Delete((3, "c".to_string())),
+                Diff::Insert((3, "C".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn hunks_with_context() {
+        let str1 = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let str2 = "A\nb\nc\nd\ne\nf\ng\nh\ni\nJ\n";
+
+        let (seq1, seq2) = LineSequence::from_lines(str1.lines(), str2.lines());
+        let diffs = DiffsManager::from_myers_algorithm(seq1, seq2);
+
+        // Two separated changes at the edges of the file
+        let hunks = diffs.hunks(1);
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(hunks[0].len(), 3);
+        assert_eq!(hunks[0][2], Diff::Match((2, "b".to_string())));
+        assert_eq!(hunks[1].len(), 3);
+        assert_eq!(hunks[1][0], Diff::Match((9, "i".to_string())));
+
+        // Without context only the changes are kept
+        assert!(
+            diffs
+                .hunks(0)
+                .iter()
+                .flat_map(|hunk| hunk.iter())
+                .all(|diff| !matches!(diff, Diff::Match(_)))
+        );
+
+        assert_eq!(
+            diffs.to_patch_hunks(1),
+            "@@ -1,2 +1,2 @@\n-a\n+A\n b\n@@ -9,2 +9,2 @@\n i\n-j\n+J\n"
+        );
+
+        assert_eq!(
+            diffs.to_string_with(DiffStyle::Numbered, 0),
+            "- 1 a\n+ 1 A\n...\n- 10 j\n+ 10 J\n"
+        );
+        assert_eq!(
+            diffs.to_string_with(DiffStyle::Unified, 1),
+            "-a\n+A\n b\n...\n i\n-j\n+J\n"
+        );
+        assert_eq!(diffs.to_string_with(DiffStyle::Plain, 0), "A\n...\nJ\n");
+
+        // The context overlaps, so the hunks are merged
+        let hunks = diffs.hunks(4);
+        assert_eq!(hunks.len(), 1);
+        assert_eq!(hunks[0].len(), diffs.diffs.len());
+    }
 }
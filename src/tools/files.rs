@@ -1,10 +1,13 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
-use super::diff::Range;
+use super::diff::{DiffStyle, Range};
+use super::git;
 
 use super::reader::{Readable, ReaderTool};
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use tracing::{debug, warn};
 
 impl Readable for TrackedFile {
     fn location(&self) -> &str {
@@ -48,7 +51,156 @@ impl Default for TrackedFile {
     }
 }
 
-pub struct FileReader;
+/// Source of the current time, replaceable to control the modification times in tests
+pub type Clock = fn() -> SystemTime;
+
+/// Default number of unchanged lines sent around each change of a file
+pub const DEFAULT_DIFF_CONTEXT: usize = 3;
+
+/// Default max number of files attached in a request
+pub const DEFAULT_MAX_FILES: usize = 50;
+
+/// Default lines kept from the end of a truncated file
+pub const DEFAULT_TAIL_LINES: usize = 20;
+
+/// Lines kept from the start and the end of a file that is longer than both together
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Truncation {
+    pub head: usize,
+    pub tail: usize,
+}
+
+impl Truncation {
+    /// Keep at most `max_lines`, `tail` of them from the end of the file
+    pub fn new(max_lines: usize, tail: usize) -> Self {
+        let tail = tail.min(max_lines);
+        Self {
+            head: max_lines - tail,
+            tail,
+        }
+    }
+
+    /// Replace the middle lines of `content` with a marker if it exceeds the limit
+    pub fn apply(&self, content: &str) -> String {
+        let lines: Vec<&str> = content.lines().collect();
+        if lines.len() <= self.head + self.tail {
+            return content.to_string();
+        }
+
+        let omitted = lines.len() - self.head - self.tail;
+        let mut truncated = String::new();
+        for line in &lines[..self.head] {
+            truncated.push_str(line);
+            truncated.push('\n');
+        }
+        truncated.push_str(&format!("... [{} lines omitted] ...\n", omitted));
+        for line in &lines[lines.len() - self.tail..] {
+            truncated.push_str(line);
+            truncated.push('\n');
+        }
+
+        truncated
+    }
+}
+
+/// Print the numbered content of `readable` and ask for the range to attach, `None` means the
+/// whole file
+pub async fn ask_range(readable: &impl Readable) -> std::io::Result<Option<Range>> {
+    println!("{}", readable.add_line_numbers());
+    print!(
+        "Range of {} to attach (start-end, empty for the whole file): ",
+        readable.location()
+    );
+    std::io::stdout().flush()?;
+
+    // Read in a blocking thread, the runtime keeps running the other tasks meanwhile
+    let answer = tokio::task::spawn_blocking(|| {
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).map(|_| answer)
+    })
+    .await??;
+    let range = parse_range(&answer);
+    if range.is_none() && !answer.trim().is_empty() {
+        warn!(%answer, "Invalid range, attaching the whole file");
+    }
+    Ok(range)
+}
+
+/// Parse a `start-end` range typed by the user, the end can be omitted
+fn parse_range(answer: &str) -> Option<Range> {
+    let (start, end) = answer.trim().split_once('-')?;
+    let start = start.trim().parse().ok()?;
+    let end = match end.trim() {
+        "" => 0,
+        end => end.parse().ok()?,
+    };
+    Some(Range { start, end })
+}
+
+/// Settings for attaching files to the chat
+#[derive(Debug, Clone)]
+pub struct FileOptions {
+    /// Unchanged lines surrounding each change when sending the updates of a file
+    pub diff_context: usize,
+    /// Format of the changes sent when a file is updated
+    pub diff_style: DiffStyle,
+    /// Time used when a file has no modification time, e.g. it does not exist
+    pub clock: Clock,
+    /// Levels of the local includes of the files that are also attached, if any
+    pub follow_includes: Option<usize>,
+    /// Max number of files attached in a request, the includes count
+    pub max_files: usize,
+    /// Skip the files that cannot be attached instead of failing the request
+    pub keep_going: bool,
+    /// Lines kept of the long files, all of them if not set
+    pub truncation: Option<Truncation>,
+    /// Prefix each line of the attached files with its number
+    pub line_numbers: bool,
+    /// File or directory names skipped when a directory is attached
+    pub exclude: Vec<String>,
+    /// Extensions of the files included when a directory is attached, all of them if empty
+    pub extensions: Vec<String>,
+    /// Ask in the terminal for the range of the files attached without one
+    pub interactive_range: bool,
+    /// Directory whose tree is attached before the files of a code request, if any
+    pub file_tree: Option<PathBuf>,
+}
+
+impl Default for FileOptions {
+    fn default() -> Self {
+        Self {
+            diff_context: DEFAULT_DIFF_CONTEXT,
+            diff_style: DiffStyle::default(),
+            clock: SystemTime::now,
+            follow_includes: None,
+            max_files: DEFAULT_MAX_FILES,
+            keep_going: false,
+            truncation: None,
+            line_numbers: true,
+            exclude: vec![],
+            extensions: vec![],
+            interactive_range: false,
+            file_tree: None,
+        }
+    }
+}
+
+pub struct FileReader {
+    clock: Clock,
+}
+
+impl Default for FileReader {
+    fn default() -> Self {
+        Self { clock: SystemTime::now }
+    }
+}
+
+impl FileReader {
+    /// A reader that takes the current time from `clock`
+    pub fn new(clock: Clock) -> Self {
+        Self { clock }
+    }
+}
 
 impl ReaderTool for FileReader {
     async fn read<'a>(&self, readable: &'a mut impl Readable) -> anyhow::Result<&'a str> {
@@ -56,10 +208,14 @@ impl ReaderTool for FileReader {
 
         // If the file doesn't exist, we don't want to fail, because maybe the file
         // is deleted, return an empty string instead
-        let content = std::fs::read_to_string(file_path).unwrap_or_else(|_| {
-            debug!(%file_path, "File not found, setting an empty string");
-            String::new()
-        });
+        let content = match std::fs::read_to_string(file_path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!(%file_path, "File not found, setting an empty string");
+                String::new()
+            }
+            Err(e) => return Err(anyhow::anyhow!("cannot read {}: {}", file_path, e)),
+        };
 
         debug!(?file_path, "Updating content");
         readable.set_content(content);
@@ -68,13 +224,141 @@ impl ReaderTool for FileReader {
         self.update_modified_time(readable).unwrap_or_else(|_| {
             debug!("File not found, updating the modified time to now");
             // Update this because the file is "up to date" with empty content
-            readable.set_modified_time(SystemTime::now());
+            readable.set_modified_time((self.clock)());
         });
 
         Ok(readable.content())
     }
 }
 
+/// The content of a `readable` marked to be loaded once, formatted following `file_options`
+pub fn load_once_content(readable: &impl Readable, file_options: &FileOptions) -> String {
+    let mut content = if file_options.line_numbers {
+        readable.add_line_numbers()
+    } else {
+        readable.content().to_string()
+    };
+    if let Some(truncation) = &file_options.truncation {
+        content = truncation.apply(&content);
+    }
+    format!("File: {} [load-once]\n\n{}", readable.location(), content)
+}
+
+/// Concatenate the text files of `dir`, recursively and sorted by path, into one document with a
+/// marker before each file. The names in the excludes, the hidden entries, the `ignored` paths
+/// (relative to `dir`) and the files without one of the `extensions` are skipped, and at most
+/// `max_files` files are included.
+pub fn directory_document(dir: &Path, file_options: &FileOptions, ignored: &[String]) -> std::io::Result<String> {
+    let mut files = vec![];
+    collect_files(dir, &file_options.exclude, &mut files)?;
+    files.retain(|path| {
+        let relative = path.strip_prefix(dir).unwrap_or(path);
+        has_extension(path, &file_options.extensions) && !git::is_ignored(ignored, &relative.to_string_lossy())
+    });
+    files.sort();
+
+    if files.len() > file_options.max_files {
+        warn!(
+            count = files.len(),
+            max = file_options.max_files,
+            "Too many files in the directory"
+        );
+        files.truncate(file_options.max_files);
+    }
+
+    let mut document = format!("Directory: {} [load-once]\n", dir.display());
+    for path in files {
+        // Binary files are not useful as context
+        let Ok(content) = std::fs::read_to_string(&path) else {
+            debug!(?path, "Skipping non-text file");
+            continue;
+        };
+
+        let mut file = TrackedFile::new(Some(path.display().to_string()));
+        file.set_content(content);
+        let mut content = if file_options.line_numbers {
+            file.add_line_numbers()
+        } else {
+            file.content
+        };
+        if let Some(truncation) = &file_options.truncation {
+            content = truncation.apply(&content);
+        }
+
+        document.push_str(&format!("\n=== File: {} ===\n{}", path.display(), content));
+    }
+
+    Ok(document)
+}
+
+/// Max entries of the tree attached with `--prepend-file-tree`
+const MAX_TREE_FILES: usize = 500;
+
+/// A compact tree of the `files` of `root`, one entry per line indented by depth; the directories end
+/// with `/`. At most [`MAX_TREE_FILES`] files are listed.
+pub fn file_tree(root: &Path, files: &[PathBuf]) -> String {
+    let mut files: Vec<&Path> = files
+        .iter()
+        .map(|file| file.strip_prefix(root).unwrap_or(file))
+        .collect();
+    files.sort();
+
+    let mut tree = format!("Tree: {} [load-once]\n", root.display());
+    let mut parents: Vec<String> = vec![];
+    for file in files.iter().take(MAX_TREE_FILES) {
+        let components: Vec<String> = file
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().to_string())
+            .collect();
+        let Some((name, dirs)) = components.split_last() else {
+            continue;
+        };
+
+        // Only the directories that differ from the previous file are listed
+        let common = parents.iter().zip(dirs).take_while(|(a, b)| a == b).count();
+        for (depth, dir) in dirs.iter().enumerate().skip(common) {
+            tree.push_str(&format!("\n{}{}/", "  ".repeat(depth), dir));
+        }
+        tree.push_str(&format!("\n{}{}", "  ".repeat(dirs.len()), name));
+        parents = dirs.to_vec();
+    }
+    if files.len() > MAX_TREE_FILES {
+        tree.push_str(&format!("\n... [{} more files]", files.len() - MAX_TREE_FILES));
+    }
+
+    tree
+}
+
+/// Whether the extension of `path` is one of `extensions`, given with or without the dot; any file
+/// matches if there are no extensions
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    extensions.is_empty()
+        || path.extension().is_some_and(|ext| {
+            extensions
+                .iter()
+                .any(|expected| expected.trim_start_matches('.') == ext.to_string_lossy())
+        })
+}
+
+fn collect_files(dir: &Path, exclude: &[String], files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with('.') || exclude.iter().any(|ex| *ex == name) {
+            continue;
+        }
+
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), exclude, files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
 impl TrackedFile {
     /// Get a new `FileReader`
     #[allow(dead_code)]
@@ -92,7 +376,8 @@ impl TrackedFile {
 
     /// Get the clean file path by removing the range if it exists; if there is no range,
     /// returns the argument itself. e.g. /path/to/file:10-20 -> /path/to/file
-    pub fn from_file_arg(arg: &str) -> Self {
+    /// If the file does not exist, the modification time is taken from `clock`.
+    pub fn from_file_arg(arg: &str, clock: Clock) -> Self {
         let path = if let Some((path, _)) = arg.split_once(':') {
             path.to_string()
         } else {
@@ -102,7 +387,7 @@ impl TrackedFile {
         let last_modification = std::fs::metadata(&path)
             .ok()
             .and_then(|meta| meta.modified().ok())
-            .unwrap_or_else(SystemTime::now);
+            .unwrap_or_else(clock);
 
         Self {
             path,
@@ -113,11 +398,11 @@ impl TrackedFile {
 
     /// Prepare all the necesary data for copilot
     /// - Read the file
-    /// - Add the line number for each line
+    /// - Add the line number for each line, unless disabled in `file_options`
     /// - Add the file name and indicate the range selected by the user
-    pub async fn prepare_load_once(&self) -> anyhow::Result<String> {
-        let numbered = self.add_line_numbers();
-        Ok(format!("File: {} [load-once]\n\n{}", self.path, numbered))
+    /// - Truncate the long files, the numbers keep referencing the original lines
+    pub async fn prepare_load_once(&self, file_options: &FileOptions) -> anyhow::Result<String> {
+        Ok(load_once_content(self, file_options))
     }
 
     /// Prepare the necesary data for copilot
@@ -134,10 +419,28 @@ impl TrackedFile {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tools::diff::Diff;
     use std::fs::File;
     use std::io::Write;
     use std::sync::atomic::{AtomicUsize, Ordering};
 
+    #[test]
+    fn render_file_tree() {
+        let root = Path::new("/project");
+        let files = [
+            "/project/src/main.rs",
+            "/project/Cargo.toml",
+            "/project/src/chat/core.rs",
+            "/project/src/lib.rs",
+        ]
+        .map(PathBuf::from);
+
+        assert_eq!(
+            file_tree(root, &files),
+            "Tree: /project [load-once]\n\nCargo.toml\nsrc/\n  chat/\n    core.rs\n  lib.rs\n  main.rs"
+        );
+    }
+
     static COUNTER: AtomicUsize = AtomicUsize::new(0);
 
     #[derive(Debug, Clone)]
@@ -163,7 +466,7 @@ mod tests {
         fn location(&self) -> &str {
             let mut file = File::create(self.path.clone()).expect("create the file");
 
-            file.write("Hello\nWelcome to Copilot\nTell me something\n".as_bytes())
+            file.write_all("Hello\nWelcome to Copilot\nTell me something\n".as_bytes())
                 .expect("write to the file");
 
             &self.path
@@ -177,7 +480,7 @@ mod tests {
             &self._timemod
         }
 
-        fn content<'a>(&'a self) -> &'a str {
+        fn content(&self) -> &str {
             &self.content
         }
 
@@ -188,7 +491,7 @@ mod tests {
 
     #[tokio::test]
     async fn numbered_lines() {
-        let reader = FileReader;
+        let reader = FileReader::default();
         let mut readable = MockFile::new_unique();
         let _ = reader.read(&mut readable).await.expect("read the file");
         let numbered = readable.add_line_numbers();
@@ -198,6 +501,111 @@ mod tests {
         std::fs::remove_file(readable.location()).expect("cleanup the file");
     }
 
+    #[tokio::test]
+    async fn diffs_follow_the_clock() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let path = dir.path().join("tracked.rs");
+        let path_str = path.to_str().expect("valid path");
+
+        // The file does not exist yet, so the modification times come from the clock
+        let past = || SystemTime::UNIX_EPOCH;
+        let reader = FileReader::new(past);
+        let mut tracked = TrackedFile::from_file_arg(path_str, past);
+        reader.read(&mut tracked).await.expect("read the file");
+        assert_eq!(*tracked.modified_time(), SystemTime::UNIX_EPOCH);
+
+        std::fs::write(&path, "fn main() {}\n").expect("write the file");
+        let diffs = reader.get_diffs(&tracked).expect("compute the diffs");
+        assert_eq!(
+            diffs.expect("file is newer than the clock").diffs,
+            vec![Diff::Insert((1, "fn main() {}".to_string()))]
+        );
+
+        // A clock in the future marks the missing file as up to date
+        std::fs::remove_file(&path).expect("remove the file");
+        let future = || SystemTime::now() + std::time::Duration::from_secs(3600);
+        let reader = FileReader::new(future);
+        let mut tracked = TrackedFile::from_file_arg(path_str, future);
+        reader.read(&mut tracked).await.expect("read the file");
+
+        std::fs::write(&path, "fn main() {}\n").expect("write the file");
+        assert!(reader.get_diffs(&tracked).expect("compute the diffs").is_none());
+    }
+
+    #[test]
+    fn parse_typed_range() {
+        let range = parse_range("10-20\n").expect("valid range");
+        assert_eq!((range.start, range.end), (10, 20));
+        let range = parse_range(" 5- ").expect("open range");
+        assert_eq!((range.start, range.end), (5, 0));
+        assert!(parse_range("\n").is_none());
+        assert!(parse_range("a-b").is_none());
+    }
+
+    #[test]
+    fn truncate_keeps_head_and_tail() {
+        let content: String = (1..=10).map(|i| format!("line {i}\n")).collect();
+
+        let truncated = Truncation::new(5, 2).apply(&content);
+        assert_eq!(
+            truncated,
+            "line 1\nline 2\nline 3\n... [5 lines omitted] ...\nline 9\nline 10\n"
+        );
+
+        // Short files are kept as they are
+        assert_eq!(Truncation::new(10, 2).apply(&content), content);
+    }
+
+    #[test]
+    fn concatenate_directory() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        std::fs::create_dir_all(dir.path().join("inner")).expect("create the inner dir");
+        std::fs::create_dir_all(dir.path().join("target")).expect("create the target dir");
+        std::fs::write(dir.path().join("b.rs"), "fn b() {}\n").expect("write b");
+        std::fs::write(dir.path().join("inner/a.rs"), "fn a() {}\n").expect("write a");
+        std::fs::write(dir.path().join("target/skip.rs"), "skip\n").expect("write skip");
+        std::fs::write(dir.path().join(".hidden"), "skip\n").expect("write hidden");
+
+        let file_options = FileOptions {
+            exclude: vec!["target".to_string()],
+            ..Default::default()
+        };
+        let document = directory_document(dir.path(), &file_options, &[]).expect("read the directory");
+        let root = dir.path().display();
+
+        assert_eq!(
+            document,
+            format!(
+                "Directory: {root} [load-once]\n\n=== File: {root}/b.rs ===\n1: fn b() {{}}\n\n=== File: {root}/inner/a.rs ===\n1: fn a() {{}}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn filter_directory_files() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        std::fs::create_dir_all(dir.path().join("generated")).expect("create the generated dir");
+        std::fs::write(dir.path().join("lib.rs"), "fn lib() {}\n").expect("write lib");
+        std::fs::write(dir.path().join("Cargo.toml"), "[package]\n").expect("write manifest");
+        std::fs::write(dir.path().join("notes.md"), "skip\n").expect("write notes");
+        std::fs::write(dir.path().join("generated/out.rs"), "skip\n").expect("write generated");
+
+        let file_options = FileOptions {
+            extensions: vec!["rs".to_string(), ".toml".to_string()],
+            ..Default::default()
+        };
+        let document =
+            directory_document(dir.path(), &file_options, &["generated/".to_string()]).expect("read the directory");
+        let root = dir.path().display();
+
+        assert_eq!(
+            document,
+            format!(
+                "Directory: {root} [load-once]\n\n=== File: {root}/Cargo.toml ===\n1: [package]\n\n=== File: {root}/lib.rs ===\n1: fn lib() {{}}\n"
+            )
+        );
+    }
+
     #[test]
     fn extract_range() {
         let range = Range::from_file_arg("/path/to/file:20-30");
@@ -212,27 +620,50 @@ mod tests {
     async fn prepare_once() {
         let mut readable = MockFile::new_unique();
         let mut file_tracked = TrackedFile::new(None);
-        let reader = FileReader;
+        let reader = FileReader::default();
         reader.read(&mut readable).await.expect("read the file");
 
         file_tracked.set_content(readable.content.clone());
         file_tracked.path = readable.location().into();
 
-        let prepared = file_tracked.prepare_load_once().await.expect("prepare the request");
+        let prepared = file_tracked
+            .prepare_load_once(&FileOptions::default())
+            .await
+            .expect("prepare the request");
 
         assert_eq!(
             prepared,
-            format!("File: {} [load-once]\n\n1: Hello\n2: Welcome to Copilot\n3: Tell me something\n", readable.location())
+            format!(
+                "File: {} [load-once]\n\n1: Hello\n2: Welcome to Copilot\n3: Tell me something\n",
+                readable.location()
+            )
         );
 
         std::fs::remove_file(readable.location()).expect("cleanup the file");
     }
 
+    #[tokio::test]
+    async fn prepare_once_without_line_numbers() {
+        let mut file_tracked = TrackedFile::new(Some("main.rs".to_string()));
+        file_tracked.set_content("fn main() {}\n".to_string());
+
+        let file_options = FileOptions {
+            line_numbers: false,
+            ..Default::default()
+        };
+        let prepared = file_tracked
+            .prepare_load_once(&file_options)
+            .await
+            .expect("prepare the request");
+
+        assert_eq!(prepared, "File: main.rs [load-once]\n\nfn main() {}\n");
+    }
+
     #[tokio::test]
     async fn prepare_copilot() {
         let mut readable = MockFile::new_unique();
         let mut file_tracked = TrackedFile::new(None);
-        let reader = FileReader;
+        let reader = FileReader::default();
 
         reader.read(&mut readable).await.expect("read the file");
 
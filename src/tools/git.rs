@@ -0,0 +1,124 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+
+use super::cli::CliExecutor;
+
+/// Retrieve the staged changes of the current repository
+pub async fn staged_diff() -> anyhow::Result<String> {
+    CliExecutor::new().execute("git", &["diff", "--staged"]).await
+}
+
+/// Retrieve the changes of the working tree that are not staged
+pub async fn unstaged_diff() -> anyhow::Result<String> {
+    CliExecutor::new().execute("git", &["diff"]).await
+}
+
+/// The untracked paths of `root` that git ignores, relative to it; the ignored directories end
+/// with `/`
+pub async fn ignored_paths(root: &Path) -> anyhow::Result<Vec<String>> {
+    let root = root.to_string_lossy();
+    let ignored = CliExecutor::new()
+        .execute(
+            "git",
+            &[
+                "-C",
+                &root,
+                "ls-files",
+                "--others",
+                "--ignored",
+                "--exclude-standard",
+                "--directory",
+            ],
+        )
+        .await?;
+
+    Ok(ignored.lines().map(|line| line.to_string()).collect())
+}
+
+/// Whether the `relative` path is one of the `ignored` paths or inside one of the ignored directories
+pub fn is_ignored(ignored: &[String], relative: &str) -> bool {
+    ignored
+        .iter()
+        .any(|path| *path == relative || (path.ends_with('/') && relative.starts_with(path.as_str())))
+}
+
+/// Read a diff from `path`, e.g. a patch produced by `git diff` in a previous CI step. Fails if
+/// the file is empty or it does not look like a unified diff.
+pub fn read_diff_file(path: &Path) -> anyhow::Result<String> {
+    let diff = std::fs::read_to_string(path).map_err(|e| anyhow!("cannot read {}: {}", path.display(), e))?;
+
+    if diff.trim().is_empty() {
+        return Err(anyhow!("The diff file {} is empty.", path.display()));
+    }
+
+    let is_diff = diff.lines().any(|line| {
+        line.starts_with("diff --git") || line.starts_with("--- ") || line.starts_with("+++ ") || line.starts_with("@@")
+    });
+    if !is_diff {
+        return Err(anyhow!("{} is not a unified diff.", path.display()));
+    }
+
+    Ok(diff)
+}
+
+/// Retrieve the commit log and the diff of the current branch since it diverged from `base`.
+/// Returns an empty string if there are no changes.
+pub async fn branch_changes(base: &str) -> anyhow::Result<String> {
+    let executor = CliExecutor::new();
+    let log = executor
+        .execute("git", &["log", "--format=%h %s%n%b", &format!("{}..HEAD", base)])
+        .await?;
+    let diff = executor.execute("git", &["diff", &format!("{}...HEAD", base)]).await?;
+
+    if log.is_empty() && diff.is_empty() {
+        return Ok(String::new());
+    }
+
+    Ok(format!("Commits:\n{}\n\nDiff:\n{}", log, diff))
+}
+
+/// Retrieve the commit log since `since`, or since the latest tag if it is not provided. If the
+/// repository has no tags, the whole history is returned.
+pub async fn commit_log(since: Option<&str>) -> anyhow::Result<String> {
+    let executor = CliExecutor::new();
+    let since = match since {
+        Some(since) => Some(since.to_string()),
+        None => executor
+            .execute("git", &["describe", "--tags", "--abbrev=0"])
+            .await
+            .ok(),
+    };
+
+    let range = since.map(|since| format!("{}..HEAD", since));
+    let mut args = vec!["log", "--format=%h %s%n%b"];
+    if let Some(range) = &range {
+        args.push(range);
+    }
+
+    executor.execute("git", &args).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_diff_file() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+
+        let patch = dir.path().join("changes.patch");
+        std::fs::write(&patch, "diff --git a/main.rs b/main.rs\n@@ -1 +1 @@\n-old\n+new\n").expect("write the patch");
+        assert!(read_diff_file(&patch).expect("valid diff").contains("+new"));
+
+        let empty = dir.path().join("empty.patch");
+        std::fs::write(&empty, "\n").expect("write the patch");
+        assert!(read_diff_file(&empty).is_err());
+
+        let text = dir.path().join("notes.txt");
+        std::fs::write(&text, "some notes").expect("write the file");
+        assert!(read_diff_file(&text).is_err());
+
+        assert!(read_diff_file(&dir.path().join("missing.patch")).is_err());
+    }
+}
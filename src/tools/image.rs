@@ -0,0 +1,83 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// An image attached to a request for a vision-capable model
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Image {
+    pub path: String,
+    /// MIME type, e.g. `image/png`
+    pub media_type: String,
+    /// Persisted in base64, as it is sent
+    #[serde(serialize_with = "serialize_base64", deserialize_with = "deserialize_base64")]
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// Read the image in `path`, the media type is inferred from its extension
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase);
+        let media_type = match extension.as_deref() {
+            Some("png") => "image/png",
+            Some("jpg" | "jpeg") => "image/jpeg",
+            Some("gif") => "image/gif",
+            Some("webp") => "image/webp",
+            _ => return Err(anyhow!("{} is not a supported image (png, jpeg, gif or webp)", path)),
+        };
+
+        let data = std::fs::read(path).map_err(|e| anyhow!("cannot read the image {}: {}", path, e))?;
+        Ok(Self {
+            path: path.to_string(),
+            media_type: media_type.to_string(),
+            data,
+        })
+    }
+
+    /// The image encoded as a `data:` URL, as expected by the content parts of a message
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, STANDARD.encode(&self.data))
+    }
+}
+
+fn serialize_base64<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(data))
+}
+
+fn deserialize_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+}
+
+/// The data is omitted, it can be large
+impl std::fmt::Debug for Image {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Image")
+            .field("path", &self.path)
+            .field("media_type", &self.media_type)
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_image() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let path = dir.path().join("pixel.PNG");
+        std::fs::write(&path, b"\x89PNG").expect("write the image");
+
+        let image = Image::load(path.to_str().expect("utf-8 path")).expect("load the image");
+        assert_eq!(image.media_type, "image/png");
+        assert_eq!(image.data_url(), "data:image/png;base64,iVBORw==");
+
+        assert!(Image::load("notes.txt").is_err());
+    }
+}
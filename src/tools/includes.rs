@@ -0,0 +1,171 @@
+//! Discover the local files referenced by the imports of the attached files
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use tracing::debug;
+
+/// Add to `files` the local files they include, following the includes up to `depth` levels.
+/// Rust `mod` declarations and Python imports are recognized; other files are kept as they are.
+pub fn follow_includes(files: &[String], depth: usize) -> Vec<String> {
+    let mut result = files.to_vec();
+    let mut seen: HashSet<PathBuf> = files.iter().map(|file| PathBuf::from(strip_range(file))).collect();
+    let mut pending: Vec<PathBuf> = seen.iter().cloned().collect();
+
+    for _ in 0..depth {
+        let mut next = vec![];
+        for path in &pending {
+            let Ok(content) = std::fs::read_to_string(path) else {
+                continue;
+            };
+
+            for include in local_includes(path, &content) {
+                if seen.insert(include.clone()) {
+                    debug!(?include, from=?path, "Following include");
+                    result.push(include.to_string_lossy().to_string());
+                    next.push(include);
+                }
+            }
+        }
+        pending = next;
+    }
+
+    result
+}
+
+/// Local files included by the file in `path` with the given `content`
+pub fn local_includes(path: &Path, content: &str) -> Vec<PathBuf> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("rs") => rust_modules(path, content),
+        Some("py") => python_imports(path, content),
+        _ => vec![],
+    }
+}
+
+/// Remove the range of a file argument, e.g. /path/to/file:10-20 -> /path/to/file
+fn strip_range(file: &str) -> &str {
+    file.split_once(':').map_or(file, |(path, _)| path)
+}
+
+/// Files of the `mod name;` declarations, inline modules are skipped
+fn rust_modules(path: &Path, content: &str) -> Vec<PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+    let modules_dir = match stem {
+        "mod" | "main" | "lib" => dir.to_path_buf(),
+        _ => dir.join(stem),
+    };
+
+    content
+        .lines()
+        .filter_map(|line| {
+            let (visibility, declaration) = line.trim().split_once("mod ")?;
+            if !(visibility.is_empty() || visibility.starts_with("pub")) {
+                return None;
+            }
+            let name = declaration.strip_suffix(';')?.trim();
+
+            [
+                modules_dir.join(format!("{name}.rs")),
+                modules_dir.join(name).join("mod.rs"),
+            ]
+            .into_iter()
+            .find(|candidate| candidate.is_file())
+        })
+        .collect()
+}
+
+/// Files of the `import a.b` and `from a.b import c` statements, resolved from the file directory
+fn python_imports(path: &Path, content: &str) -> Vec<PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut modules = vec![];
+
+    for line in content.lines().map(str::trim) {
+        if let Some(names) = line.strip_prefix("import ") {
+            modules.extend(
+                names
+                    .split(',')
+                    .filter_map(|name| name.split_whitespace().next())
+                    .map(str::to_string),
+            );
+        } else if let Some(statement) = line.strip_prefix("from ")
+            && let Some((module, names)) = statement.split_once(" import ")
+        {
+            let module = module.trim();
+            modules.push(module.to_string());
+            // The imported names can be submodules, e.g. `from . import utils`
+            for name in names.trim_matches(['(', ')', ' ']).split(',') {
+                if let Some(name) = name.split_whitespace().next() {
+                    let separator = if module.ends_with('.') { "" } else { "." };
+                    modules.push(format!("{module}{separator}{name}"));
+                }
+            }
+        }
+    }
+
+    modules
+        .into_iter()
+        .filter_map(|module| {
+            let relative = module.trim_start_matches('.');
+            let dots = module.len() - relative.len();
+            let parents = dots.saturating_sub(1);
+            let base = (0..parents).fold(dir.to_path_buf(), |base, _| {
+                base.parent().map(Path::to_path_buf).unwrap_or(base)
+            });
+            let module_path = relative.split('.').fold(base, |path, part| path.join(part));
+
+            [module_path.with_extension("py"), module_path.join("__init__.py")]
+                .into_iter()
+                .find(|candidate| !relative.is_empty() && candidate.is_file())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follow_rust_modules() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("foo")).expect("create foo dir");
+        std::fs::create_dir_all(root.join("bar")).expect("create bar dir");
+        std::fs::write(root.join("main.rs"), "mod foo;\npub(crate) mod bar;\nmod inline {}\n").expect("write main");
+        std::fs::write(root.join("foo.rs"), "mod baz;\n").expect("write foo");
+        std::fs::write(root.join("foo/baz.rs"), "").expect("write baz");
+        std::fs::write(root.join("bar/mod.rs"), "").expect("write bar");
+
+        let main = root.join("main.rs").to_string_lossy().to_string();
+        let files = follow_includes(std::slice::from_ref(&main), 1);
+        assert_eq!(files.len(), 3);
+        assert!(files.iter().any(|file| file.ends_with("foo.rs")));
+        assert!(files.iter().any(|file| file.ends_with("bar/mod.rs")));
+
+        let files = follow_includes(&[format!("{main}:1-2")], 2);
+        assert_eq!(files.len(), 4);
+        assert!(files.iter().any(|file| file.ends_with("foo/baz.rs")));
+    }
+
+    #[test]
+    fn python_includes() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("pkg")).expect("create pkg dir");
+        std::fs::write(root.join("pkg/__init__.py"), "").expect("write init");
+        std::fs::write(root.join("pkg/utils.py"), "").expect("write utils");
+        std::fs::write(root.join("helpers.py"), "").expect("write helpers");
+
+        let content = "import os\nimport helpers\nfrom pkg import utils\n";
+        let includes = local_includes(&root.join("app.py"), content);
+
+        assert_eq!(
+            includes,
+            vec![
+                root.join("helpers.py"),
+                root.join("pkg/__init__.py"),
+                root.join("pkg/utils.py")
+            ]
+        );
+    }
+}
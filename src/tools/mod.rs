@@ -1,4 +1,11 @@
 pub mod cli;
+pub mod clipboard;
 pub mod diff;
 pub mod files;
+pub mod git;
+pub mod image;
+pub mod includes;
+pub mod patch;
 pub mod reader;
+pub mod schema;
+pub mod url;
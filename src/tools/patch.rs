@@ -0,0 +1,185 @@
+//! Convert the `File: path:range` blocks of a response into a unified diff against the working tree
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use tracing::debug;
+
+use super::diff::{DiffsManager, LineSequence};
+
+/// Unchanged lines around each change of the patch, the same as `git diff`
+const PATCH_CONTEXT: usize = 3;
+
+/// A fenced code block of a response and the file range it replaces
+#[derive(Debug, PartialEq)]
+pub struct FileBlock {
+    pub path: String,
+    /// Lines `start..=end` replaced by the block, `end` 0 means the end of the file. The whole file
+    /// is replaced if it is not set.
+    pub range: Option<(usize, usize)>,
+    pub content: String,
+}
+
+/// Parse the code blocks preceded by a `File: path[:start[-end]]` line, as requested by the code prompt
+pub fn parse_file_blocks(response: &str) -> Vec<FileBlock> {
+    let mut blocks = vec![];
+    let mut lines = response.lines();
+    let mut target: Option<(String, Option<(usize, usize)>)> = None;
+
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if let Some(arg) = line.strip_prefix("File:") {
+            // The argument can be followed by a comment, e.g. `File: src/main.rs:1-3  # here`
+            target = arg.split_whitespace().next().map(parse_file_arg);
+        } else if line.starts_with("```") {
+            let content: Vec<&str> = lines
+                .by_ref()
+                .take_while(|line| !line.trim().starts_with("```"))
+                .collect();
+            if let Some((path, range)) = target.take() {
+                blocks.push(FileBlock {
+                    path,
+                    range,
+                    content: content.join("\n"),
+                });
+            }
+        } else if !line.is_empty() {
+            target = None;
+        }
+    }
+
+    blocks
+}
+
+/// Split `path:start[-end]` in the path and the range
+fn parse_file_arg(arg: &str) -> (String, Option<(usize, usize)>) {
+    let Some((path, range)) = arg.split_once(':') else {
+        return (arg.to_string(), None);
+    };
+
+    let range = match range.split_once('-') {
+        Some((start, end)) => start.parse().ok().map(|start| (start, end.parse().unwrap_or(0))),
+        None => range.parse().ok().map(|start| (start, 0)),
+    };
+    (path.to_string(), range)
+}
+
+/// Render the `blocks` as a unified diff of the files in the working tree, one per file. The ranges
+/// of several blocks for the same file refer to its current lines.
+pub fn to_patch(blocks: &[FileBlock]) -> anyhow::Result<String> {
+    let mut by_file: BTreeMap<&str, Vec<&FileBlock>> = BTreeMap::new();
+    for block in blocks {
+        by_file.entry(&block.path).or_default().push(block);
+    }
+
+    let mut patch = String::new();
+    for (path, mut blocks) in by_file {
+        let exists = Path::new(path).exists();
+        let original = if exists {
+            std::fs::read_to_string(path)?
+        } else {
+            String::new()
+        };
+
+        // Replace from the bottom so the ranges of the previous blocks stay valid
+        blocks.sort_by_key(|block| std::cmp::Reverse(block.range.map_or(0, |(start, _)| start)));
+        let mut lines: Vec<&str> = original.lines().collect();
+        for block in blocks {
+            let (start, end) = match block.range {
+                Some((start, end)) => {
+                    let end = if end == 0 { lines.len() } else { end.min(lines.len()) };
+                    (start.saturating_sub(1).min(end), end)
+                }
+                None => (0, lines.len()),
+            };
+            lines.splice(start..end, block.content.lines());
+        }
+        let updated = lines.join("\n") + "\n";
+
+        let (seq1, seq2) = LineSequence::from_lines(original.lines(), updated.lines());
+        let hunks = DiffsManager::from_myers_algorithm(seq1, seq2).to_patch_hunks(PATCH_CONTEXT);
+        if hunks.is_empty() {
+            debug!(%path, "The blocks do not change the file");
+            continue;
+        }
+
+        let name = relative_name(path);
+        let old_name = if exists {
+            format!("a/{}", name)
+        } else {
+            "/dev/null".to_string()
+        };
+        patch.push_str(&format!("--- {}\n+++ b/{}\n{}", old_name, name, hunks));
+    }
+
+    Ok(patch)
+}
+
+/// Path of the file relative to the current directory, as expected by `git apply`
+fn relative_name(path: &str) -> String {
+    std::env::current_dir()
+        .ok()
+        .and_then(|cwd| Path::new(path).strip_prefix(cwd).ok().map(Path::to_path_buf))
+        .map_or_else(
+            || path.trim_start_matches("./").to_string(),
+            |path| path.to_string_lossy().to_string(),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_blocks() {
+        let response = "Some explanation\n\nFile: src/lib.rs:2-3  # here\n```rust\nfn b() {}\n```\n\n\
+                        ```sh\ncargo test\n```\nFile: README.md\n\n```md\n# Title\n```\n";
+
+        assert_eq!(
+            parse_file_blocks(response),
+            vec![
+                FileBlock {
+                    path: "src/lib.rs".to_string(),
+                    range: Some((2, 3)),
+                    content: "fn b() {}".to_string(),
+                },
+                FileBlock {
+                    path: "README.md".to_string(),
+                    range: None,
+                    content: "# Title".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn patch_the_working_tree() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let file = dir.path().join("lib.rs");
+        std::fs::write(&file, "fn a() {}\nfn b() {}\nfn c() {}\nfn d() {}\n").expect("write the file");
+        let path = file.to_string_lossy().to_string();
+
+        let blocks = [
+            FileBlock {
+                path: path.clone(),
+                range: Some((4, 4)),
+                content: "fn d() -> u8 { 0 }".to_string(),
+            },
+            FileBlock {
+                path: path.clone(),
+                range: Some((2, 2)),
+                content: "fn b() -> u8 { 0 }".to_string(),
+            },
+        ];
+
+        let patch = to_patch(&blocks).expect("render the patch");
+        let name = relative_name(&path);
+        assert_eq!(
+            patch,
+            format!(
+                "--- a/{name}\n+++ b/{name}\n@@ -1,4 +1,4 @@\n fn a() {{}}\n-fn b() {{}}\n+fn b() -> u8 {{ 0 }}\n \
+                 fn c() {{}}\n-fn d() {{}}\n+fn d() -> u8 {{ 0 }}\n"
+            )
+        );
+    }
+}
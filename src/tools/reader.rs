@@ -17,12 +17,15 @@ pub trait Readable: std::fmt::Debug {
     /// Add the line number to each line
     ///
     /// Example:
-    ///     ```rust
-    ///     let reader = TrackedFile::new(Some("Hello\nWelcome to Copilot\nTell me something".to_string()));
-    ///     let numered = reader.add_line_numbers();
+    /// ```rust
+    /// use copilot_chat::tools::{files::TrackedFile, reader::Readable};
     ///
-    ///     assert_eq!(numered, "1: Hello\n2: Welcome to Copilot\n3: Tell me something\n")
-    ///     ````
+    /// let mut reader = TrackedFile::new(Some("notes.txt".to_string()));
+    /// reader.set_content("Hello\nWelcome to Copilot\nTell me something".to_string());
+    /// let numered = reader.add_line_numbers();
+    ///
+    /// assert_eq!(numered, "1: Hello\n2: Welcome to Copilot\n3: Tell me something\n")
+    /// ```
     fn add_line_numbers(&self) -> String {
         let mut new_content = String::new();
         for (i, line) in self.content().lines().enumerate() {
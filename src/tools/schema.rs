@@ -0,0 +1,122 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A JSON schema that the response must follow, for structured outputs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schema {
+    pub path: String,
+    pub value: Value,
+    /// Ask the model to follow the schema exactly, it only accepts the schemas of the strict mode:
+    /// every property required and no additional properties
+    pub strict: bool,
+}
+
+impl Schema {
+    /// Read the JSON schema in `path`, it fails if it is not a valid schema
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path).map_err(|e| anyhow!("cannot read the schema {}: {}", path, e))?;
+        let value: Value = serde_json::from_str(&content).map_err(|e| anyhow!("invalid JSON in {}: {}", path, e))?;
+        jsonschema::validator_for(&value).map_err(|e| anyhow!("invalid JSON schema in {}: {}", path, e))?;
+
+        Ok(Self {
+            path: path.to_string(),
+            value,
+            strict: false,
+        })
+    }
+
+    /// Send the schema in strict mode
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// The `response_format` of the request that asks the model for a response with the schema
+    pub fn response_format(&self) -> Value {
+        serde_json::json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "response",
+                "strict": self.strict,
+                "schema": self.value,
+            }
+        })
+    }
+
+    /// Check that `content` is JSON that follows the schema, a fenced code block is accepted.
+    /// Returns the validation errors otherwise.
+    pub fn validate(&self, content: &str) -> Result<(), String> {
+        let json = strip_fence(content.trim());
+        let instance: Value = serde_json::from_str(json).map_err(|e| format!("the response is not JSON: {}", e))?;
+
+        let validator = jsonschema::validator_for(&self.value).map_err(|e| e.to_string())?;
+        let errors: Vec<String> = validator
+            .iter_errors(&instance)
+            .map(|error| match error.instance_path.to_string() {
+                path if path.is_empty() => error.to_string(),
+                path => format!("{}: {}", path, error),
+            })
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+}
+
+/// The content of a fenced code block, e.g. ```` ```json ````, or `content` if it is not fenced
+fn strip_fence(content: &str) -> &str {
+    content
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+        .map(|block| block.split_once('\n').map_or(block, |(_, body)| body))
+        .unwrap_or(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn person() -> Schema {
+        Schema {
+            path: "person.json".to_string(),
+            value: serde_json::json!({
+                "type": "object",
+                "properties": {"name": {"type": "string"}, "age": {"type": "integer"}},
+                "required": ["name", "age"]
+            }),
+            strict: false,
+        }
+    }
+
+    #[test]
+    fn strict_response_format() {
+        assert_eq!(person().response_format()["json_schema"]["strict"], false);
+        assert_eq!(
+            person().with_strict(true).response_format()["json_schema"]["strict"],
+            true
+        );
+    }
+
+    #[test]
+    fn validate_response() {
+        let schema = person();
+
+        assert!(schema.validate(r#"{"name": "Ada", "age": 36}"#).is_ok());
+        assert!(
+            schema
+                .validate("```json\n{\"name\": \"Ada\", \"age\": 36}\n```")
+                .is_ok()
+        );
+
+        let error = schema
+            .validate(r#"{"name": "Ada", "age": "36"}"#)
+            .expect_err("wrong type");
+        assert!(error.starts_with("/age: "), "{}", error);
+        assert!(schema.validate(r#"{"name": "Ada"}"#).is_err());
+        assert!(schema.validate("Ada is 36").expect_err("not JSON").contains("not JSON"));
+    }
+}
@@ -0,0 +1,149 @@
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use tracing::debug;
+
+use super::reader::{Readable, ReaderTool};
+
+/// Max bytes downloaded from a URL
+pub const MAX_URL_SIZE: usize = 1024 * 1024;
+
+/// Whether the file argument is a remote resource
+pub fn is_url(file: &str) -> bool {
+    file.starts_with("http://") || file.starts_with("https://")
+}
+
+/// A remote file attached by its URL, the content is not tracked for diffs
+#[derive(Debug)]
+pub struct UrlReadable {
+    url: String,
+    content: String,
+    fetched_at: SystemTime,
+}
+
+impl UrlReadable {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            content: String::new(),
+            fetched_at: SystemTime::now(),
+        }
+    }
+}
+
+impl Readable for UrlReadable {
+    fn location(&self) -> &str {
+        &self.url
+    }
+
+    fn content(&self) -> &str {
+        &self.content
+    }
+
+    fn modified_time(&self) -> &SystemTime {
+        &self.fetched_at
+    }
+
+    fn set_modified_time(&mut self, new_time: SystemTime) {
+        self.fetched_at = new_time
+    }
+
+    fn set_content(&mut self, content: String) {
+        self.content = content
+    }
+}
+
+/// Fetch the content of a URL
+pub struct UrlReader {
+    client: reqwest::Client,
+}
+
+impl UrlReader {
+    /// Fetch with `client`, so the requests go through the same proxy as the provider's
+    pub fn new(client: reqwest::Client) -> Self {
+        Self { client }
+    }
+}
+
+impl ReaderTool for UrlReader {
+    async fn read<'a>(&self, readable: &'a mut impl Readable) -> anyhow::Result<&'a str> {
+        let url = readable.location();
+        debug!(%url, "Fetching");
+
+        let mut resp = self.client.get(url).send().await?.error_for_status()?;
+        if resp
+            .content_length()
+            .is_some_and(|length| length as usize > MAX_URL_SIZE)
+        {
+            return Err(anyhow!("{} exceeds the limit of {} bytes", url, MAX_URL_SIZE));
+        }
+
+        // The length may be unknown, stop reading as soon as the body exceeds the limit
+        let mut body = vec![];
+        while let Some(chunk) = resp.chunk().await? {
+            if body.len() + chunk.len() > MAX_URL_SIZE {
+                return Err(anyhow!("{} exceeds the limit of {} bytes", url, MAX_URL_SIZE));
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        readable.set_content(String::from_utf8_lossy(&body).into_owned());
+        readable.set_modified_time(SystemTime::now());
+
+        Ok(readable.content())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Serve `body` once without a `Content-Length`, the end of the body is the end of the connection
+    async fn serve(body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind the listener");
+        let addr = listener.local_addr().expect("listener address");
+
+        tokio::spawn(async move {
+            let (connection, _) = listener.accept().await.expect("accept the request");
+            let mut connection = BufReader::new(connection);
+            // The request ends with an empty line
+            let mut line = String::new();
+            while connection.read_line(&mut line).await.expect("read the request") > 2 {
+                line.clear();
+            }
+            connection
+                .write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n")
+                .await
+                .expect("write the headers");
+            // The client may close the connection once the body is too large
+            connection.write_all(&body).await.ok();
+        });
+
+        format!("http://{}/snippet.rs", addr)
+    }
+
+    #[test]
+    fn detect_urls() {
+        assert!(is_url("https://example.com/snippet.rs"));
+        assert!(is_url("http://localhost:8000/main.py"));
+        assert!(!is_url("/path/to/file:10-20"));
+        assert!(!is_url("src/https.rs"));
+    }
+
+    #[tokio::test]
+    async fn fetch_url() {
+        let reader = UrlReader::new(reqwest::Client::new());
+
+        let mut readable = UrlReadable::new(&serve(b"fn main() {}\n".to_vec()).await);
+        let content = reader.read(&mut readable).await.expect("fetch the url");
+        assert_eq!(content, "fn main() {}\n");
+
+        // Without a `Content-Length`, the limit is checked while reading
+        let mut readable = UrlReadable::new(&serve(vec![b'a'; MAX_URL_SIZE + 1]).await);
+        let error = reader.read(&mut readable).await.expect_err("the body is too large");
+        assert!(error.to_string().contains("exceeds the limit"));
+        assert!(readable.content().is_empty());
+    }
+}